@@ -1,35 +1,62 @@
+use asa::runtimes::{
+    breakpoints::Breakpoints, io::Buffered, machine::Machine, watchpoints::Watchpoints,
+};
 use asa::*;
 use criterion::{Criterion, criterion_group, criterion_main};
 use std::fs;
 
 fn assembler(c: &mut Criterion) {
+    let config = assembler::AssemblerConfig::default();
     let contents = fs::read_to_string("./subleq/libs/sublib/tests/JumpIfTest.sbl").unwrap();
     c.bench_function("assembler normal", |b| {
         b.iter(|| {
             assembler::assemble(
                 &contents,
                 "./subleq/libs/sublib/tests/ControlTest.sbl".to_owned(),
+                &config,
             )
+            .unwrap()
         })
     });
     let contents = fs::read_to_string("./subleq/large.sbl").unwrap();
     c.bench_function("assembler large", |b| {
-        b.iter(|| assembler::assemble(&contents, "./subleq/large.sbl".to_owned()))
+        b.iter(|| assembler::assemble(&contents, "./subleq/large.sbl".to_owned(), &config).unwrap())
     });
 }
 
 fn runtimes(c: &mut Criterion) {
     let path = "./subleq/libs/sublib/tests/JumpIfTest.sbl";
     let contents = fs::read_to_string(path).unwrap();
+    let config = assembler::AssemblerConfig::default();
 
-    let (mut mem, toks) = assembler::assemble(&contents, path.to_owned());
+    let ((mem, toks, symbols), _) =
+        assembler::assemble(&contents, path.to_owned(), &config).unwrap();
 
     c.bench_function("normal", |b| {
-        b.iter(|| runtimes::interpreter::interpret(&mut mem))
+        b.iter(|| {
+            let mut machine = Machine::new(mem.clone(), Buffered::default());
+            runtimes::interpreter::interpret(&mut machine)
+        })
+    });
+
+    c.bench_function("decoded", |b| {
+        b.iter(|| {
+            let mut machine = Machine::new(mem.clone(), Buffered::default());
+            runtimes::interpreter::interpret_fast(&mut machine)
+        })
     });
 
     c.bench_function("debugger,", |b| {
-        b.iter(|| runtimes::debugger::run_with_debugger(&mut mem, &toks))
+        b.iter(|| {
+            let mut machine = Machine::new(mem.clone(), Buffered::default());
+            runtimes::debugger::run_with_debugger(
+                &mut machine,
+                &toks,
+                &symbols,
+                &mut Breakpoints::default(),
+                &mut Watchpoints::default(),
+            )
+        })
     });
 }
 
@@ -1,9 +1,14 @@
 //! Parses command line arguments
 use core::fmt;
+use std::time::Duration;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use once_cell::sync::OnceCell;
 
+use crate::runtimes::devices::DeviceKind;
+use crate::runtimes::io::EndOfInput;
+use crate::symbols::MEMORY_SIZE;
+
 static ARGS: OnceCell<Args> = OnceCell::new();
 
 /// Wrapper type for log::LevelFilter
@@ -39,9 +44,65 @@ impl fmt::Display for FeedbackLevel {
     }
 }
 
+/// How diagnostics are printed
+#[derive(clap::ValueEnum, Clone, Debug, Default, PartialEq)]
+pub enum MessageFormat {
+    /// Colourised, with a preview of the source
+    #[default]
+    Human,
+    /// One JSON object per diagnostic, per line. Progress messages go to stderr
+    Json,
+}
+
+/// Tools that work on a program instead of running it
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Turn a .bin or .sblx file back into sublang. Labels are synthesised for jump targets
+    /// and referenced data, unless a symbol table or the .sblmap next to the file names them
+    Disasm {
+        /// The .bin, .sblx or .sbl file to disassemble
+        target: String,
+
+        /// Write the sublang to this file instead of printing it
+        #[arg(short, long, value_name = "FILE")]
+        output: Option<String>,
+
+        /// A symbol table written with --symbols, whose names are used for the labels
+        #[arg(long, value_name = "FILE")]
+        symbols: Option<String>,
+    },
+    /// Write a .sbl file with every macro, assignment and dereference expanded. It
+    /// assembles to exactly the same program
+    Expand {
+        /// The .sbl file to expand
+        target: String,
+
+        /// Write the expanded sublang to this file instead of printing it
+        #[arg(short, long, value_name = "FILE")]
+        output: Option<String>,
+
+        /// End every line with a comment saying where it came from: its file and line,
+        /// followed by the macro calls it was expanded from, innermost first
+        #[arg(long, default_value_t = false)]
+        origins: bool,
+    },
+    /// Step through a run recorded with --trace in the debugger, backwards and forwards,
+    /// without running the program or giving it input again
+    Replay {
+        /// The program the trace was recorded with. A .bin or .sblx needs its .sblmap
+        target: String,
+
+        /// The trace written with --trace
+        trace: String,
+    },
+}
+
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, args_conflicts_with_subcommands = true)]
 pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     /// File to assemble or execute.
     ///
     /// If not given it will search for ./Main.sbl in the
@@ -57,7 +118,7 @@ pub struct Args {
     /// Level of assembler and runtime feedback. DEBUG is used for assembler debugging!
     ///
     /// When at DEBUG level a dump.sbl file is generated during the assembly process
-    #[arg(short,long, default_value_t = FeedbackLevel::Note)]
+    #[arg(short, long, global = true, default_value_t = FeedbackLevel::Note)]
     pub feedback_level: FeedbackLevel,
 
     /// Disable program execution, files will still be assembled and possibly written to disk
@@ -68,12 +129,22 @@ pub struct Args {
     #[arg(short, long, default_value_t = false)]
     pub debugger: bool,
 
-    /// Folder that stores libraries
-    #[arg(short = 'l', long, default_value = "./subleq/libs")]
-    pub libs_path: String,
+    /// Stop in the debugger at this 'file:line', line of the main file or label. A line in a
+    /// macro stops at every expansion. May be given multiple times, implies --debugger
+    #[arg(long = "break", value_name = "LOCATION", global = true)]
+    pub breakpoints: Vec<String>,
+
+    /// Stop in the debugger when this label or hex address like 0x1F is written: 'X',
+    /// 'X changed' or 'X = value'. May be given multiple times, implies --debugger
+    #[arg(long = "watch", value_name = "WATCHPOINT", global = true)]
+    pub watchpoints: Vec<String>,
+
+    /// Folder that stores libraries. May be given multiple times, the folders are searched in order
+    #[arg(short = 'l', long, global = true, default_value = "./subleq/libs")]
+    pub libs_path: Vec<String>,
 
     /// Disables type checking for macro arguments. Not recommended
-    #[arg(short = 't', long, default_value_t = false)]
+    #[arg(short = 't', long, global = true, default_value_t = false)]
     pub disable_type_checking: bool,
 
     /// Output file for assembled subleq, if not given no output be will generated.
@@ -89,22 +160,122 @@ pub struct Args {
     pub output: Option<Option<String>>,
 
     /// Suppresses all assembler output except for errors, overrides --feedback-level. Program output will still be shown.
-    #[arg(short, long, default_value_t = false)]
+    #[arg(short, long, global = true, default_value_t = false)]
     pub silent: bool,
 
     /// Shows more notes and warning. Recommended for release builds
-    #[arg(short, long, default_value_t = false)]
+    #[arg(short, long, global = true, default_value_t = false)]
     pub pedantic: bool,
 
     /// Treat warnings as errors
-    #[arg(short = 'w', long, default_value_t = false)]
+    #[arg(short = 'w', long, global = true, default_value_t = false)]
     pub warnings_are_errors: bool,
+
+    /// Also write a .sblmap source map next to the output file. When a .bin or .sblx file
+    /// with a source map is run, the debugger and runtime errors show its source
+    #[arg(short = 'm', long, default_value_t = false)]
+    pub source_map: bool,
+
+    /// Write the address, definition and scope of every label to this file.
+    /// As JSON if the file has a .json extension, otherwise as text
+    #[arg(long, value_name = "FILE")]
+    pub symbols: Option<String>,
+
+    /// Write a listing to this file: every word with its address, labels and the source
+    /// line it came from. Macro expansions are indented under their call
+    #[arg(long, value_name = "FILE")]
+    pub listing: Option<String>,
+
+    /// Read the program's input from this file instead of the keyboard. When input is
+    /// piped into asa it is read from stdin. Either way every byte is one char
+    #[arg(long, value_name = "FILE")]
+    pub input: Option<String>,
+
+    /// What reading input from a file or stdin yields once all of it has been read: 'halt'
+    /// to stop the program, or a number like 0 or -1 for the program to handle
+    #[arg(
+        long,
+        value_name = "VALUE",
+        default_value = "halt",
+        allow_hyphen_values = true
+    )]
+    pub eof: EndOfInput,
+
+    /// Run the program in the full 64K address space instead of only its image. The words
+    /// above the image start as 0
+    #[arg(long)]
+    pub full_memory: bool,
+
+    /// Reserve this many words at the top of memory for a stack. Assembly fails if the
+    /// program doesn't fit below it
+    #[arg(long, value_name = "WORDS", requires = "full_memory", value_parser = clap::value_parser!(u16).range(..MEMORY_SIZE as i64))]
+    pub stack_size: Option<u16>,
+
+    /// Attach devices to the words below the debug address, separated by commas. 'cycles'
+    /// counts instructions, 'random' gives pseudo-random words, 'int-input' reads a number
+    /// from the input and 'halt' halts with the written value as exit code, where values
+    /// outside of 0..=255 exit with 255. The ASM library has macros for them. With
+    /// --full-memory only the words of attached devices aren't memory
+    #[arg(long, value_name = "DEVICES", value_delimiter = ',')]
+    pub devices: Vec<DeviceKind>,
+
+    /// Seed for the random device. Without it every run is different
+    #[arg(long, value_name = "N", requires = "devices")]
+    pub seed: Option<u64>,
+
+    /// Count how often every instruction is executed and show the hottest source lines
+    /// and macros when the program stops
+    #[arg(long)]
+    pub profile: bool,
+
+    /// Profile the program and write the counts per call stack to this file as folded
+    /// stacks, which flamegraph tools can render
+    #[arg(long, value_name = "FILE")]
+    pub profile_folded: Option<String>,
+
+    /// Write which source lines the program executed to this file as LCOV. Lines in macro
+    /// bodies count every expansion
+    #[arg(long, value_name = "FILE")]
+    pub coverage: Option<String>,
+
+    /// Record every executed instruction to this file, so the run can be stepped through
+    /// with 'asa replay'. This makes execution a lot slower. Runs in the debugger can't be
+    /// recorded
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = ["debugger", "breakpoints", "watchpoints"]
+    )]
+    pub trace: Option<String>,
+
+    /// Stop the program with an error after it has executed this many instructions. The
+    /// debugger ignores it
+    #[arg(long, value_name = "N")]
+    pub max_instructions: Option<u128>,
+
+    /// Stop the program with an error after it has run for this many seconds, like 1.5. The
+    /// debugger ignores it
+    #[arg(long, value_name = "SECS", value_parser = parse_seconds)]
+    pub timeout: Option<Duration>,
+
+    /// Format of the errors, warnings and notes
+    #[arg(long, global = true, value_enum, default_value_t = MessageFormat::Human)]
+    pub message_format: MessageFormat,
+
+    /// Also write all errors, warnings and notes to this file as a SARIF log
+    #[arg(long, global = true, value_name = "FILE")]
+    pub sarif: Option<String>,
+}
+
+fn parse_seconds(s: &str) -> Result<Duration, String> {
+    s.parse::<f64>()
+        .ok()
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+        .ok_or_else(|| format!("expected a non-negative number of seconds, found '{s}'"))
 }
 
 pub fn get() -> &'static Args {
-    ARGS.get().unwrap_or_else(|| {
-        crate::error!("No arguments have been parsed");
-    })
+    ARGS.get().expect("No arguments have been parsed")
 }
 
 pub fn exist() -> bool {
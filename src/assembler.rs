@@ -1,21 +1,187 @@
 //! Dispatches the lexer, parser and code generator
+use std::path::PathBuf;
+use std::sync::Arc;
 use std::time::Instant;
 
 use log::LevelFilter;
 
-use crate::println_silenceable;
+use crate::args::{Args, FeedbackLevel, MessageFormat};
+use crate::feedback::{AssembleError, Diagnostics};
+use crate::sources::{FileSystem, SourceProvider};
+use crate::symbol_table::Symbol;
+use crate::symbols::MEMORY_SIZE;
 use crate::tokens::Token;
 use crate::tokens::TokenVariant;
-use crate::{codegen, lexer, parser};
+use crate::{codegen, error, lexer, parser};
 
-pub fn assemble(text: &str, path: String) -> (Vec<u16>, Vec<Token>) {
-    println_silenceable!("Assembling {}", path);
+/// Memory, the token each word originates from and the labels
+pub type Assembly = (Vec<u16>, Vec<Token>, Vec<Symbol>);
+
+/// Settings for a single assembly. Nothing in the assembler reads the command line
+/// arguments directly, so different assemblies can use different settings
+#[derive(Debug, Clone)]
+pub struct AssemblerConfig {
+    /// Folders searched, in order, for includes that can't be found relative to the file being assembled
+    pub libs_paths: Vec<PathBuf>,
+    /// Type checking for macro arguments
+    pub type_checking: bool,
+    /// Shows more notes and warnings
+    pub pedantic: bool,
+    pub warnings_are_errors: bool,
+    /// Which notes and warnings of a successful assembly are shown. When assembly fails
+    /// everything is returned, so they are not affected
+    pub feedback_level: FeedbackLevel,
+    /// Suppresses progress messages like the assembly time and program size
+    pub silent: bool,
+    pub message_format: MessageFormat,
+    /// Where a SARIF log of the diagnostics is written
+    pub sarif_path: Option<PathBuf>,
+    /// Where included files are read from
+    pub sources: Arc<dyn SourceProvider>,
+    /// Words reserved for a stack at the top of the full address space. The program has to
+    /// fit below it, the size summary shows the stack and the free words in between
+    pub stack_size: Option<usize>,
+}
+
+impl Default for AssemblerConfig {
+    fn default() -> Self {
+        AssemblerConfig {
+            libs_paths: vec![PathBuf::from("./subleq/libs")],
+            type_checking: true,
+            pedantic: false,
+            warnings_are_errors: false,
+            feedback_level: FeedbackLevel::Note,
+            silent: false,
+            message_format: MessageFormat::Human,
+            sarif_path: None,
+            stack_size: None,
+            sources: Arc::new(FileSystem),
+        }
+    }
+}
+
+impl From<&Args> for AssemblerConfig {
+    fn from(args: &Args) -> Self {
+        AssemblerConfig {
+            libs_paths: args.libs_path.iter().map(PathBuf::from).collect(),
+            type_checking: !args.disable_type_checking,
+            pedantic: args.pedantic,
+            warnings_are_errors: args.warnings_are_errors,
+            feedback_level: if args.silent {
+                FeedbackLevel::Error
+            } else {
+                args.feedback_level.clone()
+            },
+            silent: args.silent,
+            message_format: args.message_format.clone(),
+            sarif_path: args.sarif.as_ref().map(PathBuf::from),
+            sources: Arc::new(FileSystem),
+            stack_size: args.stack_size.map(usize::from),
+        }
+    }
+}
+
+/// Assembles sublang into memory, returning it together with the token each word originates
+/// from and the symbol table. All errors, warnings and notes are returned sorted by location,
+/// with the assembly if it succeeded, so the caller decides how to report them, see
+/// Diagnostics::emit()
+pub fn assemble(
+    text: &str,
+    path: String,
+    config: &AssemblerConfig,
+) -> Result<(Assembly, Diagnostics), Diagnostics> {
+    progress(config, &format!("Assembling {path}"));
 
     let timer = Instant::now();
+    let ((mem, tokens, symbols), diagnostics) =
+        with_diagnostics(|diagnostics| assemble_and_report(text, path, config, diagnostics))?;
+
+    progress(config, &format!("\nAssembled in: {:.3?}", timer.elapsed()));
+    progress(
+        config,
+        &format!(
+            "Size: {}/{}, {:.4}%",
+            mem.len(),
+            0xFFFF,
+            (mem.len() as f32 / 0xFFFF as f32) * 100f32
+        ),
+    );
+    if let Some(stack_size) = config.stack_size {
+        let stack_start = MEMORY_SIZE - stack_size;
+        progress(
+            config,
+            &format!(
+                "Stack: {stack_size} words at 0x{stack_start:04X}..0x{:04X}",
+                MEMORY_SIZE - 1
+            ),
+        );
+        progress(config, &format!("Free: {} words", stack_start - mem.len()));
+    }
+    Ok(((mem, tokens, symbols), diagnostics))
+}
+
+/// Silently assembles the text as Test.sbl, panicking if it doesn't assemble
+#[cfg(test)]
+pub(crate) fn assemble_for_test(text: &str) -> Assembly {
+    let config = AssemblerConfig {
+        silent: true,
+        ..Default::default()
+    };
+    assemble(text, "Test.sbl".to_owned(), &config).unwrap().0
+}
+
+/// Prints a progress message, unless the config is silent. With JSON diagnostics it goes
+/// to stderr, so stdout only has one JSON object per line
+fn progress(config: &AssemblerConfig, text: &str) {
+    match (config.silent, &config.message_format) {
+        (true, _) => {}
+        (false, MessageFormat::Human) => println!("{text}"),
+        (false, MessageFormat::Json) => eprintln!("{text}"),
+    }
+}
+
+/// Expands everything in the sublang that isn't a plain instruction, literal or label, see
+/// expansion::to_sublang(). Diagnostics are handled the same way as by assemble()
+pub fn expand(
+    text: &str,
+    path: String,
+    config: &AssemblerConfig,
+) -> Result<(Vec<Token>, Diagnostics), Diagnostics> {
+    with_diagnostics(|diagnostics| {
+        let tokens = lexer::tokenise(text.to_owned(), path, config)?;
+        Ok(Some(parser::expand(tokens, config, diagnostics)?))
+    })
+}
+
+/// Runs a stage of the assembler, collecting everything it reports. The result is only
+/// returned if there were no errors
+fn with_diagnostics<T>(
+    stage: impl FnOnce(&mut Diagnostics) -> Result<Option<T>, AssembleError>,
+) -> Result<(T, Diagnostics), Diagnostics> {
+    let mut diagnostics = Diagnostics::default();
+    let output = stage(&mut diagnostics).unwrap_or_else(|e| {
+        diagnostics.push(e);
+        None
+    });
+    diagnostics.sort();
+
+    match output {
+        Some(output) if !diagnostics.has_errors() => Ok((output, diagnostics)),
+        _ => Err(diagnostics),
+    }
+}
 
-    let tokens = lexer::tokenise(text.to_owned(), path);
+/// Errors that can be recovered from are reported, only the first unrecoverable one is returned.
+/// None if errors were reported
+fn assemble_and_report(
+    text: &str,
+    path: String,
+    config: &AssemblerConfig,
+    diagnostics: &mut Diagnostics,
+) -> Result<Option<Assembly>, AssembleError> {
+    let tokens = lexer::tokenise(text.to_owned(), path, config)?;
 
-    if log::max_level() >= LevelFilter::Debug {
+    if config.feedback_level.to_log_level() >= LevelFilter::Debug {
         log::debug!("Tokens:");
         for i in &tokens {
             if let TokenVariant::Linebreak = i.variant {
@@ -26,15 +192,160 @@ pub fn assemble(text: &str, path: String) -> (Vec<u16>, Vec<Token>) {
         }
     }
 
-    let tokens = parser::parse(tokens);
-    let (mem, tokens) = codegen::generate(tokens);
+    let (tokens, symbols) = parser::parse(tokens, config, diagnostics)?;
+    if diagnostics.has_errors() {
+        // Code generation would only add confusing errors about the unresolved parts
+        return Ok(None);
+    }
+    let (mem, tokens) = codegen::generate(tokens, config, diagnostics)?;
 
-    println_silenceable!("\nAssembled in: {:.3?}", timer.elapsed());
-    println_silenceable!(
-        "Size: {}/{}, {:.4}%",
-        mem.len(),
-        0xFFFF,
-        (mem.len() as f32 / 0xFFFF as f32) * 100f32
-    );
-    (mem, tokens)
+    if let Some(stack_size) = config.stack_size
+        && mem.len() + stack_size > MEMORY_SIZE
+    {
+        error!(
+            "The program ({} words) and the stack ({stack_size} words) don't fit in the {MEMORY_SIZE} words of memory",
+            mem.len()
+        );
+    }
+    Ok(Some((mem, tokens, symbols)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::feedback::Severity;
+    use crate::sources::InMemory;
+
+    #[test]
+    fn configs_are_independent() {
+        // A label is passed where a literal is expected
+        let text = "@Lit l_x? [\n    A -= A\n]\n!Lit A\nA -> 0\n";
+        let strict = AssemblerConfig {
+            silent: true,
+            ..Default::default()
+        };
+        let lenient = AssemblerConfig {
+            type_checking: false,
+            silent: true,
+            ..Default::default()
+        };
+
+        let diagnostics = assemble(text, "Test.sbl".to_owned(), &strict).unwrap_err();
+        assert_eq!(
+            diagnostics.errors().next().unwrap().message,
+            "Expected a 'literal' as argument "
+        );
+        let ((mem, _, _), _) = assemble(text, "Test.sbl".to_owned(), &lenient).unwrap();
+        assert_eq!(mem, vec![3, 3, 3, 0]);
+    }
+
+    #[test]
+    fn collects_all_diagnostics() {
+        let text = "A -= Y\nA -> 0\nA -> 1\nZ -= A\n";
+        let config = AssemblerConfig {
+            silent: true,
+            ..Default::default()
+        };
+
+        let diagnostics = assemble(text, "Test.sbl".to_owned(), &config).unwrap_err();
+        let messages = diagnostics
+            .list
+            .iter()
+            .map(|d| (d.severity, d.message.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            messages,
+            vec![
+                (Severity::Error, "No definition for the label 'Y' found"),
+                (
+                    Severity::Warning,
+                    "The label called 'A' has already been defined in this scope"
+                ),
+                (Severity::Error, "No definition for the label 'Z' found"),
+            ]
+        );
+        assert_eq!(diagnostics.summary(), "2 errors, 1 warning");
+    }
+
+    #[test]
+    fn returns_warnings_of_a_success() {
+        let config = AssemblerConfig {
+            silent: true,
+            ..Default::default()
+        };
+
+        let ((mem, _, _), diagnostics) =
+            assemble("A -= A\nA -> 0\nA -> 1\n", "Test.sbl".to_owned(), &config).unwrap();
+        assert_eq!(mem, vec![4, 4, 3, 0, 1]);
+        assert_eq!(diagnostics.summary(), "0 errors, 1 warning");
+    }
+
+    #[test]
+    fn assemble_from_memory() {
+        let mut sources = InMemory::new();
+        sources.insert("gen/Values.sbl", "B -> 5\n");
+        let config = AssemblerConfig {
+            silent: true,
+            sources: Arc::new(sources),
+            ..Default::default()
+        };
+
+        let ((mem, _, _), _) =
+            assemble("B -= B\n#Values\n", "gen/Main.sbl".to_owned(), &config).unwrap();
+        assert_eq!(mem, vec![3, 3, 3, 5]);
+        lexer::FILES.with_borrow(|files| {
+            assert_eq!(files[1].path, PathBuf::from("gen/Values.sbl"));
+            assert_eq!(files[1].contents, "B -> 5\n");
+        });
+    }
+
+    #[test]
+    fn includes_each_file_once() {
+        let mut sources = InMemory::new();
+        sources.insert("Values.sbl", "B -> 5\n");
+        sources.insert("lib/Lib.sbl", "#Values\n");
+        let config = AssemblerConfig {
+            silent: true,
+            sources: Arc::new(sources),
+            ..Default::default()
+        };
+
+        let text = "B -= B\n#./Values\n#Values\n#lib\n";
+        let ((mem, _, _), _) = assemble(text, "Main.sbl".to_owned(), &config).unwrap();
+        assert_eq!(mem, vec![3, 3, 3, 5]);
+        lexer::FILES.with_borrow(|files| assert_eq!(files.len(), 3));
+    }
+
+    #[test]
+    fn missing_include_has_a_location() {
+        let config = AssemblerConfig {
+            silent: true,
+            sources: Arc::new(InMemory::new()),
+            ..Default::default()
+        };
+
+        let diagnostics =
+            assemble("Z -> 0\n#Missing\n", "Main.sbl".to_owned(), &config).unwrap_err();
+        let error = diagnostics.errors().next().unwrap();
+        assert!(error.message.starts_with("File to include not found"));
+        assert_eq!(error.info.as_ref().unwrap().line_number, 2);
+    }
+
+    #[test]
+    fn stack_has_to_fit() {
+        let text = "Z -= Z -1\nZ -> 0\n";
+        let config = |stack_size| AssemblerConfig {
+            silent: true,
+            stack_size: Some(stack_size),
+            ..Default::default()
+        };
+
+        assert!(assemble(text, "Test.sbl".to_owned(), &config(MEMORY_SIZE - 4)).is_ok());
+        let diagnostics =
+            assemble(text, "Test.sbl".to_owned(), &config(MEMORY_SIZE - 3)).unwrap_err();
+        assert_eq!(
+            diagnostics.errors().next().unwrap().message,
+            "The program (4 words) and the stack (65531 words) don't fit in the 65534 words of memory"
+        );
+    }
 }
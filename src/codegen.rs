@@ -1,25 +1,33 @@
 //! Generate a vec of executable words from a vector of tokens
 
 use crate::{
-    asm_error, asm_warn, terminate,
+    asm_err, asm_warn,
+    assembler::AssemblerConfig,
+    error,
+    feedback::{AssembleError, Diagnostics},
     tokens::{Token, TokenVariant},
 };
 
 /// Returns both a list of executable words AND their corrosponding tokens,
 /// to be able to give runtime errors
-pub fn generate(tokens: Vec<Token>) -> (Vec<u16>, Vec<Token>) {
+pub fn generate(
+    tokens: Vec<Token>,
+    config: &AssemblerConfig,
+    diagnostics: &mut Diagnostics,
+) -> Result<(Vec<u16>, Vec<Token>), AssembleError> {
     let mut mem: Vec<u16> = Vec::with_capacity(tokens.len());
     let mut final_tokens: Vec<Token> = Vec::with_capacity(tokens.len());
     for token in tokens {
         if final_tokens.len() > 0xFFF0 {
-            log::error!("Program is too big");
-            terminate!();
+            error!("Program is too big");
         }
         match &token.variant {
             TokenVariant::DecLiteral { value } => {
                 let as_u16 = *value as u16;
                 if (*value >> 16) > 0 {
                     asm_warn!(
+                        diagnostics,
+                        config,
                         &token.info,
                         "Number {} is too large, it will equal {}",
                         value,
@@ -35,9 +43,9 @@ pub fn generate(tokens: Vec<Token>) -> (Vec<u16>, Vec<Token>) {
                 continue;
             }
             _ => {
-                asm_error!(&token.info, "Unprocessed token",);
+                return Err(asm_err!(&token.info, "Unprocessed token",).trace(&token.origin_info));
             }
         }
     }
-    (mem, final_tokens)
+    Ok((mem, final_tokens))
 }
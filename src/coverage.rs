@@ -0,0 +1,121 @@
+//! Which source lines a run executed, written as LCOV so coverage viewers can show it
+use std::collections::{BTreeMap, HashMap};
+use std::{fs, path::Path};
+
+use crate::{
+    error, feedback::AssembleError, lexer, listing::statement_of, profiler::Profile, tokens::Info,
+    tokens::Token,
+};
+
+/// A file and a line number in it
+type Line = (usize, i32);
+
+/// Words don't know if they are part of an instruction, but the line they are written on does
+fn is_instruction_line(info: &Info) -> bool {
+    lexer::FILES.with_borrow(|files| {
+        files
+            .get(info.file)
+            .and_then(|file| {
+                file.contents
+                    .lines()
+                    .nth((info.line_number - 1).max(0) as usize)
+            })
+            .is_some_and(|line| line.split(';').next().unwrap_or("").contains("-="))
+    })
+}
+
+/// How often every line with instructions was executed. A macro body line counts every
+/// expansion of it and a macro call counts as a line of its own. Within one expansion a
+/// line counts as often as its most executed instruction, so it isn't counted once per
+/// instruction
+pub fn line_counts(profile: &Profile, tokens: &[Token]) -> BTreeMap<Line, u64> {
+    let mut lines: BTreeMap<Line, u64> = BTreeMap::new();
+    // The lines the instructions so far belong to, with the most executions in this expansion
+    let mut open: HashMap<Line, u64> = HashMap::new();
+
+    for (address, token) in tokens.iter().enumerate() {
+        let count = profile.counts.get(address).copied().unwrap_or(0);
+        let (calls, statement) = statement_of(token);
+        // Data in between the instructions of a line doesn't end it
+        if count == 0 && !is_instruction_line(statement) {
+            continue;
+        }
+        let current: Vec<Line> = calls
+            .iter()
+            .chain([statement])
+            .map(|info| (info.file, info.line_number))
+            .collect();
+
+        open.retain(|line, most| {
+            let still_open = current.contains(line);
+            if !still_open {
+                *lines.entry(*line).or_default() += *most;
+            }
+            still_open
+        });
+        for line in current {
+            let most = open.entry(line).or_default();
+            *most = (*most).max(count);
+            lines.entry(line).or_default();
+        }
+    }
+    for (line, most) in open {
+        *lines.entry(line).or_default() += most;
+    }
+    lines
+}
+
+/// A record per file with the lines that contain instructions and how often they ran
+pub fn to_lcov(profile: &Profile, tokens: &[Token]) -> String {
+    let mut per_file: BTreeMap<usize, Vec<(i32, u64)>> = BTreeMap::new();
+    for ((file, line), count) in line_counts(profile, tokens) {
+        per_file.entry(file).or_default().push((line, count));
+    }
+
+    let mut text = String::new();
+    for (file, lines) in per_file {
+        let path = lexer::FILES.with_borrow(|files| {
+            files
+                .get(file)
+                .map_or("?".to_owned(), |file| file.path.display().to_string())
+        });
+        text.push_str(&format!("TN:\nSF:{path}\n"));
+        for (line, count) in &lines {
+            text.push_str(&format!("DA:{line},{count}\n"));
+        }
+        let hit = lines.iter().filter(|(_, count)| *count > 0).count();
+        text.push_str(&format!("LF:{}\nLH:{hit}\nend_of_record\n", lines.len()));
+    }
+    text
+}
+
+pub fn to_file(path: &Path, profile: &Profile, tokens: &[Token]) -> Result<(), AssembleError> {
+    if let Err(e) = fs::write(path, to_lcov(profile, tokens)) {
+        error!("Failed to write the coverage {}. {e}", path.display());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble_for_test;
+    use crate::runtimes::{interpreter, io::Buffered, machine::Machine};
+
+    #[test]
+    fn lcov() {
+        // The loop runs three times, the instruction after the halt never runs
+        let text = "@DecJump a? .to? [ a? -= ONE .to? ]\n.loop ->\n    !DecJump N .end\n    \
+                    Z -= Z .loop\n.end ->\n    Z -= Z -1\n    Z -= Z\nN -> 3\nONE -> 1\nZ -> 0\n";
+        let (mem, tokens, _) = assemble_for_test(text);
+
+        let mut profile = Profile::new(mem.len());
+        let mut machine = Machine::new(mem, Buffered::default());
+        interpreter::interpret_profiled(&mut machine, &mut profile).unwrap();
+
+        assert_eq!(
+            to_lcov(&profile, &tokens),
+            "TN:\nSF:Test.sbl\nDA:1,3\nDA:3,3\nDA:4,2\nDA:6,1\nDA:7,0\nLF:5\nLH:4\nend_of_record\n"
+        );
+    }
+}
@@ -0,0 +1,156 @@
+//! Machine readable renderings of diagnostics, for tools like CI that annotate the source
+use serde_json::{Value, json};
+
+use crate::feedback::{AssembleError, Diagnostics};
+use crate::lexer;
+use crate::tokens::Info;
+
+const SARIF_SCHEMA: &str = "https://json.schemastore.org/sarif-2.1.0.json";
+
+/// The path of the file the info points into, as it was given to the assembler
+fn file_of(info: &Info) -> Option<String> {
+    lexer::FILES.with_borrow(|files| {
+        files
+            .get(info.file)
+            .map(|file| file.path.display().to_string())
+    })
+}
+
+fn location_json(info: &Info) -> Value {
+    json!({
+        "file": file_of(info),
+        "line": info.line_number,
+        "column": info.start_char,
+        "length": info.length,
+    })
+}
+
+/// A single JSON object, all locations are 1-based.
+///
+/// Example:
+/// {"severity":"error","message":"No definition for the label 'Y' found","file":"Main.sbl",
+///  "line":1,"column":6,"length":1,"hints":[],"details":[],"origin_info":[]}
+pub fn to_json(diagnostic: &AssembleError) -> Value {
+    let mut object = json!({
+        "severity": diagnostic.severity.as_str(),
+        "message": diagnostic.message,
+        "file": null,
+        "line": null,
+        "column": null,
+        "length": null,
+        "hints": diagnostic.hints,
+        "details": diagnostic.details.iter().map(|(info, message)| {
+            let mut detail = location_json(info);
+            detail["message"] = json!(message);
+            detail
+        }).collect::<Vec<Value>>(),
+        // Innermost macro call first, the same order as the pretty printer
+        "origin_info": diagnostic.origin_info.iter().rev().map(location_json).collect::<Vec<Value>>(),
+    });
+    if let Some(info) = &diagnostic.info
+        && let Value::Object(location) = location_json(info)
+    {
+        for (key, value) in location {
+            object[key] = value;
+        }
+    }
+    object
+}
+
+fn sarif_location(info: &Info, message: Option<&str>) -> Value {
+    let mut location = json!({
+        "physicalLocation": {
+            "artifactLocation": { "uri": file_of(info).map(|path| path.replace('\\', "/")) },
+            "region": {
+                "startLine": info.line_number,
+                "startColumn": info.start_char,
+                "endColumn": info.start_char + info.length.max(1),
+            },
+        },
+    });
+    if let Some(message) = message {
+        location["message"] = json!({ "text": message });
+    }
+    location
+}
+
+/// Details and the macro trace become related locations
+fn sarif_result(diagnostic: &AssembleError) -> Value {
+    let details = diagnostic
+        .details
+        .iter()
+        .map(|(info, message)| sarif_location(info, Some(message)));
+    let trace = diagnostic
+        .origin_info
+        .iter()
+        .rev()
+        .map(|info| sarif_location(info, Some("Expanded from this macro call")));
+
+    let mut result = json!({
+        "level": diagnostic.severity.as_str(),
+        "message": { "text": diagnostic.message },
+        "relatedLocations": details.chain(trace).collect::<Vec<Value>>(),
+        "properties": { "hints": diagnostic.hints },
+    });
+    if let Some(info) = &diagnostic.info {
+        result["locations"] = json!([sarif_location(info, None)]);
+    }
+    result
+}
+
+/// A SARIF 2.1.0 log with a single run
+pub fn to_sarif(diagnostics: &Diagnostics) -> Value {
+    let results = diagnostics
+        .list
+        .iter()
+        .map(sarif_result)
+        .collect::<Vec<Value>>();
+
+    json!({
+        "$schema": SARIF_SCHEMA,
+        "version": "2.1.0",
+        "runs": [{
+            "tool": {
+                "driver": {
+                    "name": env!("CARGO_PKG_NAME"),
+                    "version": env!("CARGO_PKG_VERSION"),
+                    "informationUri": env!("CARGO_PKG_REPOSITORY"),
+                },
+            },
+            "results": results,
+        }],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::{self, AssemblerConfig};
+
+    #[test]
+    fn json_and_sarif() {
+        let config = AssemblerConfig {
+            silent: true,
+            ..Default::default()
+        };
+        let diagnostics =
+            assembler::assemble("A -= Y\nA -> 0\n", "Test.sbl".to_owned(), &config).unwrap_err();
+
+        let json = to_json(&diagnostics.list[0]);
+        assert_eq!(json["severity"], "error");
+        assert_eq!(json["message"], "No definition for the label 'Y' found");
+        assert_eq!(json["file"], "Test.sbl");
+        assert_eq!(json["line"], 1);
+        assert_eq!(json["column"], 6);
+        assert_eq!(json["length"], 1);
+
+        let sarif = to_sarif(&diagnostics);
+        let result = &sarif["runs"][0]["results"][0];
+        assert_eq!(sarif["version"], "2.1.0");
+        assert_eq!(result["level"], "error");
+        let location = &result["locations"][0]["physicalLocation"];
+        assert_eq!(location["artifactLocation"]["uri"], "Test.sbl");
+        assert_eq!(location["region"]["startLine"], 1);
+        assert_eq!(location["region"]["endColumn"], 7);
+    }
+}
@@ -0,0 +1,273 @@
+//! Turns memory back into sublang. The output assembles to the exact same memory
+use std::collections::{HashMap, HashSet};
+
+use crate::symbols::{DEBUG_ADDR, DEVICES_ADDR, IO_ADDR};
+
+/// Strings shorter than this are shown as numbers
+const MIN_STRING_LENGTH: usize = 3;
+const WORDS_PER_LINE: usize = 8;
+const INDENT: &str = "    ";
+
+/// Follows every path through the program from address 0. Anything that can't be reached
+/// is data. Jumps through addresses that are only known at runtime can't be followed, so
+/// code that is only reached that way shows up as data
+fn find_instructions(mem: &[u16]) -> Vec<bool> {
+    let mut is_instruction = vec![false; mem.len()];
+    let mut covered = vec![false; mem.len()];
+    let mut to_visit = vec![0];
+
+    while let Some(pc) = to_visit.pop() {
+        if pc + 2 >= mem.len() || covered[pc..pc + 3].iter().any(|c| *c) {
+            continue;
+        }
+        is_instruction[pc] = true;
+        covered[pc..pc + 3].fill(true);
+
+        let (a, b, c) = (mem[pc], mem[pc + 1], mem[pc + 2] as usize);
+        if c < mem.len() {
+            to_visit.push(c);
+        }
+        // x -= x always jumps, so execution never continues at the next instruction
+        if a != b || c == pc + 3 {
+            to_visit.push(pc + 3);
+        }
+    }
+    is_instruction
+}
+
+/// Label names have to be valid sublang, the hygiene names of macros ('?Macro?name')
+/// and dereferences ('*0*name') are not
+fn sanitise_name(name: &str) -> Option<String> {
+    if name.contains('*') {
+        return None;
+    }
+    let name = name.trim_start_matches(|c: char| !(c.is_alphabetic() || c == '_' || c == '.'));
+    if name.is_empty() {
+        return None;
+    }
+    Some(
+        name.chars()
+            .map(|c| {
+                if c.is_alphanumeric() || "_:?.".contains(c) {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect(),
+    )
+}
+
+struct Labels {
+    names: HashMap<usize, Vec<String>>,
+    used: HashSet<String>,
+}
+
+impl Labels {
+    fn add(&mut self, address: usize, name: String) {
+        let name = if self.used.contains(&name) {
+            format!("{name}_{address:04X}")
+        } else {
+            name
+        };
+        self.used.insert(name.clone());
+        self.names.entry(address).or_default().push(name);
+    }
+
+    /// Gives the address a label if it doesn't have one yet
+    fn ensure(&mut self, address: usize, prefix: &str) {
+        if !self.names.contains_key(&address) {
+            self.add(address, format!("{prefix}{address:04X}"));
+        }
+    }
+
+    fn get(&self, address: usize) -> &[String] {
+        self.names.get(&address).map_or(&[], Vec::as_slice)
+    }
+}
+
+/// A literal for a data word
+fn literal(word: u16) -> String {
+    match word {
+        0x8000.. if word as usize >= DEVICES_ADDR => format!("-{}", 0x10000 - word as usize),
+        0x8000.. => format!("0x{word:04X}"),
+        _ => word.to_string(),
+    }
+}
+
+fn is_string_char(word: u16) -> bool {
+    (0x20..0x7F).contains(&word) || word == '\n' as u16 || word == '\t' as u16
+}
+
+/// The length of the string starting at the start of the words, including its null
+/// terminator. None if it doesn't look like a string
+fn string_length(words: &[u16]) -> Option<usize> {
+    let length = words.iter().take_while(|w| is_string_char(**w)).count();
+    if length >= MIN_STRING_LENGTH && words.get(length) == Some(&0) {
+        Some(length + 1)
+    } else {
+        None
+    }
+}
+
+fn string_literal(words: &[u16]) -> String {
+    let mut literal = String::from("\"");
+    for word in words {
+        match char::from_u32(*word as u32).unwrap_or('?') {
+            '\n' => literal.push_str("\\n"),
+            '\t' => literal.push_str("\\t"),
+            '"' => literal.push_str("\\\""),
+            '\\' => literal.push_str("\\\\"),
+            c => literal.push(c),
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+/// Data words between two labels. Strings are recognised, everything else is a list of numbers
+fn push_data(text: &mut String, words: &[u16]) {
+    let mut i = 0;
+    let mut numbers: Vec<String> = Vec::new();
+    while i < words.len() {
+        if let Some(length) = string_length(&words[i..]) {
+            if !numbers.is_empty() {
+                text.push_str(&format!("{INDENT}{}\n", numbers.join(" ")));
+                numbers.clear();
+            }
+            // The assembler adds the null terminator
+            text.push_str(&format!(
+                "{INDENT}{}\n",
+                string_literal(&words[i..i + length - 1])
+            ));
+            i += length;
+            continue;
+        }
+        numbers.push(literal(words[i]));
+        if numbers.len() == WORDS_PER_LINE {
+            text.push_str(&format!("{INDENT}{}\n", numbers.join(" ")));
+            numbers.clear();
+        }
+        i += 1;
+    }
+    if !numbers.is_empty() {
+        text.push_str(&format!("{INDENT}{}\n", numbers.join(" ")));
+    }
+}
+
+/// Reconstructs the program as sublang. Jump targets get labels starting with '.L' and
+/// other referenced addresses labels starting with 'D', unless the names give them one.
+/// The IO (-1) and debug (-2) addresses are kept as literals
+pub fn disassemble(mem: &[u16], names: &[(usize, String)]) -> String {
+    let is_instruction = find_instructions(mem);
+    let mut labels = Labels {
+        names: HashMap::new(),
+        used: HashSet::new(),
+    };
+    for (address, name) in names {
+        if *address < mem.len()
+            && let Some(name) = sanitise_name(name)
+            && !labels.used.contains(&name)
+        {
+            labels.add(*address, name);
+        }
+    }
+    for pc in (0..mem.len()).filter(|pc| is_instruction[*pc]) {
+        let (a, b, c) = (mem[pc] as usize, mem[pc + 1] as usize, mem[pc + 2] as usize);
+        if c < mem.len() && c != pc + 3 {
+            labels.ensure(c, ".L");
+        }
+        for operand in [a, b] {
+            if operand < mem.len() {
+                labels.ensure(operand, "D");
+            }
+        }
+    }
+
+    let operand = |word: u16| -> String {
+        match word as usize {
+            IO_ADDR => "-1".to_owned(),
+            DEBUG_ADDR => "-2".to_owned(),
+            address if address < mem.len() => labels.get(address)[0].clone(),
+            address if address >= DEVICES_ADDR => literal(word),
+            _ => format!("0x{word:04X}"),
+        }
+    };
+
+    let mut text = String::from("; Disassembled by asa\n");
+    let mut pc = 0;
+    while pc < mem.len() {
+        if is_instruction[pc] {
+            for (offset, arrow) in ["->", "-b>", "-c>"].iter().enumerate() {
+                for name in labels.get(pc + offset) {
+                    text.push_str(&format!("{name} {arrow}\n"));
+                }
+            }
+            let (a, b, c) = (mem[pc], mem[pc + 1], mem[pc + 2]);
+            if c as usize == pc + 3 {
+                text.push_str(&format!("{INDENT}{} -= {}\n", operand(b), operand(a)));
+            } else {
+                text.push_str(&format!(
+                    "{INDENT}{} -= {} {}\n",
+                    operand(b),
+                    operand(a),
+                    operand(c)
+                ));
+            }
+            pc += 3;
+            continue;
+        }
+
+        // Data runs until the next instruction or label
+        for name in labels.get(pc) {
+            text.push_str(&format!("{name} ->\n"));
+        }
+        let mut end = pc + 1;
+        while end < mem.len() && !is_instruction[end] && labels.get(end).is_empty() {
+            end += 1;
+        }
+        push_data(&mut text, &mem[pc..end]);
+        pc = end;
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::assembler::{self, AssemblerConfig};
+    use crate::sources::InMemory;
+
+    fn assemble(text: &str) -> Vec<u16> {
+        let mut sources = InMemory::new();
+        sources.insert("Test.sbl", text);
+        let config = AssemblerConfig {
+            silent: true,
+            sources: Arc::new(sources),
+            ..Default::default()
+        };
+        assembler::assemble(text, "Test.sbl".to_owned(), &config)
+            .unwrap()
+            .0
+            .0
+    }
+
+    #[test]
+    fn reassembles_to_the_same_memory() {
+        let text = "Z -= Z .main\np_string -> \"Hi!\\n\"\nchar -> 0\nN_ONE -> -1\nZ -> 0\n\
+                    .main ->\n    char -= char\n    char -= p_string\n    -1 -= char\n    \
+                    -1 -= N_ONE\n    Z -= Z -1\n";
+        let mem = assemble(text);
+
+        let disassembled = disassemble(&mem, &[]);
+        assert!(disassembled.contains("\"Hi!\\n\""));
+        assert!(disassembled.contains("-1 -= D0008"));
+        assert_eq!(assemble(&disassembled), mem);
+
+        let named = disassemble(&mem, &[(0x8, "char".to_owned()), (0xB, ".main".to_owned())]);
+        assert!(named.contains(".main ->\n    char -= char\n"));
+        assert_eq!(assemble(&named), mem);
+    }
+}
@@ -0,0 +1,182 @@
+//! Writes expanded tokens back as sublang, so what macros and syntax sugar turn into can be read
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    lexer,
+    listing::statement_of,
+    tokens::{Info, IntOrString, LabelOffset, Token, TokenVariant},
+};
+
+const INDENT: &str = "    ";
+
+/// Labels made by the assembler can't be written in sublang. Macros prefix their labels
+/// with '?Macro?' and dereferences name theirs '*N*label'. They are given names starting
+/// with '__' that no label of the program uses
+struct Names {
+    renamed: HashMap<String, String>,
+    used: HashSet<String>,
+}
+
+impl Names {
+    fn new(tokens: &[Token]) -> Self {
+        let used = tokens
+            .iter()
+            .filter_map(|token| match &token.variant {
+                TokenVariant::Label { name } | TokenVariant::BracedLabelDefinition { name, .. } => {
+                    Some(name.clone())
+                }
+                _ => None,
+            })
+            .collect();
+        Names {
+            renamed: HashMap::new(),
+            used,
+        }
+    }
+
+    fn get(&mut self, name: &str) -> String {
+        if let Some(renamed) = self.renamed.get(name) {
+            return renamed.clone();
+        }
+        let mut renamed = if let Some(hygienic) = name.strip_prefix('?') {
+            format!("__{hygienic}")
+        } else if let Some(deref) = name.strip_prefix('*')
+            && let Some((id, label)) = deref.split_once('*')
+        {
+            format!("__deref{id}_{}", self.get(label))
+        } else {
+            return name.to_owned();
+        };
+        while self.used.contains(&renamed) {
+            renamed.push('_');
+        }
+        self.used.insert(renamed.clone());
+        self.renamed.insert(name.to_owned(), renamed.clone());
+        renamed
+    }
+}
+
+fn file_line(info: &Info) -> String {
+    lexer::FILES.with_borrow(|files| match files.get(info.file) {
+        Some(file) => format!("{}:{}", file.path.display(), info.line_number),
+        None => format!("?:{}", info.line_number),
+    })
+}
+
+/// Where a line came from, followed by the macro calls it was expanded from, innermost first
+fn origin_comment(token: &Token) -> String {
+    let (calls, statement) = statement_of(token);
+    let mut comment = format!("; {}", file_line(statement));
+    for call in calls.iter().rev() {
+        comment.push_str(&format!(" <- {}", file_line(call)));
+    }
+    comment
+}
+
+fn token_to_sublang(token: &Token, names: &mut Names) -> String {
+    match &token.variant {
+        TokenVariant::DecLiteral { value } => value.to_string(),
+        TokenVariant::Label { name } => names.get(name),
+        TokenVariant::Subleq => "-=".to_owned(),
+        TokenVariant::Relative { offset } => format!("${offset}"),
+        TokenVariant::LabelArrow { offset } => match offset {
+            LabelOffset::Char(c) => format!("-{c}>"),
+            // The lexer only makes '->'
+            LabelOffset::Int(_) => "->".to_owned(),
+        },
+        TokenVariant::BracedLabelDefinition { name, data } => {
+            let data = match data {
+                IntOrString::Int(value) => value.to_string(),
+                IntOrString::Str(label) => names.get(label),
+            };
+            format!("({} -> {data})", names.get(name))
+        }
+        // Everything else has been expanded by now
+        _ => token.to_string(),
+    }
+}
+
+/// Sublang that assembles to the same memory as the tokens, which come from
+/// parser::expand(). Scopes are indented and consecutive empty lines are collapsed.
+/// With origins every line ends in a comment with the file and line it was written on,
+/// followed by the macro calls it was expanded from.
+///
+/// Example:
+/// {
+///     __Clear?tmp -= __Clear?tmp ; Lib.sbl:3 <- Main.sbl:5
+/// }
+pub fn to_sublang(tokens: &[Token], origins: bool) -> String {
+    let mut names = Names::new(tokens);
+    let mut text = String::new();
+    let mut depth: usize = 0;
+
+    for line in tokens.split(|token| token.variant == TokenVariant::Linebreak) {
+        if line.is_empty() {
+            if !text.is_empty() && !text.ends_with("\n\n") {
+                text.push('\n');
+            }
+            continue;
+        }
+        // A line closing a scope is indented like the line that opened it
+        let closed = line
+            .iter()
+            .take_while(|token| token.variant == TokenVariant::Unscope)
+            .count();
+        text.push_str(&INDENT.repeat(depth.saturating_sub(closed)));
+
+        let mut words = Vec::with_capacity(line.len());
+        for token in line {
+            match token.variant {
+                TokenVariant::Scope => depth += 1,
+                TokenVariant::Unscope => depth = depth.saturating_sub(1),
+                _ => {}
+            }
+            words.push(token_to_sublang(token, &mut names));
+        }
+        text.push_str(&words.join(" "));
+
+        let statement = line
+            .iter()
+            .find(|token| !matches!(token.variant, TokenVariant::Scope | TokenVariant::Unscope));
+        if origins && let Some(statement) = statement {
+            text.push(' ');
+            text.push_str(&origin_comment(statement));
+        }
+        text.push('\n');
+    }
+    text.truncate(text.trim_end().len());
+    text.push('\n');
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::{self, AssemblerConfig};
+
+    #[test]
+    fn reassembles_to_the_same_memory() {
+        let config = AssemblerConfig {
+            silent: true,
+            ..Default::default()
+        };
+        let text = "#ASM\n@Clear a? {\n    a? -= a?\n    tmp -> 0\n}\nZ -= Z .main\n\
+                    p -> \"Hi\" 0 * 2\n.main ->\n    !Clear A\n    A = 5\n    -1 -= *p\n    \
+                    Z -= Z -1\n_ASM -> 0\nZ -> 0\n";
+        let ((mem, _, _), _) = assembler::assemble(text, "Test.sbl".to_owned(), &config).unwrap();
+
+        for origins in [false, true] {
+            let (tokens, _) = assembler::expand(text, "Test.sbl".to_owned(), &config).unwrap();
+            let expanded = to_sublang(&tokens, origins);
+            assert!(!expanded.contains('!'));
+            assert!(expanded.contains("__Clear?tmp"));
+            assert!(expanded.contains("__deref0_p"));
+            if origins {
+                assert!(expanded.contains("; Test.sbl:3 <- Test.sbl:9"));
+            }
+            let ((reassembled, _, _), _) =
+                assembler::assemble(&expanded, "Expanded.sbl".to_owned(), &config).unwrap();
+            assert_eq!(reassembled, mem);
+        }
+    }
+}
@@ -1,17 +1,14 @@
 //! Generates and prints friendly feedback messages for the user
-use crate::lexer;
-use crate::runtimes::RuntimeError;
+use crate::args::MessageFormat;
+use crate::assembler::AssemblerConfig;
+use crate::runtimes::{Limit, RuntimeError};
+use crate::{diagnostic_formats, lexer};
 use crate::{tokens::Info, tokens::Token};
 use colored::{Color, Colorize};
 use core::fmt;
-use std::cell::RefCell;
+use log::LevelFilter;
 use std::fs;
 
-thread_local!(
-    /// The type/level of the current feedback message, used by sub messages
-    /// to check if they have a sufficient level
-    static FEEDBACK_TYPE: RefCell<log::Level> = const { RefCell::new(log::Level::Debug) });
-
 #[derive(PartialEq, Clone, Copy)]
 pub enum Type {
     Info,
@@ -74,152 +71,327 @@ impl Stylise for str {
     }
 }
 
-#[cfg(not(test))]
+/// How serious a diagnostic is. Only errors stop the assembly
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+    Note,
+}
+impl Severity {
+    fn msg_type(&self) -> Type {
+        match self {
+            Severity::Error => Type::Error,
+            Severity::Warning => Type::Warn,
+            Severity::Note => Type::Info,
+        }
+    }
+    pub fn to_log_level(&self) -> log::Level {
+        self.msg_type().to_log_level()
+    }
+    /// The name used in machine readable output, these match the SARIF levels
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+            Severity::Note => "note",
+        }
+    }
+}
+
+/// An error, warning or note produced during assembly. It carries everything needed to
+/// render it, so that the caller decides if and how it is shown. See print_diagnostic()
+#[derive(Debug, Clone, PartialEq)]
+pub struct AssembleError {
+    pub severity: Severity,
+    /// None for errors that don't originate from a token, like a file that can't be found.
+    /// This and the trace are boxed, so results that can fail with an error stay small
+    pub info: Option<Box<Info>>,
+    pub message: String,
+    pub hints: Vec<String>,
+    /// Related locations, like the definition of the macro that was called incorrectly
+    pub details: Vec<(Info, String)>,
+    /// Macro trace of the token that caused the error
+    pub origin_info: Box<[Info]>,
+    /// Where in the assembler the error was raised, only shown in debug builds
+    pub asa_call_origin: &'static str,
+    pub asa_line_number: u32,
+}
+
+impl AssembleError {
+    pub fn new(
+        info: Option<Info>,
+        message: String,
+        asa_call_origin: &'static str,
+        asa_line_number: u32,
+    ) -> Self {
+        AssembleError {
+            severity: Severity::Error,
+            info: info.map(Box::new),
+            message,
+            hints: Vec::new(),
+            details: Vec::new(),
+            origin_info: Box::default(),
+            asa_call_origin,
+            asa_line_number,
+        }
+    }
+
+    pub fn severity(mut self, severity: Severity) -> Self {
+        self.severity = severity;
+        self
+    }
+
+    pub fn hint(mut self, hint: impl Into<String>) -> Self {
+        self.hints.push(hint.into());
+        self
+    }
+
+    pub fn details(mut self, info: &Info, message: impl Into<String>) -> Self {
+        self.details.push((info.clone(), message.into()));
+        self
+    }
+
+    pub fn trace(mut self, origin_info: &[Info]) -> Self {
+        self.origin_info = origin_info.into();
+        self
+    }
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for AssembleError {}
+
+/// All diagnostics of an assembly
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Diagnostics {
+    pub list: Vec<AssembleError>,
+}
+
+impl Diagnostics {
+    /// Records a diagnostic. The passes of the assembler report into it instead of returning,
+    /// so they can continue after an error
+    pub fn push(&mut self, diagnostic: AssembleError) {
+        self.list.push(diagnostic);
+    }
+
+    /// Adds to the most recently reported diagnostic. It should generally only be used by
+    /// asm_hint!(), asm_details!() and asm_trace!()
+    pub fn amend_last(&mut self, amend: impl FnOnce(&mut AssembleError)) {
+        if let Some(last) = self.list.last_mut() {
+            amend(last);
+        }
+    }
+
+    pub fn errors(&self) -> impl Iterator<Item = &AssembleError> {
+        self.list.iter().filter(|d| d.severity == Severity::Error)
+    }
+
+    pub fn error_count(&self) -> usize {
+        self.errors().count()
+    }
+
+    pub fn warning_count(&self) -> usize {
+        self.list
+            .iter()
+            .filter(|d| d.severity == Severity::Warning)
+            .count()
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.errors().next().is_some()
+    }
+
+    /// Orders the diagnostics by file, line and column. Those without a location come first,
+    /// diagnostics at the same location keep the order in which they were reported
+    pub fn sort(&mut self) {
+        self.list.sort_by_key(|d| {
+            d.info
+                .as_ref()
+                .map(|info| (info.file, info.line_number, info.start_char))
+        });
+    }
+
+    /// Example: 2 errors, 1 warning
+    pub fn summary(&self) -> String {
+        fn plural(count: usize, word: &str) -> String {
+            format!("{count} {word}{}", if count == 1 { "" } else { "s" })
+        }
+        format!(
+            "{}, {}",
+            plural(self.error_count(), "error"),
+            plural(self.warning_count(), "warning")
+        )
+    }
+
+    /// The diagnostics the level allows, errors are always shown
+    fn shown(&self, level: LevelFilter) -> Vec<&AssembleError> {
+        self.list
+            .iter()
+            .filter(|d| d.severity == Severity::Error || level >= d.severity.to_log_level())
+            .collect()
+    }
+
+    /// Prints every diagnostic the level allows. Followed by the summary, unless nothing was shown
+    pub fn print(&self, level: LevelFilter) {
+        let shown = self.shown(level);
+        for diagnostic in &shown {
+            print_diagnostic(diagnostic);
+        }
+
+        if shown.iter().any(|d| d.severity != Severity::Note) {
+            let summary = self.summary();
+            if self.has_errors() {
+                println!("\n{}", summary.red().bold());
+            } else {
+                println!("\n{}", summary.yellow().bold());
+            }
+        }
+    }
+
+    /// Shows the diagnostics in the configured message format and writes the SARIF log,
+    /// if the config asks for one
+    pub fn emit(&self, config: &AssemblerConfig) {
+        let level = config.feedback_level.to_log_level();
+        match config.message_format {
+            MessageFormat::Human => self.print(level),
+            // One object per line
+            MessageFormat::Json => {
+                for diagnostic in self.shown(level) {
+                    println!("{}", diagnostic_formats::to_json(diagnostic));
+                }
+            }
+        }
+
+        if let Some(path) = &config.sarif_path {
+            let sarif = diagnostic_formats::to_sarif(self);
+            if let Err(e) = fs::write(path, format!("{sarif:#}")) {
+                log::error!("Couldn't write the SARIF log to {}. {e}", path.display());
+            }
+        }
+    }
+}
+
+impl From<AssembleError> for Diagnostics {
+    fn from(error: AssembleError) -> Self {
+        Diagnostics { list: vec![error] }
+    }
+}
+
+/// Creates an AssembleError originating from a token, without returning it.
+/// Useful for adding hints or inside of closures
 #[macro_export]
-macro_rules! asm_error {
+macro_rules! asm_err {
     ($info:expr, $($arg:tt)*) => {
-        {
-            $crate::feedback::_asm_msg($crate::feedback::Type::Error, format!($($arg)*), $info, file!(), line!());
-            std::process::exit(1);
-        }
+        $crate::feedback::AssembleError::new(
+            Some($crate::tokens::Info::clone($info)),
+            format!($($arg)*),
+            file!(),
+            line!(),
+        )
     };
 }
-#[cfg(test)]
+
+/// Returns an AssembleError from the current function
 #[macro_export]
 macro_rules! asm_error {
     ($info:expr, $($arg:tt)*) => {
-        {
-            panic!("{}", format!($($arg)*))
-        }
+        return Err($crate::asm_err!($info, $($arg)*))
     };
 }
 
-/// Error message for an error that doesn't originate from a token
+/// Returns an AssembleError for an error that doesn't originate from a token
 #[macro_export]
 macro_rules! error {
     ($($arg:tt)*) => {
-        {
-            #[cfg(debug_assertions)]
-            log::error!("{} ({}:{})", format!($($arg)*), file!(), line!());
-            #[cfg(not(debug_assertions))]
-            log::error!("{}", format!($($arg)*), );
-
-            std::process::exit(1);
-        }
+        return Err($crate::feedback::AssembleError::new(None, format!($($arg)*), file!(), line!()))
     };
 }
 
+/// Reports a note to the Diagnostics. Like the other feedback macros it doesn't stop the
+/// assembly, the diagnostics are printed when it has finished
 #[macro_export]
 macro_rules! asm_info {
-    ($info:expr, $($arg:tt)*) => {
-        {
-            $crate::feedback::_asm_msg($crate::feedback::Type::Info, format!($($arg)*), $info, file!(), line!());
-        }
+    ($diagnostics:expr, $info:expr, $($arg:tt)*) => {
+        $diagnostics.push(
+            $crate::asm_err!($info, $($arg)*).severity($crate::feedback::Severity::Note),
+        )
     };
 }
 
-/// A sub message for extra details
+/// Adds extra details, at another location, to the previously reported diagnostic
 #[macro_export]
 macro_rules! asm_details {
-    ($info:expr, $($arg:tt)*) => {
-
-        {
-            $crate::feedback::_asm_msg($crate::feedback::Type::Details, format!($($arg)*), $info, file!(), line!());
-        }
-    };
+    ($diagnostics:expr, $info:expr, $($arg:tt)*) => {{
+        let message = format!($($arg)*);
+        $diagnostics.amend_last(|d| {
+            d.details.push(($crate::tokens::Info::clone($info), message))
+        });
+    }};
 }
 
-/// A type of sub message used for macro traces
+/// Adds a macro trace to the previously reported diagnostic
 #[macro_export]
 macro_rules! asm_trace {
-    ($origin_info:expr) => {{
-        for i in $origin_info.iter().rev() {
-            $crate::feedback::_asm_msg(
-                $crate::feedback::Type::Trace,
-                "".to_string(),
-                i,
-                file!(),
-                line!(),
-            );
-        }
+    ($diagnostics:expr, $origin_info:expr) => {{
+        let origin_info = $origin_info.into();
+        $diagnostics.amend_last(|d| d.origin_info = origin_info);
     }};
 }
-/// Terminate is used for FAILURES
-#[macro_export]
-macro_rules! terminate {
-    () => {
-        std::process::exit(1);
-    };
-}
-
-/// Since the asm_error macro always terminates, sometimes we don't want that, if we
-/// want to write more information and then terminate for example
-#[macro_export]
-macro_rules! asm_error_no_terminate {
-    ($info:expr, $($arg:tt)*) => {
-        {
-            $crate::feedback::_asm_msg($crate::feedback::Type::Error, format!($($arg)*), $info, file!(), line!());
-        }
-    };
-}
 
+/// Reports a warning, or an error if the AssemblerConfig treats warnings as errors
 #[macro_export]
 macro_rules! asm_warn {
-    ($info:expr, $($arg:tt)*) => {
-        {
-            if ($crate::args::exist() && !$crate::args::get().warnings_are_errors) || !$crate::args::exist() {
-                $crate::feedback::_asm_msg($crate::feedback::Type::Warn, format!($($arg)*), $info, file!(), line!());
+    ($diagnostics:expr, $config:expr, $info:expr, $($arg:tt)*) => {
+        $diagnostics.push($crate::asm_err!($info, $($arg)*).severity(
+            if $config.warnings_are_errors {
+                $crate::feedback::Severity::Error
             } else {
-                // If we treat warnings as errors
-                $crate::feedback::_asm_msg($crate::feedback::Type::Error, format!($($arg)*), $info, file!(), line!());
-                std::process::exit(1);
-            }
-        }
+                $crate::feedback::Severity::Warning
+            },
+        ))
     };
 }
 
-/// Display a small hint message under an asm_msg
+/// Adds a small hint message to the previously reported diagnostic
 #[macro_export]
 macro_rules! asm_hint {
-    ($($arg:tt)*) => {
-
-        if $crate::feedback::sub_message_level_check() {
-            println!("      {} {} {}",":".white(), "Hint:".blue(), format!($($arg)*).white().bold())
-
-        }
-    };
+    ($diagnostics:expr, $($arg:tt)*) => {{
+        let hint = format!($($arg)*);
+        $diagnostics.amend_last(|d| d.hints.push(hint));
+    }};
 }
 /// These prints will be silenced by the silence command line argument
 #[macro_export]
 macro_rules! println_silenceable {
     ($($arg:tt)*) => {
         if $crate::args::exist() && !$crate::args::get().silent {
-            println!($($arg)*);
+            // Keeps stdout to one JSON object per line
+            if $crate::args::get().message_format == $crate::args::MessageFormat::Json {
+                eprintln!($($arg)*);
+            } else {
+                println!($($arg)*);
+            }
         }
     };
 }
-/// Read the contents of the file at the index in the FILES vector, which contains
-/// all assembled files
-fn get_file_contents(index: usize) -> String {
-    let path = lexer::FILES.with_borrow(|v| v[index].clone());
-    fs::read_to_string(&path).unwrap_or_else(|_| {
-        crate::error!("Error reading file for file preview: {}", path.display());
-    })
-}
-
-/// Sub messages, like Traces and Hints should not be printed if their parent message (like Warn) is not
-/// printed due to the log level being too low
-pub fn sub_message_level_check() -> bool {
-    FEEDBACK_TYPE.with(|cell| {
-        let t = cell.borrow();
-
-        log::max_level() >= *t
-    })
+/// Prints a hint under the previous message. It should generally only be used by print_diagnostic()
+pub fn _asm_hint(hint: String) {
+    println!(
+        "      {} {} {}",
+        ":".white(),
+        "Hint:".blue(),
+        hint.white().bold()
+    )
 }
 
 /// Prints a pretty error message for errors that happen during the assembly process.
-/// This function is only for errors which are caused by a Token. For other types of error,
-/// see error!(). It should generally only be used by the asm_ macros defined in this file
+/// This function is only for messages which are caused by a Token. It should generally
+/// only be used by print_diagnostic()
 ///
 /// Example:
 /// ERROR + ./subleq/testing.sbl:52:10
@@ -236,26 +408,17 @@ pub fn _asm_msg(
     #[allow(unused)] asa_line_number: u32,
 ) {
     match msg_type {
-        Type::Trace | Type::Details => {
-            if !sub_message_level_check() {
-                return;
-            }
-
-            println!("      |");
-        }
-        _ => {
-            FEEDBACK_TYPE.set(msg_type.to_log_level());
-            if log::max_level() < msg_type.to_log_level() {
-                return;
-            }
-            println!();
-        }
+        Type::Trace | Type::Details => println!("      |"),
+        _ => println!(),
     }
 
-    let name = lexer::FILES.with_borrow(|f| f[info.file].clone());
-
-    let contents = get_file_contents(info.file);
-    let lines = contents.lines().collect::<Vec<&str>>();
+    // The FILES vector contains all assembled files, with the contents that were assembled
+    let Some(file) = lexer::FILES.with_borrow(|f| f.get(info.file).cloned()) else {
+        log::warn!("No file preview available for: {msg}");
+        return;
+    };
+    let name = file.path;
+    let lines = file.contents.lines().collect::<Vec<&str>>();
 
     let title_prefix = match msg_type {
         Type::Details | Type::Trace => "      + ",
@@ -285,7 +448,7 @@ pub fn _asm_msg(
                 "{}{: >5} | {}",
                 file_preview_prefix,
                 format!("{}", info.line_number - (i - 1)).bright_cyan(),
-                lines[(info.line_number - i) as usize]
+                lines.get((info.line_number - i) as usize).unwrap_or(&"")
             );
         }
     }
@@ -296,7 +459,7 @@ pub fn _asm_msg(
         file_preview_prefix,
         format!("{}", info.line_number).color(msg_type.colour()),
         " > ".stylise(msg_type),
-        lines[(info.line_number - 1) as usize]
+        lines.get((info.line_number - 1) as usize).unwrap_or(&"")
     );
     if let Some(x) = &info.sourceline_suffix {
         println!("{} {}", fmt, x.purple());
@@ -339,28 +502,74 @@ pub fn _asm_msg(
     }
 }
 
+/// Prints a diagnostic with its hints, details and macro trace, regardless of the feedback level
+pub fn print_diagnostic(diagnostic: &AssembleError) {
+    match &diagnostic.info {
+        Some(info) => _asm_msg(
+            diagnostic.severity.msg_type(),
+            diagnostic.message.clone(),
+            info,
+            diagnostic.asa_call_origin,
+            diagnostic.asa_line_number,
+        ),
+        None => {
+            let level = diagnostic.severity.to_log_level();
+            #[cfg(debug_assertions)]
+            log::log!(
+                level,
+                "{} ({}:{})",
+                diagnostic.message,
+                diagnostic.asa_call_origin,
+                diagnostic.asa_line_number
+            );
+            #[cfg(not(debug_assertions))]
+            log::log!(level, "{}", diagnostic.message);
+        }
+    }
+    for hint in &diagnostic.hints {
+        _asm_hint(hint.clone());
+    }
+    for (info, message) in &diagnostic.details {
+        _asm_msg(Type::Details, message.clone(), info, file!(), line!());
+    }
+    for info in diagnostic.origin_info.iter().rev() {
+        _asm_msg(Type::Trace, String::new(), info, file!(), line!());
+    }
+}
+
 /// Show a pretty trace for runtime errors
 pub fn asm_runtime_error(e: RuntimeError, tokens: &Option<Vec<Token>>) {
     let (index, message) = match e {
-        RuntimeError::AOutOfRange(pc) => (pc, "Address at 'A' is outside of memory bounds"),
+        RuntimeError::AOutOfRange(pc) => {
+            (pc, "Address at 'A' is outside of memory bounds".to_owned())
+        }
         RuntimeError::BOutOfRange(pc) => (
             if tokens.is_some() { pc + 1 } else { pc },
-            "Address at 'B' is outside of memory bounds",
+            "Address at 'B' is outside of memory bounds".to_owned(),
         ),
         RuntimeError::COutOfRange(pc) => (
             if tokens.is_some() { pc + 2 } else { pc },
-            "Jump outside of memory bounds",
+            "Jump outside of memory bounds".to_owned(),
+        ),
+        RuntimeError::Breakpoint(pc) => (
+            if tokens.is_some() { pc + 2 } else { pc },
+            "Breakpoint".to_owned(),
+        ),
+        RuntimeError::LimitExceeded(pc, Limit::Instructions(max)) => (
+            pc,
+            format!("Stopped here after executing the maximum of {max} instructions"),
+        ),
+        RuntimeError::LimitExceeded(pc, Limit::Time(timeout)) => (
+            pc,
+            format!("Stopped here after running for the maximum of {timeout:.3?}"),
         ),
-        RuntimeError::Breakpoint(pc) => (if tokens.is_some() { pc + 2 } else { pc }, "Breakpoint"),
     };
 
-    if let Some(tokens) = tokens {
-        if index >= tokens.len() {
-            return;
+    // The pc may be past the assembled image, in which case there is no source to show
+    match tokens.as_ref().and_then(|tokens| tokens.get(index)) {
+        Some(token) => {
+            print_diagnostic(&asm_err!(&token.info, "{message}").trace(&token.origin_info))
         }
-        asm_error_no_terminate!(&tokens[index].info, "{message}");
-        asm_trace!(&tokens[index].origin_info);
-    } else {
-        log::error!("{message}. PC: {index}");
+        None => log::error!("{message}. PC: {index}"),
     }
 }
@@ -6,7 +6,14 @@ use std::{
     path::{Path, PathBuf},
 };
 
-use crate::{assembler, tokens::Token};
+use crate::{
+    assembler::{self, AssemblerConfig},
+    error,
+    feedback::{AssembleError, Diagnostics},
+    source_map,
+    symbol_table::Symbol,
+    tokens::Token,
+};
 
 const PLAINTEXT_EXTENSION: &str = "sblx";
 const BINARY_EXTENSION: &str = "bin";
@@ -119,13 +126,15 @@ pub fn to_text(data: &[u16]) -> String {
     text
 }
 
-pub fn from_text(text: &str) -> Vec<u16> {
-    text.split_ascii_whitespace()
-        .map(|val| {
-            val.parse::<u16>()
-                .unwrap_or_else(|_| crate::error!("Invalid u16 in input file"))
-        })
-        .collect()
+pub fn from_text(text: &str) -> Result<Vec<u16>, AssembleError> {
+    let mut data: Vec<u16> = Vec::new();
+    for val in text.split_ascii_whitespace() {
+        match val.parse::<u16>() {
+            Ok(x) => data.push(x),
+            Err(_) => error!("Invalid u16 in input file"),
+        }
+    }
+    Ok(data)
 }
 
 /// Binary format is in Big Endian
@@ -151,7 +160,7 @@ pub fn from_bytes(data: &[u8]) -> Vec<u16> {
     u16data
 }
 
-pub fn to_file(data: &[u16], output: OutputFile) {
+pub fn to_file(data: &[u16], output: OutputFile) -> Result<(), AssembleError> {
     let bytes = match output.file_type {
         OutputFileType::Plaintext => to_text(data).as_bytes().to_vec(),
         OutputFileType::Binary => to_bytes(data),
@@ -159,54 +168,116 @@ pub fn to_file(data: &[u16], output: OutputFile) {
 
     let mut path = output.file_base;
     path.set_extension(output.file_type.extension());
-    let mut file =
-        File::create(path).unwrap_or_else(|e| crate::error!("Failed to create sblx file. {e}"));
-    file.write_all(&bytes)
-        .unwrap_or_else(|e| crate::error!("Failed to write to sblx file. {e}"));
+    let mut file = match File::create(path) {
+        Ok(file) => file,
+        Err(e) => error!("Failed to create sblx file. {e}"),
+    };
+    if let Err(e) = file.write_all(&bytes) {
+        error!("Failed to write to sblx file. {e}");
+    }
+    Ok(())
 }
 
+/// Writes the source map of the tokens and symbols next to where the output file goes
+pub fn to_source_map_file(
+    tokens: &[Token],
+    symbols: &[Symbol],
+    output: &OutputFile,
+) -> Result<(), AssembleError> {
+    let path = source_map::path_for(&output.file_base);
+    if let Err(e) = fs::write(&path, source_map::to_string(tokens, symbols)) {
+        error!("Failed to write the source map {}. {e}", path.display());
+    }
+    Ok(())
+}
+
+/// Memory, the tokens if there is a source and the symbols, which are empty without one
+pub type InputFile = (Vec<u16>, Option<Vec<Token>>, Vec<Symbol>);
+
 /// Reads and processes the target file, returning its memory and if possible the tokens associated with it.
+/// The notes and warnings of an assembly are returned with it, see assembler::assemble().
 /// The assembler can take three types of input files:
 /// * .sbl files will be assembled
-/// * .bin and .sblx files will only be read
+/// * .bin and .sblx files will only be read, their tokens and symbols come from a .sblmap next to them
 pub fn process_input_file(
     target: &PathBuf,
     input_file_type: InputFileType,
-) -> (Vec<u16>, Option<Vec<Token>>) {
-    fn unwrap_contents<T>(contents: Result<T, std::io::Error>, target: &Path) -> T {
-        contents.unwrap_or_else(|e| {
-            crate::error!("Error reading file: {}. {}", target.display(), e);
-        })
+    config: &AssemblerConfig,
+) -> Result<(InputFile, Diagnostics), Diagnostics> {
+    fn unwrap_contents<T>(
+        contents: Result<T, std::io::Error>,
+        target: &Path,
+    ) -> Result<T, AssembleError> {
+        match contents {
+            Ok(contents) => Ok(contents),
+            Err(e) => error!("Error reading file: {}. {}", target.display(), e),
+        }
     }
 
     match input_file_type {
         InputFileType::Sublang => {
-            let contents = fs::read_to_string(target);
-            let contents = unwrap_contents(contents, target);
+            let contents = config.sources.read_file(target);
+            let contents = unwrap_contents(contents, target)?;
 
-            let (mem, tokens) =
-                assembler::assemble(&contents, target.to_str().unwrap().to_string());
+            let ((mem, tokens, symbols), diagnostics) =
+                assembler::assemble(&contents, target.to_str().unwrap().to_string(), config)?;
 
-            (mem, Some(tokens))
+            Ok(((mem, Some(tokens), symbols), diagnostics))
         }
         InputFileType::Binary => {
             let contents = fs::read(target);
-            let contents = unwrap_contents(contents, target);
-
-            (from_bytes(&contents), None)
+            let contents = unwrap_contents(contents, target)?;
+
+            let mem = from_bytes(&contents);
+            let (tokens, symbols) = source_map::load_sidecar(target, &mem).unzip();
+            Ok((
+                (mem, tokens, symbols.unwrap_or_default()),
+                Diagnostics::default(),
+            ))
         }
         InputFileType::Plaintext => {
             let contents = fs::read_to_string(target);
-            let contents = unwrap_contents(contents, target);
+            let contents = unwrap_contents(contents, target)?;
+
+            let mem = from_text(&contents)?;
+            let (tokens, symbols) = source_map::load_sidecar(target, &mem).unzip();
+            Ok((
+                (mem, tokens, symbols.unwrap_or_default()),
+                Diagnostics::default(),
+            ))
+        }
+    }
+}
 
-            (from_text(&contents), None)
+/// Reads a .sbl target and expands it, see assembler::expand()
+pub fn expand_input_file(
+    target: &Path,
+    input_file_type: InputFileType,
+    config: &AssemblerConfig,
+) -> Result<(Vec<Token>, Diagnostics), Diagnostics> {
+    fn read(
+        target: &Path,
+        input_file_type: InputFileType,
+        config: &AssemblerConfig,
+    ) -> Result<String, AssembleError> {
+        if input_file_type != InputFileType::Sublang {
+            error!("Only .sbl files can be expanded");
+        }
+        match config.sources.read_file(target) {
+            Ok(contents) => Ok(contents),
+            Err(e) => error!("Error reading file: {}. {}", target.display(), e),
         }
     }
+
+    let contents = read(target, input_file_type, config)?;
+    assembler::expand(&contents, target.to_str().unwrap().to_string(), config)
 }
 
 /// Process the target argument for the assembler. It returns the path of the target file
 /// the type of the input and the module (parent folder) name
-pub fn get_target_and_module_name(argument: Option<String>) -> (PathBuf, InputFileType, String) {
+pub fn get_target_and_module_name(
+    argument: Option<String>,
+) -> Result<(PathBuf, InputFileType, String), AssembleError> {
     let target = argument.unwrap_or_else(|| ".".to_string());
     let cwd = env::current_dir().unwrap();
 
@@ -228,19 +299,15 @@ pub fn get_target_and_module_name(argument: Option<String>) -> (PathBuf, InputFi
         target_path
     };
 
-    let input_file_type = InputFileType::from_str(
-        target
-            .extension()
-            .unwrap_or_else(|| {
-                crate::error!("Target file does not have a file extension");
-            })
-            .to_str()
-            .unwrap(),
-    )
-    .unwrap_or_else(|| {
-        crate::error!("Can't assemble a file with this file extension");
-    });
-    (target, input_file_type, module)
+    let extension = match target.extension() {
+        Some(extension) => extension.to_str().unwrap(),
+        None => error!("Target file does not have a file extension"),
+    };
+    let input_file_type = match InputFileType::from_str(extension) {
+        Some(input_file_type) => input_file_type,
+        None => error!("Can't assemble a file with this file extension"),
+    };
+    Ok((target, input_file_type, module))
 }
 
 mod tests {
@@ -310,17 +377,17 @@ mod tests {
 
     #[test]
     fn target_and_module_name() {
-        let (target, input_file_type, module) = get_target_and_module_name(None);
+        let (target, input_file_type, module) = get_target_and_module_name(None).unwrap();
         assert_eq!(target, Path::new("./Main.sbl"));
         assert_eq!(module, "asa");
         assert_eq!(input_file_type, InputFileType::Sublang);
         let (target, input_file_type, module) =
-            get_target_and_module_name(Some("subleq/tests/Fibonacci.sbl".to_owned()));
+            get_target_and_module_name(Some("subleq/tests/Fibonacci.sbl".to_owned())).unwrap();
         assert_eq!(target, Path::new("subleq/tests/Fibonacci.sbl"));
         assert_eq!(module, "Fibonacci");
         assert_eq!(input_file_type, InputFileType::Sublang);
         let (target, input_file_type, module) =
-            get_target_and_module_name(Some("subleq".to_owned()));
+            get_target_and_module_name(Some("subleq".to_owned())).unwrap();
         assert_eq!(target, Path::new("subleq/Main.sbl"));
         assert_eq!(module, "subleq");
         assert_eq!(input_file_type, InputFileType::Sublang);
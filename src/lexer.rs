@@ -1,21 +1,28 @@
 //! Converts a string into a vector of tokens, resolving includes along the way
 
-use crate::{args, terminate};
+use crate::assembler::AssemblerConfig;
+use crate::feedback::AssembleError;
 use crate::{
-    asm_error, asm_error_no_terminate, asm_hint,
+    asm_err, asm_error,
     tokens::{Info, LabelOffset, Token, TokenVariant},
 };
-use colored::Colorize;
 use std::cell::RefCell;
-use std::path::{self, Path, PathBuf};
-use std::{env, fs};
+use std::path::{Path, PathBuf};
 
 use log::LevelFilter;
 use unescape::unescape;
 
+/// A file that is part of the assembly. Its (sanitised) contents are kept so file previews
+/// show exactly what was assembled, without reading the file again
+#[derive(Debug, Clone)]
+pub struct SourceFile {
+    pub path: PathBuf,
+    pub contents: String,
+}
+
 thread_local! {
     // Array of all included files
-    pub static FILES: RefCell<Vec<PathBuf>> = const { RefCell::new(vec![]) };
+    pub static FILES: RefCell<Vec<SourceFile>> = const { RefCell::new(vec![]) };
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -69,8 +76,8 @@ fn updated_context(
     buffer: &str,
     cur_char: char,
     info: &Info,
-) -> (Context, Option<char>, Option<TokenVariant>) {
-    match context {
+) -> Result<(Context, Option<char>, Option<TokenVariant>), AssembleError> {
+    Ok(match context {
         Context::DontConsume => unreachable!(),
 
         Context::None => match cur_char {
@@ -106,9 +113,9 @@ fn updated_context(
             '#' => (Context::Inclusion, None, None),
 
             '?' => {
-                asm_error_no_terminate!(info, "Unexpected character");
-                asm_hint!("Labels may not start with a '?'");
-                terminate!();
+                return Err(
+                    asm_err!(info, "Unexpected character").hint("Labels may not start with a '?'")
+                );
             }
             _ => asm_error!(info, "Unexpected character"),
         },
@@ -167,13 +174,13 @@ fn updated_context(
             '>' => {
                 let ch: char = buffer.chars().nth(1).unwrap();
                 if ch == 'a' || ch == 'b' || ch == 'c' {
-                    return (
+                    return Ok((
                         Context::None,
                         Some(cur_char),
                         Some(TokenVariant::LabelArrow {
                             offset: LabelOffset::Char(ch),
                         }),
-                    );
+                    ));
                 }
                 asm_error!(info, "Unexpected character");
             }
@@ -184,12 +191,11 @@ fn updated_context(
             'x' => (Context::Hex, None, None),
             c if c.is_ascii_digit() => (Context::Dec, Some(c), None),
             c if c.is_ascii_alphabetic() => {
-                asm_error_no_terminate!(
+                return Err(asm_err!(
                     info,
                     "Unexpected character when defining Hex or Dec literal",
-                );
-                asm_hint!("Labels may not start with a number");
-                terminate!();
+                )
+                .hint("Labels may not start with a number"));
             }
             _ => (
                 Context::DontConsume,
@@ -225,7 +231,7 @@ fn updated_context(
                 Some(TokenVariant::DecLiteral {
                     value: buffer
                         .parse::<i32>()
-                        .unwrap_or_else(|_| asm_error!(&info, "Invalid decimal literal")),
+                        .map_err(|_| asm_err!(info, "Invalid decimal literal"))?,
                 }),
             ),
         },
@@ -268,9 +274,9 @@ fn updated_context(
                 Context::None,
                 None,
                 Some(TokenVariant::CharLiteral {
-                    value: buffer.chars().next().unwrap_or_else(|| {
-                        asm_error!(&info, "Char literal does not contain a character")
-                    }),
+                    value: buffer.chars().next().ok_or_else(|| {
+                        asm_err!(info, "Char literal does not contain a character")
+                    })?,
                 }),
             ),
             _ => (Context::Char, Some(cur_char), None),
@@ -281,7 +287,7 @@ fn updated_context(
                 None,
                 Some(TokenVariant::CharLiteral {
                     value: unescape(&format!("\\{}", buffer.chars().next().unwrap()))
-                        .unwrap_or_else(|| asm_error!(&info, "Invalid escape sequence"))
+                        .ok_or_else(|| asm_err!(info, "Invalid escape sequence"))?
                         .chars()
                         .next()
                         .unwrap(),
@@ -306,7 +312,7 @@ fn updated_context(
                 let offset = if !buffer.is_empty() {
                     buffer
                         .parse::<i32>()
-                        .unwrap_or_else(|_| crate::error!("Relative offsets must be decimal"))
+                        .map_err(|_| asm_err!(info, "Relative offsets must be decimal"))?
                 } else {
                     asm_error!(info, "Expected an offset",);
                 };
@@ -318,103 +324,111 @@ fn updated_context(
                 )
             }
         },
-    }
+    })
 }
 
 /// Takes a string, recursively solves includes and tokenises everything whilst
 /// keeping track of the metadata of each token, like the file it originates from
-pub fn tokenise(text: String, path: String) -> Vec<Token> {
-    FILES.set(vec![Path::new(&path).to_path_buf()]);
+pub fn tokenise(
+    text: String,
+    path: String,
+    config: &AssemblerConfig,
+) -> Result<Vec<Token>, AssembleError> {
+    let text = generic_sanitisation(&text);
+    FILES.set(vec![SourceFile {
+        path: Path::new(&path).to_path_buf(),
+        contents: text.clone(),
+    }]);
 
     // Includes are resolved relative to the file being assembled
-    let base_dir = path::absolute(Path::new(&path).to_path_buf().parent().unwrap())
-        .unwrap_or_else(|_| env::current_dir().unwrap());
+    let parent = match Path::new(&path).parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let base_dir = config.sources.canonicalise(parent);
 
     let result = recursive_tokenisation(
         text,
         0,
-        &mut vec![Path::new(&path).to_path_buf()],
+        &mut vec![config.sources.canonicalise(Path::new(&path))],
         &base_dir,
-    );
+        config,
+    )?;
     log::debug!("Included files");
-    if log::max_level() >= LevelFilter::Debug {
-        FILES.with_borrow(|files| println!("{files:#?}"));
-    }
-    result
-}
-
-fn fix_include_path(path: &mut PathBuf) {
-    // When trying to import a folder, it looks for a file named Lib.sbl in the folder
-    if path.is_dir() {
-        path.push("Lib");
-    }
-
-    // Imports in sublang don't need an extension but the filesystem does
-    if path.extension().is_none() {
-        path.set_extension("sbl");
+    if config.feedback_level.to_log_level() >= LevelFilter::Debug {
+        FILES.with_borrow(|files| {
+            for file in files {
+                println!("{}", file.path.display());
+            }
+        });
     }
+    Ok(result)
 }
 
 fn include(
-    name: &String,
+    name: &str,
+    info: &Info,
     currently_imported: &mut Vec<PathBuf>,
     base_dir: &Path,
-) -> Option<Vec<Token>> {
-    let mut path = base_dir.to_path_buf().join(name);
-    fix_include_path(&mut path);
-    if !path.is_file() {
-        let libs_path = if args::exist() {
-            &args::get().libs_path
-        } else {
-            "./subleq/libs"
-        };
-        let mut new_path = Path::new(libs_path).to_path_buf().join(name);
-        fix_include_path(&mut new_path);
-        if !new_path.is_file() {
-            log::error!(
-                "File to include not found locally: {} or in the libraries folder: {}",
-                path.display(),
-                new_path.display()
-            );
-            log::info!("Make sure the library path is correctly set using the '-l' argument");
-            terminate!();
+    config: &AssemblerConfig,
+) -> Result<Option<Vec<Token>>, AssembleError> {
+    let sources = &config.sources;
+    let path = match sources.resolve_include(name, base_dir, &config.libs_paths) {
+        Some(path) => path,
+        None => {
+            let candidates = sources.include_candidates(name, base_dir, &config.libs_paths);
+            let searched = candidates[1..]
+                .iter()
+                .map(|p| p.display().to_string())
+                .collect::<Vec<String>>()
+                .join(", ");
+            return Err(asm_err!(
+                info,
+                "File to include not found locally: {} or in the libraries folder(s): {}",
+                candidates[0].display(),
+                searched
+            )
+            .hint("Make sure the library path is correctly set using the '-l' argument"));
         }
-        path = new_path;
-    }
+    };
 
-    let exists = FILES.with_borrow_mut(|files| {
-        if !files.contains(&path) {
-            files.push(path.clone());
-            return false;
-        }
-        true
-    });
+    let canonical = sources.canonicalise(&path);
+    if currently_imported.contains(&canonical) {
+        return Ok(None);
+    }
+    currently_imported.push(canonical);
 
-    if !exists {
-        let contents = fs::read_to_string(&path).unwrap_or_else(|_| {
-            crate::error!("Couldn't include the file: '{path:?}'");
+    let contents = match sources.read_file(&path) {
+        Ok(contents) => generic_sanitisation(&contents),
+        Err(e) => asm_error!(info, "Couldn't include the file: '{path:?}'. {e}"),
+    };
+    let file_idx = FILES.with_borrow_mut(|files| {
+        files.push(SourceFile {
+            path,
+            contents: contents.clone(),
         });
-        Some(recursive_tokenisation(
-            contents,
-            FILES.with_borrow_mut(|files| files.len() - 1),
-            currently_imported,
-            base_dir,
-        ))
-    } else {
-        None
-    }
+        files.len() - 1
+    });
+    Ok(Some(recursive_tokenisation(
+        contents,
+        file_idx,
+        currently_imported,
+        base_dir,
+        config,
+    )?))
 }
 
-/// Recursion occurs on file includes
+/// Recursion occurs on file includes. The text has to be sanitised already, so it matches
+/// the contents kept in FILES
 fn recursive_tokenisation(
     mut text: String,
     file_idx: usize,
     currently_imported: &mut Vec<PathBuf>,
     base_dir: &Path,
-) -> Vec<Token> {
+    config: &AssemblerConfig,
+) -> Result<Vec<Token>, AssembleError> {
     let mut result_tokens: Vec<Token> = Vec::new();
 
-    text = generic_sanitisation(&text);
     text.push('\n'); // Little hack
 
     let mut context: Context = Context::None;
@@ -430,7 +444,7 @@ fn recursive_tokenisation(
     for c in text.chars() {
         loop {
             let (new_context, add_to_buffer, variant_to_add) =
-                updated_context(&context, &buffer, c, &info);
+                updated_context(&context, &buffer, c, &info)?;
 
             context = new_context;
             info.start_char = column - info.length;
@@ -440,10 +454,11 @@ fn recursive_tokenisation(
             }
 
             if let Some(var) = &variant_to_add {
-                if let TokenVariant::Inclusion { path } = var {
-                    if let Some(mut toks) = include(path, currently_imported, base_dir) {
-                        result_tokens.append(&mut toks);
-                    }
+                if let TokenVariant::Inclusion { path } = var
+                    && let Some(mut toks) =
+                        include(path, &info, currently_imported, base_dir, config)?
+                {
+                    result_tokens.append(&mut toks);
                 }
 
                 if context == Context::None {
@@ -497,5 +512,5 @@ fn recursive_tokenisation(
         column += 1;
     }
 
-    result_tokens
+    Ok(result_tokens)
 }
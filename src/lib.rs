@@ -6,12 +6,21 @@
 pub mod args;
 pub mod assembler;
 pub mod codegen;
+pub mod coverage;
+pub mod diagnostic_formats;
+pub mod disassembler;
+pub mod expansion;
 pub mod feedback;
 pub mod files;
 pub mod lexer;
+pub mod listing;
 pub mod mem_view;
 pub mod parser;
+pub mod profiler;
 pub mod runtimes;
+pub mod source_map;
+pub mod sources;
+pub mod symbol_table;
 pub mod symbols;
 pub mod tokens;
 pub mod utils;
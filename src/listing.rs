@@ -0,0 +1,186 @@
+//! A classic assembler listing: every word with its address, labels and the source it came from
+use std::{fs, path::Path};
+
+use crate::{
+    error, feedback::AssembleError, lexer, symbol_table::Symbol, tokens::Info, tokens::Token,
+};
+
+const WORDS_PER_ROW: usize = 3;
+/// Wide enough for a row of three words
+const WORDS_WIDTH: usize = 4 * WORDS_PER_ROW + WORDS_PER_ROW - 1;
+/// The address and words columns, which lines without words leave empty
+const PREFIX_WIDTH: usize = 4 + 2 + WORDS_WIDTH + 2;
+const INDENT: &str = "  ";
+
+/// The location and text of a source line
+pub(crate) fn source_line(info: &Info) -> String {
+    lexer::FILES.with_borrow(|files| match files.get(info.file) {
+        Some(file) => {
+            let line = file
+                .contents
+                .lines()
+                .nth((info.line_number - 1).max(0) as usize)
+                .unwrap_or("")
+                .trim();
+            format!("{}:{: <5} {line}", file.path.display(), info.line_number)
+        }
+        None => format!("?:{}", info.line_number),
+    })
+}
+
+/// The macro calls a word was expanded from, outermost first, and the statement it belongs to.
+/// The last entry of the origin info is the statement in the macro body
+pub(crate) fn statement_of(token: &Token) -> (&[Info], &Info) {
+    match token.origin_info.split_last() {
+        Some((statement, calls)) => (calls, statement),
+        None => (&[], &token.info),
+    }
+}
+
+fn same_line(a: &Info, b: &Info) -> bool {
+    a.file == b.file && a.line_number == b.line_number
+}
+
+/// Words are grouped per source statement, macro expansions are indented under their call.
+///
+/// Example:
+///                       Main.sbl:5     !Clear A
+/// 0000  0004 0004 0003    Main.sbl:2     a? -= a?
+///                       A:
+/// 0003  0000            Main.sbl:6     A -> 0
+pub fn to_string(mem: &[u16], tokens: &[Token], symbols: &[Symbol]) -> String {
+    let mut text = String::new();
+    // The symbols are sorted by address
+    let mut labels = symbols
+        .iter()
+        .map(|symbol| (symbol.address, &symbol.name))
+        .peekable();
+    let mut previous_calls: &[Info] = &[];
+    // Where a statement was split by a label defined inside of it
+    let mut split_at = None;
+
+    let mut start = 0;
+    while start < tokens.len() {
+        let (calls, statement) = statement_of(&tokens[start]);
+        let mut end = start + 1;
+        while end < tokens.len() {
+            let (next_calls, next_statement) = statement_of(&tokens[end]);
+            if next_calls != calls || !same_line(next_statement, statement) {
+                break;
+            }
+            end += 1;
+        }
+        // The rest of the statement is shown after the label, so it is at its own address
+        let continues = split_at == Some(start);
+        split_at = None;
+        if let Some(address) = symbols
+            .iter()
+            .map(|symbol| symbol.address)
+            .find(|address| (start + 1..end).contains(address))
+        {
+            end = address;
+            split_at = Some(end);
+        }
+
+        // The macro calls this statement is expanded from, which haven't been shown yet
+        let shared = previous_calls
+            .iter()
+            .zip(calls)
+            .take_while(|(a, b)| a == b)
+            .count();
+        for (depth, call) in calls.iter().enumerate().skip(shared) {
+            text.push_str(&format!(
+                "{:<PREFIX_WIDTH$}{}{}\n",
+                "",
+                INDENT.repeat(depth),
+                source_line(call),
+            ));
+        }
+        previous_calls = calls;
+
+        let indent = INDENT.repeat(calls.len());
+        while let Some((_, name)) = labels.next_if(|(address, _)| *address < end) {
+            text.push_str(&format!("{:<PREFIX_WIDTH$}{indent}{name}:\n", ""));
+        }
+
+        for (row, words) in mem[start..end].chunks(WORDS_PER_ROW).enumerate() {
+            let words = words
+                .iter()
+                .map(|word| format!("{word:04X}"))
+                .collect::<Vec<String>>()
+                .join(" ");
+            let source = if row == 0 && !continues {
+                source_line(statement)
+            } else {
+                String::new()
+            };
+            let line = format!(
+                "{:04X}  {words:<WORDS_WIDTH$}  {indent}{source}",
+                start + row * WORDS_PER_ROW
+            );
+            text.push_str(line.trim_end());
+            text.push('\n');
+        }
+        start = end;
+    }
+
+    // Labels may point past the end of the program
+    for (address, name) in labels {
+        text.push_str(&format!("{address:04X}  {:<WORDS_WIDTH$}  {name}:\n", ""));
+    }
+    text
+}
+
+pub fn to_file(
+    path: &Path,
+    mem: &[u16],
+    tokens: &[Token],
+    symbols: &[Symbol],
+) -> Result<(), AssembleError> {
+    if let Err(e) = fs::write(path, to_string(mem, tokens, symbols)) {
+        error!("Failed to write the listing {}. {e}", path.display());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble_for_test;
+
+    #[test]
+    fn listing_with_macro() {
+        let text = "@Clear a? {\n    a? -= a?\n}\n!Clear A\nA -> 0\n";
+        let (mem, tokens, symbols) = assemble_for_test(text);
+
+        let listing = to_string(&mem, &tokens, &symbols);
+        let lines = listing.lines().collect::<Vec<&str>>();
+        assert_eq!(
+            lines,
+            vec![
+                "                      Test.sbl:4     !Clear A",
+                "0000  0003 0003 0003    Test.sbl:2     a? -= a?",
+                "                      A:",
+                "0003  0000            Test.sbl:5     A -> 0",
+            ]
+        );
+    }
+
+    #[test]
+    fn label_inside_a_statement() {
+        let text = "A -> 1 B -> 2\n";
+        let (mem, tokens, symbols) = assemble_for_test(text);
+
+        let listing = to_string(&mem, &tokens, &symbols);
+        let lines = listing.lines().collect::<Vec<&str>>();
+        assert_eq!(
+            lines,
+            [
+                "                      A:",
+                "0000  0001            Test.sbl:1     A -> 1 B -> 2",
+                "                      B:",
+                "0001  0002",
+            ]
+        );
+    }
+}
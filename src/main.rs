@@ -1,14 +1,180 @@
 use asa::{
-    args::{self},
-    feedback::asm_runtime_error,
+    args::{self, Command},
+    assembler::AssemblerConfig,
+    coverage, disassembler, expansion,
+    feedback::{AssembleError, Diagnostics, asm_runtime_error},
     files::{self, OutputFile},
-    println_silenceable,
-    runtimes::{debugger, interpreter},
+    listing, println_silenceable,
+    profiler::{self, Profile},
+    runtimes::{
+        breakpoints::Breakpoints,
+        debugger,
+        devices::{self, Devices},
+        interpreter,
+        io::{Buffered, Io, Pipe, Terminal},
+        machine::Machine,
+        trace::{Trace, TraceWriter},
+        watchpoints::Watchpoints,
+    },
+    symbol_table::{self, Symbol},
+    tokens::Token,
     utils,
 };
 use simple_logger::SimpleLogger;
+use std::fs;
+use std::io::IsTerminal;
+use std::path::Path;
 use std::time::Instant;
 
+/// How many of the hottest lines and macros --profile shows
+const PROFILE_ROWS: usize = 15;
+
+/// The only place where errors are reported and the process is terminated
+fn exit_with_error(e: impl Into<Diagnostics>, config: &AssemblerConfig) -> ! {
+    e.into().emit(config);
+    std::process::exit(1);
+}
+
+/// For files read or written after a successful assembly. They are logged instead of
+/// emitted, which would replace the SARIF log of the assembly
+fn exit_with_file_error(e: AssembleError) -> ! {
+    log::error!("{e}");
+    std::process::exit(1);
+}
+
+/// Disassembles the target. Names come from the given symbol table, or from the source
+/// the target was assembled from
+fn disasm(
+    target: &str,
+    output: &Option<String>,
+    symbols: &Option<String>,
+    config: &AssemblerConfig,
+) {
+    let (target, input_file_type, _) = files::get_target_and_module_name(Some(target.to_owned()))
+        .unwrap_or_else(|e| exit_with_error(e, config));
+    let ((mem, _, assembled_symbols), diagnostics) =
+        files::process_input_file(&target, input_file_type, config)
+            .unwrap_or_else(|e| exit_with_error(e, config));
+    diagnostics.emit(config);
+
+    let names = match symbols {
+        Some(path) => symbol_table::names_from_file(Path::new(path))
+            .unwrap_or_else(|e| exit_with_file_error(e)),
+        None => assembled_symbols
+            .into_iter()
+            .map(|symbol| (symbol.address, symbol.name))
+            .collect(),
+    };
+    let text = disassembler::disassemble(&mem, &names);
+
+    match output {
+        Some(path) => {
+            if let Err(e) = fs::write(path, text) {
+                log::error!("Failed to write the disassembly {path}. {e}");
+                std::process::exit(1);
+            }
+        }
+        None => print!("{text}"),
+    }
+}
+
+/// Expands the target, see expansion::to_sublang()
+fn expand(target: &str, output: &Option<String>, origins: bool, config: &AssemblerConfig) {
+    let (target, input_file_type, _) = files::get_target_and_module_name(Some(target.to_owned()))
+        .unwrap_or_else(|e| exit_with_error(e, config));
+    let (tokens, diagnostics) = files::expand_input_file(&target, input_file_type, config)
+        .unwrap_or_else(|e| exit_with_error(e, config));
+    diagnostics.emit(config);
+    let text = expansion::to_sublang(&tokens, origins);
+
+    match output {
+        Some(path) => {
+            if let Err(e) = fs::write(path, text) {
+                log::error!("Failed to write the expanded sublang {path}. {e}");
+                std::process::exit(1);
+            }
+        }
+        None => print!("{text}"),
+    }
+}
+
+/// The breakpoints given with --break
+fn breakpoints(tokens: &[Token], symbols: &[Symbol]) -> Breakpoints {
+    let mut breakpoints = Breakpoints::default();
+    for location in &args::get().breakpoints {
+        if let Err(e) = breakpoints.add(location, tokens, symbols) {
+            log::error!("{e}");
+            std::process::exit(1);
+        }
+    }
+    breakpoints
+}
+
+/// The watchpoints given with --watch
+fn watchpoints(memory_size: usize, symbols: &[Symbol]) -> Watchpoints {
+    let mut watchpoints = Watchpoints::default();
+    for watchpoint in &args::get().watchpoints {
+        if let Err(e) = watchpoints.add(watchpoint, memory_size, symbols) {
+            log::error!("{e}");
+            std::process::exit(1);
+        }
+    }
+    watchpoints
+}
+
+/// Steps through a recorded run of the target, see debugger::replay()
+fn replay(target: &str, trace: &str, config: &AssemblerConfig) {
+    let (target, input_file_type, _) = files::get_target_and_module_name(Some(target.to_owned()))
+        .unwrap_or_else(|e| exit_with_error(e, config));
+    let ((mut mem, tokens, symbols), diagnostics) =
+        files::process_input_file(&target, input_file_type, config)
+            .unwrap_or_else(|e| exit_with_error(e, config));
+    diagnostics.emit(config);
+    let Some(tokens) = tokens else {
+        log::error!("Can't replay .SBLX or .BIN files without a source map");
+        std::process::exit(1);
+    };
+
+    let trace = match fs::File::open(trace) {
+        Ok(file) => Trace::read(std::io::BufReader::new(file), &mem),
+        Err(e) => Err(format!("Failed to open the trace {trace}. {e}")),
+    }
+    .unwrap_or_else(|e| {
+        log::error!("{e}");
+        std::process::exit(1);
+    });
+    // Recorded with full memory
+    if trace.memory_size > mem.len() {
+        mem.resize(trace.memory_size, 0);
+    }
+    let mut machine = Machine::new(mem, Buffered::default());
+    let mut watchpoints = watchpoints(machine.mem.len(), &symbols);
+    debugger::replay(
+        &mut machine,
+        &tokens,
+        &symbols,
+        &trace,
+        &mut breakpoints(&tokens, &symbols),
+        &mut watchpoints,
+    );
+}
+
+/// Input comes from --input, from stdin when something is piped into it, or else from the keyboard
+fn program_io() -> Box<dyn Io> {
+    let eof = args::get().eof;
+    match &args::get().input {
+        Some(path) => match Pipe::file(Path::new(path), eof) {
+            Ok(pipe) => Box::new(pipe),
+            Err(e) => {
+                log::error!("Failed to open the input file {path}. {e}");
+                std::process::exit(1);
+            }
+        },
+        None if !std::io::stdin().is_terminal() => Box::new(Pipe::stdin(eof)),
+        None => Box::new(Terminal),
+    }
+}
+
 fn main() {
     // Setup
     SimpleLogger::new().init().unwrap();
@@ -18,16 +184,68 @@ fn main() {
         log::set_max_level(log::LevelFilter::Error);
     }
 
+    let config = AssemblerConfig::from(args::get());
+
+    match &args::get().command {
+        Some(Command::Disasm {
+            target,
+            output,
+            symbols,
+        }) => {
+            disasm(target, output, symbols, &config);
+            return;
+        }
+        Some(Command::Expand {
+            target,
+            output,
+            origins,
+        }) => {
+            expand(target, output, *origins, &config);
+            return;
+        }
+        Some(Command::Replay { target, trace }) => {
+            replay(target, trace, &config);
+            return;
+        }
+        None => {}
+    }
+
     let (target, input_file_type, module) =
-        files::get_target_and_module_name(args::get().target.clone());
+        files::get_target_and_module_name(args::get().target.clone())
+            .unwrap_or_else(|e| exit_with_error(e, &config));
     let output_file = OutputFile::new(&args::get().output, module.clone());
 
     // Assembly or file reading
-    let (mut mem, tokens) = files::process_input_file(&target, input_file_type);
+    let ((mem, tokens, symbols), diagnostics) =
+        files::process_input_file(&target, input_file_type, &config)
+            .unwrap_or_else(|e| exit_with_error(e, &config));
+    diagnostics.emit(&config);
 
     // Output
+    if let Some(path) = &args::get().symbols {
+        if tokens.is_some() {
+            symbol_table::to_file(Path::new(path), &symbols)
+                .unwrap_or_else(|e| exit_with_file_error(e));
+        } else {
+            log::warn!("No symbol table can be written, the input has no source");
+        }
+    }
+    if let Some(path) = &args::get().listing {
+        match &tokens {
+            Some(tokens) => listing::to_file(Path::new(path), &mem, tokens, &symbols)
+                .unwrap_or_else(|e| exit_with_file_error(e)),
+            None => log::warn!("No listing can be written, the input has no source"),
+        }
+    }
     if let Some(output) = output_file {
-        files::to_file(&mem, output);
+        if args::get().source_map {
+            match &tokens {
+                Some(tokens) => files::to_source_map_file(tokens, &symbols, &output)
+                    .unwrap_or_else(|e| exit_with_file_error(e)),
+                None => log::warn!("No source map can be written, the input has no source"),
+            }
+        }
+        files::to_file(&mem, output).unwrap_or_else(|e| exit_with_file_error(e));
     }
 
     // Execution
@@ -38,22 +256,79 @@ fn main() {
 
     println_silenceable!("{}", "-".repeat(80));
 
-    if args::get().debugger {
+    let image_length = mem.len();
+    let mut machine = Machine::new(mem, program_io());
+    if args::get().full_memory {
+        machine.use_full_memory();
+    }
+    let seed = args::get().seed.unwrap_or_else(devices::clock_seed);
+    machine.devices = Devices::from_kinds(&args::get().devices, seed).unwrap_or_else(|e| {
+        log::error!("{e}");
+        std::process::exit(1);
+    });
+    machine.max_instructions = args::get().max_instructions;
+    machine.timeout = args::get().timeout;
+    if args::get().debugger
+        || !args::get().breakpoints.is_empty()
+        || !args::get().watchpoints.is_empty()
+    {
         if let Some(tokens) = tokens {
-            debugger::run_with_debugger(&mut mem, &tokens);
+            let mut watchpoints = watchpoints(machine.mem.len(), &symbols);
+            debugger::run_with_debugger(
+                &mut machine,
+                &tokens,
+                &symbols,
+                &mut breakpoints(&tokens, &symbols),
+                &mut watchpoints,
+            );
+            if let Some(code) = machine.exit_code {
+                std::process::exit(devices::exit_status(code));
+            }
             return;
         } else {
-            log::error!("Can't run .SBLX or .BIN files with the debugger");
+            log::error!("Can't run .SBLX or .BIN files without a source map with the debugger");
+            std::process::exit(1);
         }
-        return;
     }
+    let profiling = args::get().profile
+        || args::get().profile_folded.is_some()
+        || args::get().coverage.is_some();
+    if profiling && tokens.is_none() {
+        log::warn!("No profile or coverage can be made, the input has no source");
+    }
+    let mut profile = (profiling && tokens.is_some()).then(|| Profile::new(machine.mem.len()));
+    let mut trace = args::get().trace.as_ref().map(|path| {
+        let file = fs::File::create(path).unwrap_or_else(|e| {
+            log::error!("Failed to create the trace {path}. {e}");
+            std::process::exit(1);
+        });
+        TraceWriter::new(
+            std::io::BufWriter::new(file),
+            &machine.mem[..image_length],
+            machine.mem.len(),
+        )
+    });
+
     let timer = Instant::now();
-    let (result, total_ran, io_time) = interpreter::interpret(&mut mem);
+    let result = match (&mut trace, &mut profile) {
+        (Some(trace), profile) => {
+            interpreter::interpret_traced(&mut machine, trace, profile.as_mut())
+        }
+        (None, Some(profile)) => interpreter::interpret_profiled(&mut machine, profile),
+        (None, None) => interpreter::interpret_fast(&mut machine),
+    };
     let elapsed = timer.elapsed();
+    if let Some(trace) = trace
+        && let Err(e) = trace.finish()
+    {
+        log::error!("Failed to write the trace. {e}");
+    }
+    let (total_ran, io_time) = (machine.instructions_executed, machine.io_time);
 
     // Stats
     let compute_time = elapsed - io_time;
     println_silenceable!("\n{}", "-".repeat(80));
+    let failed = result.is_err();
     if let Err(e) = result {
         asm_runtime_error(e, &tokens)
     }
@@ -64,10 +339,34 @@ fn main() {
         "Instructions executed: {}",
         utils::with_thousands(total_ran.to_string())
     );
+    if let Some(code) = machine.exit_code {
+        println_silenceable!("Exit code: {code}");
+    }
     println_silenceable!(
         "Instructions per second: {}",
         utils::with_thousands(
             ((total_ran as f64 / compute_time.as_secs_f64()) as u128).to_string()
         )
     );
+
+    if let (Some(profile), Some(tokens)) = (profile, tokens) {
+        if args::get().profile {
+            println!("\n{}", profile.report(&tokens, PROFILE_ROWS));
+        }
+        if let Some(path) = &args::get().profile_folded {
+            profiler::to_file(Path::new(path), &profile.folded_stacks(&tokens))
+                .unwrap_or_else(|e| exit_with_file_error(e));
+        }
+        if let Some(path) = &args::get().coverage {
+            coverage::to_file(Path::new(path), &profile, &tokens)
+                .unwrap_or_else(|e| exit_with_file_error(e));
+        }
+    }
+    // A runaway or crashing program fails, like a failed assembly
+    if failed {
+        std::process::exit(1);
+    }
+    if let Some(code) = machine.exit_code {
+        std::process::exit(devices::exit_status(code));
+    }
 }
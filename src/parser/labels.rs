@@ -1,18 +1,21 @@
 use crate::asm_details;
+use crate::asm_err;
 use crate::asm_error;
-use crate::asm_error_no_terminate;
-use crate::asm_hint;
 use crate::asm_warn;
-use crate::terminate;
+use crate::assembler::AssemblerConfig;
+use crate::feedback::{AssembleError, Diagnostics};
 use crate::tokens;
 use crate::tokens::*;
 
-use colored::Colorize;
 use std::collections::HashMap;
 
+/// Every element is a scope, every scope has a hashmap with the labels defined in that
+/// scope, mapping their name to their address and info
+pub type ScopedLabelTable = Vec<HashMap<String, (usize, Info)>>;
+
 /// Labels may be defined inside of instructions using the following syntax:
 /// a -= (label -> 0). This routine converts these definitions into single tokens
-pub fn grab_braced_label_definitions(tokens: Vec<Token>) -> Vec<Token> {
+pub fn grab_braced_label_definitions(tokens: Vec<Token>) -> Result<Vec<Token>, AssembleError> {
     let mut updated_tokens: Vec<Token> = Vec::with_capacity(tokens.len());
     let mut i = 0;
 
@@ -51,31 +54,39 @@ pub fn grab_braced_label_definitions(tokens: Vec<Token>) -> Vec<Token> {
         i += 1;
     }
 
-    updated_tokens
+    Ok(updated_tokens)
 }
 
 /// Find every label definition, and store the address that it should point to
 /// Returns a vector with a hashmap for each scope, containing key value pairs of the name
 /// of the label and its value and info
-pub fn assign_addresses_to_labels(tokens: &[Token]) -> Vec<HashMap<String, (usize, Info)>> {
+pub fn assign_addresses_to_labels(
+    tokens: &[Token],
+    config: &AssemblerConfig,
+    diagnostics: &mut Diagnostics,
+) -> ScopedLabelTable {
     fn new_label(
         current_scope: &mut HashMap<String, (usize, Info)>,
         name: &String,
         address: usize,
         info: &Info,
+        config: &AssemblerConfig,
+        diagnostics: &mut Diagnostics,
     ) {
         if let Some(x) = current_scope.get(name) {
             asm_warn!(
+                diagnostics,
+                config,
                 info,
                 "The label called '{name}' has already been defined in this scope"
             );
-            asm_details!(&x.1, "Here");
+            asm_details!(diagnostics, &x.1, "Here");
         }
 
         current_scope.insert(name.clone(), (address, info.clone()));
     }
     // Every element is a scope, every scope has a hashmap with labels defined in that scope
-    let mut scopes: Vec<HashMap<String, (usize, Info)>> = vec![HashMap::new()];
+    let mut scopes: ScopedLabelTable = vec![HashMap::new()];
     let mut address: usize = 0;
     // Stack maintaining the indices for the scopes. Top is the current one. They
     // index the scope vec
@@ -97,7 +108,14 @@ pub fn assign_addresses_to_labels(tokens: &[Token]) -> Vec<HashMap<String, (usiz
             TokenVariant::BracedLabelDefinition { name, .. } => {
                 let current_scope =
                     &mut scopes[current_scope_indexes[current_scope_indexes.len() - 1]];
-                new_label(current_scope, name, address, &token.info);
+                new_label(
+                    current_scope,
+                    name,
+                    address,
+                    &token.info,
+                    config,
+                    diagnostics,
+                );
             }
 
             TokenVariant::LabelDefinition { name, offset } => {
@@ -109,6 +127,8 @@ pub fn assign_addresses_to_labels(tokens: &[Token]) -> Vec<HashMap<String, (usiz
                     name,
                     address + (*offset) as usize,
                     &token.info,
+                    config,
+                    diagnostics,
                 );
             }
 
@@ -121,35 +141,39 @@ pub fn assign_addresses_to_labels(tokens: &[Token]) -> Vec<HashMap<String, (usiz
 }
 
 /// All labels get resolved, i.e. converted into the address they label.
-/// This routine also resolves relatives.
+/// This routine also resolves relatives. Undefined labels are reported and resolve
+/// to 0, so all of them are reported in a single run
 pub fn resolve_labels_and_relatives(
     tokens: &mut [Token],
     // Every HashMap in this array is a scope. The hashmap contains the labels defined in that scope
     scoped_label_table: &[HashMap<String, (usize, Info)>],
+    diagnostics: &mut Diagnostics,
 ) {
     /// Search the scopes from the deepest one for a definition of the requested label.
     fn find_label(
         name: &String,
         scoped_label_table: &[HashMap<String, (usize, Info)>],
         current_scope_indexes: &[usize],
-        info: &Info,
-    ) -> (usize, Info) {
+        token: &Token,
+        diagnostics: &mut Diagnostics,
+    ) -> usize {
         for scope in current_scope_indexes.iter().rev() {
-            if let Some(x) = scoped_label_table[*scope].get(name) {
-                return x.clone();
+            if let Some((address, _)) = scoped_label_table[*scope].get(name) {
+                return *address;
             }
         }
-        asm_error_no_terminate!(info, "No definition for the label '{name}' found");
+        let mut error = asm_err!(&token.info, "No definition for the label '{name}' found")
+            .trace(&token.origin_info);
         if name == "_ASM" {
-            asm_hint!(
-                "For some features, like dereferencing with the * operator, the assembler requires an _ASM label"
-            );
-            asm_hint!("Add '_ASM -> 0', '#ASM' or '#sublib' anywhere in your code");
+            error = error
+                .hint("For some features, like dereferencing with the * operator, the assembler requires an _ASM label")
+                .hint("Add '_ASM -> 0', '#ASM' or '#sublib' anywhere in your code");
         }
         if name == ".main" {
-            asm_hint!("Sublib assumes a .main label exists as the program's entry")
+            error = error.hint("Sublib assumes a .main label exists as the program's entry");
         }
-        terminate!();
+        diagnostics.push(error);
+        0
     }
 
     let mut address: usize = 0;
@@ -168,26 +192,25 @@ pub fn resolve_labels_and_relatives(
                 current_scope_indexes.pop();
             }
             TokenVariant::Label { name } => {
-                let (val, _) = find_label(
+                let val = find_label(
                     name,
                     scoped_label_table,
                     &current_scope_indexes,
-                    &token.info,
+                    token,
+                    diagnostics,
                 );
                 token.variant = TokenVariant::DecLiteral { value: val as i32 };
             }
             TokenVariant::BracedLabelDefinition { name, data } => {
                 let value = match data {
                     IntOrString::Int(val) => *val,
-                    IntOrString::Str(..) => {
-                        let (val, _) = find_label(
-                            name,
-                            scoped_label_table,
-                            &current_scope_indexes,
-                            &token.info,
-                        );
-                        val as i32
-                    }
+                    IntOrString::Str(..) => find_label(
+                        name,
+                        scoped_label_table,
+                        &current_scope_indexes,
+                        token,
+                        diagnostics,
+                    ) as i32,
                 };
 
                 token.variant = TokenVariant::DecLiteral { value };
@@ -380,7 +403,7 @@ mod tests {
                 },
             ),
         ]);
-        let output = grab_braced_label_definitions(input);
+        let output = grab_braced_label_definitions(input).unwrap();
         assert_eq!(output, expected);
     }
 }
@@ -1,19 +1,24 @@
 //! Converts all literals to signed integers
-use crate::asm_error;
-use crate::{terminate, tokens::*};
+use crate::feedback::{AssembleError, Diagnostics};
+use crate::{asm_err, error, tokens::*};
 
 use unescape::unescape;
 
 /// Convert character and hex literals into dec literals inplace and checks if
-/// scopes are matched
-pub fn char_and_hex_to_dec_and_check_scopes(tokens: &mut [Token]) {
+/// scopes are matched. Invalid hex literals are reported and become 0
+pub fn char_and_hex_to_dec_and_check_scopes(
+    tokens: &mut [Token],
+    diagnostics: &mut Diagnostics,
+) -> Result<(), AssembleError> {
     let mut scope_tracker: i32 = 0;
 
     for token in tokens.iter_mut() {
         match &token.variant {
             TokenVariant::HexLiteral { value } => {
-                let val = i32::from_str_radix(value, 16)
-                    .unwrap_or_else(|_| asm_error!(&token.info, "Invalid hex literal"));
+                let val = i32::from_str_radix(value, 16).unwrap_or_else(|_| {
+                    diagnostics.push(asm_err!(&token.info, "Invalid hex literal"));
+                    0
+                });
 
                 token.variant = TokenVariant::DecLiteral { value: val };
             }
@@ -31,22 +36,24 @@ pub fn char_and_hex_to_dec_and_check_scopes(tokens: &mut [Token]) {
     }
 
     if scope_tracker > 0 {
-        log::error!("Unmatched scope");
-        terminate!();
+        error!("Unmatched scope");
     } else if scope_tracker < 0 {
-        log::error!("Unmatched unscope");
-        terminate!();
+        error!("Unmatched unscope");
     }
+    Ok(())
 }
 
-/// Turn string tokens into dec tokens. Adds a null terminator.
-pub fn convert_strings(tokens: Vec<Token>) -> Vec<Token> {
+/// Turn string tokens into dec tokens. Adds a null terminator. Strings with an invalid
+/// escape sequence are reported and only get the null terminator
+pub fn convert_strings(tokens: Vec<Token>, diagnostics: &mut Diagnostics) -> Vec<Token> {
     let mut new_tokens: Vec<Token> = Vec::with_capacity(tokens.len());
     for token in tokens {
         match &token.variant {
             TokenVariant::StrLiteral { value } => {
-                let string = unescape(value)
-                    .unwrap_or_else(|| asm_error!(&token.info, "Invalid escape sequence"));
+                let string = unescape(value).unwrap_or_else(|| {
+                    diagnostics.push(asm_err!(&token.info, "Invalid escape sequence"));
+                    String::new()
+                });
 
                 for c in string.chars() {
                     new_tokens.push(Token::with_info(
@@ -72,7 +79,6 @@ mod tests {
     use super::*;
 
     #[test]
-    #[should_panic(expected = "Invalid hex literal")]
     fn garbage_hex() {
         let mut input: Vec<Token> = tokens_from_token_variant_vec(vec![(
             0,
@@ -80,7 +86,11 @@ mod tests {
                 value: "GARBAGE".to_string(),
             },
         )]);
-        char_and_hex_to_dec_and_check_scopes(&mut input);
+        let mut diagnostics = Diagnostics::default();
+        char_and_hex_to_dec_and_check_scopes(&mut input, &mut diagnostics).unwrap();
+        assert_eq!(diagnostics.error_count(), 1);
+        assert_eq!(diagnostics.list[0].message, "Invalid hex literal");
+        assert_eq!(input[0].variant, TokenVariant::DecLiteral { value: 0 });
     }
 
     #[test]
@@ -116,12 +126,13 @@ mod tests {
             (0, TokenVariant::DecLiteral { value: 291 }),
             (0, TokenVariant::DecLiteral { value: -170 }),
         ]);
-        char_and_hex_to_dec_and_check_scopes(&mut input);
+        let mut diagnostics = Diagnostics::default();
+        char_and_hex_to_dec_and_check_scopes(&mut input, &mut diagnostics).unwrap();
         assert_eq!(input, expected);
+        assert!(diagnostics.list.is_empty());
     }
 
     #[test]
-    #[should_panic(expected = "Invalid escape sequence")]
     fn test_garbage_escape_sequence() {
         let input: Vec<Token> = tokens_from_token_variant_vec(vec![(
             0,
@@ -129,7 +140,11 @@ mod tests {
                 value: "Hello, \\q World".to_string(),
             },
         )]);
-        let _ = convert_strings(input);
+        let mut diagnostics = Diagnostics::default();
+        let output = convert_strings(input, &mut diagnostics);
+        assert_eq!(diagnostics.error_count(), 1);
+        assert_eq!(diagnostics.list[0].message, "Invalid escape sequence");
+        assert_eq!(output.len(), 1);
     }
 
     #[test]
@@ -159,7 +174,7 @@ mod tests {
             (0, TokenVariant::DecLiteral { value: 0 }),
         ]);
 
-        let output = convert_strings(input);
+        let output = convert_strings(input, &mut Diagnostics::default());
         assert_eq!(output, expected);
     }
 }
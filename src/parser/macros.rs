@@ -1,13 +1,13 @@
 //! Reads, typechecks and expands macros
-use crate::args;
 use crate::asm_details;
+use crate::asm_err;
 use crate::asm_error;
-use crate::asm_error_no_terminate;
 use crate::asm_hint;
 use crate::asm_info;
 use crate::asm_warn;
+use crate::assembler::AssemblerConfig;
+use crate::feedback::{AssembleError, Diagnostics};
 use crate::symbols;
-use crate::terminate;
 use crate::tokens::*;
 use crate::utils::IterVec;
 
@@ -37,7 +37,11 @@ impl fmt::Debug for Macro {
 
 /// Grab all macro definitions, returns the tokens without macro definitions and a
 /// map with macros
-pub fn read_macros(tokens: &[Token]) -> (Vec<Token>, HashMap<String, Macro>) {
+pub fn read_macros(
+    tokens: &[Token],
+    config: &AssemblerConfig,
+    diagnostics: &mut Diagnostics,
+) -> Result<(Vec<Token>, HashMap<String, Macro>), AssembleError> {
     let mut new_tokens: Vec<Token> = Vec::with_capacity(tokens.len());
     let mut macros: HashMap<String, Macro> = HashMap::new();
 
@@ -71,22 +75,26 @@ pub fn read_macros(tokens: &[Token]) -> (Vec<Token>, HashMap<String, Macro>) {
                     internal_scope_tracker = 0;
                     if global_scope_tracker != 0 {
                         asm_warn!(
+                            diagnostics,
+                            config,
                             &token.info,
                             "Macros defined inside of a scope will still be accessible globally"
                         );
                     }
                     if let Some(x) = macros.get(&cur_macro.as_mut().unwrap().name) {
                         asm_warn!(
+                            diagnostics,
+                            config,
                             &token.info,
                             "A macro with this name has already been defined {}",
                             x.name
                         );
-                        asm_details!(&x.info, "Here");
+                        asm_details!(diagnostics, &x.info, "Here");
                     }
                     mode = Mode::Parameters;
                 }
                 TokenVariant::MacroBodyStart | TokenVariant::MacroBodyEnd => {
-                    asm_error!(&token.info, "Unexpected token");
+                    return Err(asm_err!(&token.info, "Unexpected token").trace(&token.origin_info));
                 }
                 TokenVariant::Scope => {
                     global_scope_tracker += 1;
@@ -111,10 +119,11 @@ pub fn read_macros(tokens: &[Token]) -> (Vec<Token>, HashMap<String, Macro>) {
                         .push((name.clone(), token.info.clone()));
                     if !name.ends_with('?') {
                         asm_info!(
+                            diagnostics,
                             &token.info,
                             "Notate macro parameters with a trailing question mark ",
                         );
-                        asm_hint!("'{name}' -> '{name}?'");
+                        asm_hint!(diagnostics, "'{name}' -> '{name}?'");
                     }
                 }
                 TokenVariant::MacroBodyStart => {
@@ -144,14 +153,16 @@ pub fn read_macros(tokens: &[Token]) -> (Vec<Token>, HashMap<String, Macro>) {
                     if internal_scope_tracker > 0 {
                         continue;
                     }
-                    if let TokenVariant::Label { name } = &tokens[i - 1].variant {
-                        if !name.ends_with('?') {
-                            asm_warn!(
-                                &token.info,
-                                "Label definitions in non-scoped macros are very dangerous, though it is acceptable if the label being defined is a macro parameter",
-                            );
-                            asm_hint!("Use '{{' and '}}' instead of '[' and ']'");
-                        }
+                    if let TokenVariant::Label { name } = &tokens[i - 1].variant
+                        && !name.ends_with('?')
+                    {
+                        asm_warn!(
+                            diagnostics,
+                            config,
+                            &token.info,
+                            "Label definitions in non-scoped macros are very dangerous, though it is acceptable if the label being defined is a macro parameter",
+                        );
+                        asm_hint!(diagnostics, "Use '{{' and '}}' instead of '[' and ']'");
                     }
                 }
                 // Special case for labels defined in macros, because of macro
@@ -193,15 +204,15 @@ pub fn read_macros(tokens: &[Token]) -> (Vec<Token>, HashMap<String, Macro>) {
                     let mac = cur_macro.as_mut().unwrap();
 
                     // For macros not bound by scope we remove the newlines around [ and ]
-                    if !mac.body.is_empty() {
-                        if let TokenVariant::Linebreak = mac.body[0].variant {
-                            mac.body.remove(0);
-                        }
+                    if !mac.body.is_empty()
+                        && let TokenVariant::Linebreak = mac.body[0].variant
+                    {
+                        mac.body.remove(0);
                     }
-                    if !mac.body.is_empty() {
-                        if let TokenVariant::Linebreak = mac.body[mac.body.len() - 1].variant {
-                            mac.body.remove(mac.body.len() - 1);
-                        }
+                    if !mac.body.is_empty()
+                        && let TokenVariant::Linebreak = mac.body[mac.body.len() - 1].variant
+                    {
+                        mac.body.remove(mac.body.len() - 1);
                     }
 
                     macros.insert(mac.name.clone(), cur_macro.unwrap());
@@ -230,7 +241,7 @@ pub fn read_macros(tokens: &[Token]) -> (Vec<Token>, HashMap<String, Macro>) {
             },
         }
     }
-    (new_tokens, macros)
+    Ok((new_tokens, macros))
 }
 
 fn generate_macro_body(
@@ -238,7 +249,9 @@ fn generate_macro_body(
     macros: &HashMap<String, Macro>,
     param_to_arg_map: &HashMap<String, TokenOrTokenVec>,
     context: Vec<Info>,
-) -> Vec<Token> {
+    config: &AssemblerConfig,
+    diagnostics: &mut Diagnostics,
+) -> Result<Vec<Token>, AssembleError> {
     let mut body: Vec<Token> = Vec::new();
 
     for base_body_token in &current_macro.body {
@@ -292,7 +305,7 @@ fn generate_macro_body(
         }
     }
 
-    insert_macros(body, macros, context)
+    insert_macros(body, macros, context, config, diagnostics)
 }
 
 #[derive(Debug)]
@@ -301,15 +314,25 @@ enum TokenOrTokenVec {
     TokVec(Vec<Token>),
 }
 
-fn macro_argument_type_check(argument_info: &Info, token: &Token, argument_name: &str) {
-    fn wrong_type(tok: &Token, arg_info: &Info, expected: &str) {
-        asm_error_no_terminate!(&tok.info, "Expected a '{}' as argument ", expected);
-        asm_hint!("See the documentation for information on the typing system");
-        asm_details!(arg_info, "Macro definition");
-        terminate!();
+/// Reports arguments with the wrong type. The macro is still expanded, so that every
+/// wrongly typed argument is reported
+fn macro_argument_type_check(
+    argument_info: &Info,
+    token: &Token,
+    argument_name: &str,
+    config: &AssemblerConfig,
+    diagnostics: &mut Diagnostics,
+) {
+    fn wrong_type(tok: &Token, arg_info: &Info, expected: &str, diagnostics: &mut Diagnostics) {
+        diagnostics.push(
+            asm_err!(&tok.info, "Expected a '{}' as argument ", expected)
+                .hint("See the documentation for information on the typing system")
+                .details(arg_info, "Macro definition")
+                .trace(&tok.origin_info),
+        );
     }
 
-    if args::exist() && args::get().disable_type_checking {
+    if !config.type_checking {
         return;
     }
     let lower = argument_name.to_ascii_lowercase();
@@ -317,19 +340,19 @@ fn macro_argument_type_check(argument_info: &Info, token: &Token, argument_name:
         match &lower[..2] {
             symbols::SCOPE_TYPE_PREFIX => {
                 if !matches!(token.variant, TokenVariant::Scope) {
-                    wrong_type(token, argument_info, "scope");
+                    return wrong_type(token, argument_info, "scope", diagnostics);
                 }
                 return;
             }
             symbols::BRACED_TYPE_PREFIX => {
                 if !matches!(token.variant, TokenVariant::BraceOpen) {
-                    wrong_type(token, argument_info, "braced");
+                    return wrong_type(token, argument_info, "braced", diagnostics);
                 }
                 return;
             }
             symbols::MACRO_TYPE_PREFIX => {
                 if !matches!(token.variant, TokenVariant::BraceOpen) {
-                    wrong_type(token, argument_info, "macro call");
+                    return wrong_type(token, argument_info, "macro call", diagnostics);
                 }
                 return;
             }
@@ -338,7 +361,7 @@ fn macro_argument_type_check(argument_info: &Info, token: &Token, argument_name:
                     token.variant,
                     TokenVariant::DecLiteral { .. } | TokenVariant::StrLiteral { .. }
                 ) {
-                    wrong_type(token, argument_info, "literal");
+                    return wrong_type(token, argument_info, "literal", diagnostics);
                 }
                 return;
             }
@@ -352,7 +375,7 @@ fn macro_argument_type_check(argument_info: &Info, token: &Token, argument_name:
         token.variant,
         TokenVariant::Label { .. } | TokenVariant::MacroCall { .. } | TokenVariant::Relative { .. }
     ) {
-        wrong_type(token, argument_info, "label");
+        wrong_type(token, argument_info, "label", diagnostics);
     }
 }
 
@@ -361,7 +384,9 @@ pub fn insert_macros(
     tokens: Vec<Token>,
     macros: &HashMap<String, Macro>,
     context: Vec<Info>,
-) -> Vec<Token> {
+    config: &AssemblerConfig,
+    diagnostics: &mut Diagnostics,
+) -> Result<Vec<Token>, AssembleError> {
     #[derive(Debug, PartialEq)]
     enum CompoundArgType {
         Braced,
@@ -388,56 +413,71 @@ pub fn insert_macros(
     while !tokens.finished() {
         let token = tokens.current();
         match &mode {
-            Mode::Normal => match &token.variant {
-                TokenVariant::MacroCall { name } => {
-                    let mac = macros.get(name);
-                    match mac {
-                        None => {
-                            asm_error_no_terminate!(
-                                &token.info,
-                                "No declaration found for the macro '{name}'"
-                            );
-                            if name.starts_with("ASM::") {
-                                asm_hint!(
-                                    "This is an assembler macro. Please include the ASM module"
-                                );
-                                asm_hint!("Add '#ASM' or '#sublib' somewhere in your code");
+            Mode::Normal => {
+                match &token.variant {
+                    TokenVariant::MacroCall { name } => {
+                        let mac = macros.get(name);
+                        match mac {
+                            None => {
+                                let mut error = asm_err!(
+                                    &token.info,
+                                    "No declaration found for the macro '{name}'"
+                                )
+                                .trace(&token.origin_info);
+                                if name.starts_with("ASM::") {
+                                    error = error
+                                    .hint("This is an assembler macro. Please include the ASM module")
+                                    .hint("Add '#ASM' or '#sublib' somewhere in your code");
+                                }
+                                return Err(error);
                             }
-                            terminate!();
-                        }
-                        Some(x) => {
-                            current_macro = Some(x);
-                            caller_info = Some(token.info.clone());
-                            mode = Mode::Args;
-                            if args::exist() {
-                                if name == "ASM::Breakpoint" && args::get().pedantic {
+                            Some(x) => {
+                                current_macro = Some(x);
+                                caller_info = Some(token.info.clone());
+                                mode = Mode::Args;
+                                if name == "ASM::Breakpoint" && config.pedantic {
                                     asm_info!(
+                                        diagnostics,
                                         &token.info,
                                         "Breakpoints are non-canonical and specific to this assembler"
                                     );
                                 }
-                                if name == "ASM::Debug" && args::get().pedantic {
+                                if name == "ASM::Debug" && config.pedantic {
                                     asm_info!(
+                                        diagnostics,
                                         &token.info,
                                         "Debug prints are non-canonical and specific to this assembler"
                                     );
                                 }
+                                if name.starts_with("ASM::Devices::") && config.pedantic {
+                                    asm_info!(
+                                        diagnostics,
+                                        &token.info,
+                                        "Devices are non-canonical and specific to this assembler"
+                                    );
+                                }
                             }
                         }
                     }
+                    _ => {
+                        new_tokens.push(token.clone());
+                    }
                 }
-                _ => {
-                    new_tokens.push(token.clone());
-                }
-            },
+            }
             Mode::Args => {
                 let current_macro_safe = current_macro.unwrap();
                 // It has read all arguments
                 if param_to_arg_map.len() >= current_macro_safe.params.len() {
                     let mut c = context.clone();
                     c.push(caller_info.unwrap());
-                    let mut body =
-                        generate_macro_body(current_macro_safe, macros, &param_to_arg_map, c);
+                    let mut body = generate_macro_body(
+                        current_macro_safe,
+                        macros,
+                        &param_to_arg_map,
+                        c,
+                        config,
+                        diagnostics,
+                    )?;
                     new_tokens.append(&mut body);
 
                     caller_info = None;
@@ -452,20 +492,24 @@ pub fn insert_macros(
                     &current_macro_safe.params[param_to_arg_map.len()];
 
                 if let TokenVariant::Linebreak = token.variant {
-                    asm_error_no_terminate!(
+                    return Err(asm_err!(
                         &caller_info.unwrap(),
                         "Expected {} args, found {}",
                         current_macro_safe.params.len(),
                         param_to_arg_map.len(),
-                    );
-                    asm_hint!("A newline may not separate macro arguments.");
-                    asm_hint!(
-                        "Scopes containing newlines are allowed. Multiple scopes as arguments must be chained with }} and {{ on the same line"
-                    );
-                    asm_details!(&token.info, "Expected the argument(s) here");
-                    terminate!();
-                }
-                macro_argument_type_check(parameter_info, token, parameter_name);
+                    )
+                    .hint("A newline may not separate macro arguments.")
+                    .hint("Scopes containing newlines are allowed. Multiple scopes as arguments must be chained with } and { on the same line")
+                    .details(&token.info, "Expected the argument(s) here")
+                    .trace(&token.origin_info));
+                }
+                macro_argument_type_check(
+                    parameter_info,
+                    token,
+                    parameter_name,
+                    config,
+                    diagnostics,
+                );
 
                 if let TokenVariant::Scope = token.variant {
                     mode = Mode::CompoundArg(CompoundArgType::Scoped);
@@ -475,11 +519,11 @@ pub fn insert_macros(
                     continue;
                 }
                 if TokenVariant::Unscope == token.variant {
-                    asm_error_no_terminate!(&token.info, "Unexpected token",);
-                    asm_hint!(
-                        "If you want to pass a macro as an argument, you must surround it with '(' and ')' instead of '{{' and '}}'"
-                    );
-                    terminate!();
+                    return Err(asm_err!(&token.info, "Unexpected token",)
+                        .hint(
+                            "If you want to pass a macro as an argument, you must surround it with '(' and ')' instead of '{' and '}'",
+                        )
+                        .trace(&token.origin_info));
                 }
 
                 if let TokenVariant::BraceOpen = token.variant {
@@ -568,9 +612,16 @@ pub fn insert_macros(
         // It has read all arguments
         let mut c = context.clone();
         c.push(caller_info.unwrap());
-        let mut body = generate_macro_body(current_macro_safe, macros, &param_to_arg_map, c);
+        let mut body = generate_macro_body(
+            current_macro_safe,
+            macros,
+            &param_to_arg_map,
+            c,
+            config,
+            diagnostics,
+        )?;
         new_tokens.append(&mut body);
     }
 
-    new_tokens
+    Ok(new_tokens)
 }
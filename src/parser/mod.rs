@@ -6,16 +6,19 @@ mod other;
 
 use log::LevelFilter;
 
+use crate::assembler::AssemblerConfig;
+use crate::feedback::{AssembleError, Diagnostics};
 use crate::parser::labels::*;
 use crate::parser::literals::*;
 use crate::parser::macros::*;
 use crate::parser::other::*;
+use crate::symbol_table::{self, Symbol};
 use crate::tokens::dump_tokens;
 use crate::tokens::{Token, TokenVariant};
 
 #[cfg(not(tarpaulin_include))]
-fn debug_print_tokens(tokens: &[Token], message: &str) {
-    if log::max_level() >= LevelFilter::Debug {
+fn debug_print_tokens(tokens: &[Token], message: &str, config: &AssemblerConfig) {
+    if config.feedback_level.to_log_level() >= LevelFilter::Debug {
         log::debug!("{message}");
         for token in tokens {
             if let TokenVariant::Linebreak = token.variant {
@@ -28,15 +31,21 @@ fn debug_print_tokens(tokens: &[Token], message: &str) {
     }
 }
 
-pub fn parse(mut tokens: Vec<Token>) -> Vec<Token> {
-    char_and_hex_to_dec_and_check_scopes(&mut tokens);
+/// Expands macros, assignments, strings, multiplications and dereferences. What is left
+/// are plain instructions, literals and labels, still as the lines they were written on
+pub fn expand(
+    mut tokens: Vec<Token>,
+    config: &AssemblerConfig,
+    diagnostics: &mut Diagnostics,
+) -> Result<Vec<Token>, AssembleError> {
+    char_and_hex_to_dec_and_check_scopes(&mut tokens, diagnostics)?;
 
-    let tokens = grab_braced_label_definitions(tokens);
-    let tokens = handle_assignments(&tokens);
+    let tokens = grab_braced_label_definitions(tokens)?;
+    let tokens = handle_assignments(&tokens)?;
 
-    let (mut tokens, macros) = read_macros(&tokens);
+    let (mut tokens, macros) = read_macros(&tokens, config, diagnostics)?;
 
-    if log::max_level() >= LevelFilter::Debug {
+    if config.feedback_level.to_log_level() >= LevelFilter::Debug {
         log::debug!("Found macros:");
         for i in &macros {
             println!("{:?}", i.1);
@@ -44,23 +53,33 @@ pub fn parse(mut tokens: Vec<Token>) -> Vec<Token> {
         println!();
     }
 
-    tokens = insert_macros(tokens, &macros, vec![]);
+    tokens = insert_macros(tokens, &macros, vec![], config, diagnostics)?;
 
-    debug_print_tokens(&tokens, "Inserted macros:");
+    debug_print_tokens(&tokens, "Inserted macros:", config);
 
-    let tokens = convert_strings(tokens);
+    let tokens = convert_strings(tokens, diagnostics);
     let tokens = expand_mults(&tokens);
     let tokens = expand_derefs(&tokens);
 
-    debug_print_tokens(&tokens, "Derefs and Literals:");
+    debug_print_tokens(&tokens, "Derefs and Literals:", config);
 
-    if log::max_level() >= LevelFilter::Debug {
+    if config.feedback_level.to_log_level() >= LevelFilter::Debug {
         dump_tokens(&tokens).unwrap_or_else(|e| log::warn!("Failed to dump tokens. {e}"));
     }
+    Ok(tokens)
+}
+
+/// Returns the tokens with their labels resolved, and the symbols of the labels
+pub fn parse(
+    tokens: Vec<Token>,
+    config: &AssemblerConfig,
+    diagnostics: &mut Diagnostics,
+) -> Result<(Vec<Token>, Vec<Symbol>), AssembleError> {
+    let tokens = expand(tokens, config, diagnostics)?;
 
-    let mut tokens = fix_instructions_and_collapse_label_definitions(&tokens);
+    let mut tokens = fix_instructions_and_collapse_label_definitions(&tokens, diagnostics)?;
     // From this point forwards, memory addresses are fixed.
-    if log::max_level() >= LevelFilter::Debug {
+    if config.feedback_level.to_log_level() >= LevelFilter::Debug {
         log::debug!("Fixed");
         for statement in &tokens {
             println!("{statement:?}");
@@ -68,17 +87,18 @@ pub fn parse(mut tokens: Vec<Token>) -> Vec<Token> {
         println!();
     }
 
-    let scoped_label_table = assign_addresses_to_labels(&tokens);
+    let scoped_label_table = assign_addresses_to_labels(&tokens, config, diagnostics);
+    let symbols = symbol_table::collect(&tokens, &scoped_label_table);
 
-    if log::max_level() >= LevelFilter::Debug {
+    if config.feedback_level.to_log_level() >= LevelFilter::Debug {
         log::debug!("Label Table");
 
         println!("{scoped_label_table:?}");
         println!();
     }
-    resolve_labels_and_relatives(&mut tokens, &scoped_label_table);
+    resolve_labels_and_relatives(&mut tokens, &scoped_label_table, diagnostics);
 
-    if log::max_level() >= LevelFilter::Debug {
+    if config.feedback_level.to_log_level() >= LevelFilter::Debug {
         log::debug!("Resolved Labels");
 
         for statement in &tokens {
@@ -86,5 +106,5 @@ pub fn parse(mut tokens: Vec<Token>) -> Vec<Token> {
         }
     }
 
-    tokens
+    Ok((tokens, symbols))
 }
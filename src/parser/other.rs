@@ -1,6 +1,7 @@
 //! Miscellaneous parsing, like for syntax sugar
 use crate::{
     asm_error, asm_info,
+    feedback::{AssembleError, Diagnostics},
     symbols::LITERAL_TYPE_PREFIX,
     tokens::{LabelOffset, Token, TokenVariant},
     utils::IterVec,
@@ -11,17 +12,16 @@ pub fn expand_mults(tokens: &Vec<Token>) -> Vec<Token> {
     let mut tokens = IterVec::new(tokens);
     let mut new_tokens: Vec<Token> = Vec::with_capacity(tokens.len());
     while !tokens.finished() {
+        // If it isn't followed by a literal, it's the deref operator
         if tokens.contains_n_more(1)
             && let TokenVariant::Asterisk = tokens.get_relative(1).variant
+            && let TokenVariant::DecLiteral { value: count } = &tokens.get_relative(2).variant
         {
-            if let TokenVariant::DecLiteral { value: count } = &tokens.get_relative(2).variant {
-                for _ in 0..*count {
-                    new_tokens.push(tokens.current().clone());
-                }
-                tokens.consume_n(3); // Remember that this is the index of the ORIGINAL token, we consume three
-                continue;
+            for _ in 0..*count {
+                new_tokens.push(tokens.current().clone());
             }
-            // its the deref operator
+            tokens.consume_n(3); // Remember that this is the index of the ORIGINAL token, we consume three
+            continue;
         }
         new_tokens.push(tokens.current().clone());
         tokens.consume();
@@ -34,7 +34,10 @@ pub fn expand_mults(tokens: &Vec<Token>) -> Vec<Token> {
 /// and it converts label definitions into a single token instead of the two
 /// *label* and ->. At this point linebreaks are also removed, since they don't carry
 /// any meaning anymore.
-pub fn fix_instructions_and_collapse_label_definitions(tokens: &[Token]) -> Vec<Token> {
+pub fn fix_instructions_and_collapse_label_definitions(
+    tokens: &[Token],
+    diagnostics: &mut Diagnostics,
+) -> Result<Vec<Token>, AssembleError> {
     let mut new_tokens: Vec<Token> = Vec::with_capacity(tokens.len());
 
     let mut i = 0;
@@ -111,6 +114,7 @@ pub fn fix_instructions_and_collapse_label_definitions(tokens: &[Token]) -> Vec<
                     let mut split_name = name.split('?');
                     if !split_name.next_back().unwrap().starts_with('.') {
                         asm_info!(
+                            diagnostics,
                             &tokens[i + 3].info,
                             "Labels which are jump targets should be prefixed with a '.'"
                         );
@@ -125,7 +129,7 @@ pub fn fix_instructions_and_collapse_label_definitions(tokens: &[Token]) -> Vec<
 
         i += 1;
     }
-    new_tokens
+    Ok(new_tokens)
 }
 
 /// Routine used for syntax sugar. Gives a macro call with it's arguments
@@ -145,7 +149,7 @@ pub fn insert_asm_macro(macro_name: String, origin_tok: &Token, args: Vec<&Token
 }
 
 /// Handle the assignment syntax sugar like, x = 0, y = x, z = 10.
-pub fn handle_assignments(tokens: &[Token]) -> Vec<Token> {
+pub fn handle_assignments(tokens: &[Token]) -> Result<Vec<Token>, AssembleError> {
     let mut new_tokens: Vec<Token> = Vec::with_capacity(tokens.len());
 
     let mut i = 0;
@@ -220,7 +224,7 @@ pub fn handle_assignments(tokens: &[Token]) -> Vec<Token> {
         i += 1;
     }
 
-    new_tokens
+    Ok(new_tokens)
 }
 
 #[cfg(test)]
@@ -349,14 +353,14 @@ mod tests {
             ),
         ]);
 
-        let output = handle_assignments(&input);
+        let output = handle_assignments(&input).unwrap();
 
         assert_eq!(output, expected);
     }
 
     #[test]
     fn test_mult() {
-        let mut input: Vec<Token> = tokens_from_token_variant_vec(vec![
+        let input: Vec<Token> = tokens_from_token_variant_vec(vec![
             (0, TokenVariant::Asterisk),
             (
                 1,
@@ -382,13 +386,13 @@ mod tests {
             (2, TokenVariant::DecLiteral { value: 10 }),
             (2, TokenVariant::DecLiteral { value: 10 }),
         ]);
-        let output = expand_mults(&mut input);
+        let output = expand_mults(&input);
         assert_eq!(output, expected);
     }
 
     #[test]
     fn test_fix_instructions_and_collapse_label_definitions() {
-        let mut input: Vec<Token> = tokens_from_token_variant_vec(vec![
+        let input: Vec<Token> = tokens_from_token_variant_vec(vec![
             (
                 0,
                 TokenVariant::Label {
@@ -475,7 +479,9 @@ mod tests {
                 },
             ),
         ]);
-        let output = fix_instructions_and_collapse_label_definitions(&mut input);
+        let output =
+            fix_instructions_and_collapse_label_definitions(&input, &mut Diagnostics::default())
+                .unwrap();
         assert_eq!(output, expected);
     }
 }
@@ -0,0 +1,203 @@
+//! Counts how often every address is executed and attributes the counts to the source lines
+//! and macros the words came from
+use std::collections::HashMap;
+use std::{fs, path::Path};
+
+use crate::{
+    error,
+    feedback::AssembleError,
+    lexer,
+    listing::{source_line, statement_of},
+    tokens::{Info, Token},
+    utils,
+};
+
+/// Executions per address, filled by interpreter::interpret_profiled()
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Profile {
+    pub counts: Vec<u64>,
+}
+
+impl Profile {
+    pub fn new(memory_size: usize) -> Self {
+        Profile {
+            counts: vec![0; memory_size],
+        }
+    }
+
+    #[inline(always)]
+    pub fn count(&mut self, pc: usize) {
+        if let Some(count) = self.counts.get_mut(pc) {
+            *count += 1;
+        }
+    }
+
+    fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// The executed instructions with the token they start at
+    fn executed<'a>(&'a self, tokens: &'a [Token]) -> impl Iterator<Item = (&'a Token, u64)> {
+        self.counts
+            .iter()
+            .zip(tokens)
+            .filter(|(count, _)| **count > 0)
+            .map(|(count, token)| (token, *count))
+    }
+
+    /// Instructions executed per source line, only counting the line the instruction was
+    /// written on and not the macro calls it was expanded from
+    pub fn by_line(&self, tokens: &[Token]) -> Vec<(Info, u64)> {
+        let mut lines: HashMap<(usize, i32), (Info, u64)> = HashMap::new();
+        for (token, count) in self.executed(tokens) {
+            let (_, statement) = statement_of(token);
+            lines
+                .entry((statement.file, statement.line_number))
+                .or_insert_with(|| (statement.clone(), 0))
+                .1 += count;
+        }
+        let mut lines: Vec<(Info, u64)> = lines.into_values().collect();
+        lines.sort_by(|(a, a_count), (b, b_count)| {
+            b_count
+                .cmp(a_count)
+                .then_with(|| (a.file, a.line_number).cmp(&(b.file, b.line_number)))
+        });
+        lines
+    }
+
+    /// Instructions executed per macro, including the macros it calls
+    pub fn by_macro(&self, tokens: &[Token]) -> Vec<(String, u64)> {
+        let mut macros: HashMap<String, u64> = HashMap::new();
+        for (token, count) in self.executed(tokens) {
+            let (calls, _) = statement_of(token);
+            let mut names: Vec<String> = calls.iter().map(macro_name).collect();
+            names.sort();
+            names.dedup();
+            for name in names {
+                *macros.entry(name).or_default() += count;
+            }
+        }
+        let mut macros: Vec<(String, u64)> = macros.into_iter().collect();
+        macros.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        macros
+    }
+
+    /// The hottest lines and macros, at most top of each
+    pub fn report(&self, tokens: &[Token], top: usize) -> String {
+        let total = self.total().max(1);
+        let row = |count: u64| {
+            format!(
+                "{:>7.2}% {:>15}",
+                count as f64 / total as f64 * 100.0,
+                utils::with_thousands(count.to_string())
+            )
+        };
+
+        let mut text = String::from("Hottest lines:\n");
+        for (info, count) in self.by_line(tokens).iter().take(top) {
+            text.push_str(&format!("{}  {}\n", row(*count), source_line(info)));
+        }
+        text.push_str("\nHottest macros, including the macros they call:\n");
+        for (name, count) in self.by_macro(tokens).iter().take(top) {
+            text.push_str(&format!("{}  {name}\n", row(*count)));
+        }
+        text
+    }
+
+    /// One line per call stack with the instructions executed in it, the format flamegraph
+    /// tools take. Frames are macro calls with the line they are called on, outermost first,
+    /// ending with the line the instruction was written on.
+    ///
+    /// Example:
+    /// Main.sbl:5 !Clear;Lib.sbl:3 1200
+    pub fn folded_stacks(&self, tokens: &[Token]) -> String {
+        let mut stacks: HashMap<String, u64> = HashMap::new();
+        for (token, count) in self.executed(tokens) {
+            let (calls, statement) = statement_of(token);
+            let mut frames: Vec<String> = calls
+                .iter()
+                .map(|call| format!("{} {}", file_line(call), macro_name(call)))
+                .collect();
+            frames.push(file_line(statement));
+            *stacks.entry(frames.join(";")).or_default() += count;
+        }
+        let mut stacks: Vec<(String, u64)> = stacks.into_iter().collect();
+        stacks.sort();
+        stacks
+            .iter()
+            .map(|(stack, count)| format!("{stack} {count}\n"))
+            .collect()
+    }
+}
+
+/// Spaces and semicolons separate the fields of folded stacks
+fn file_line(info: &Info) -> String {
+    lexer::FILES
+        .with_borrow(|files| match files.get(info.file) {
+            Some(file) => format!("{}:{}", file.path.display(), info.line_number),
+            None => format!("?:{}", info.line_number),
+        })
+        .replace([' ', ';'], "_")
+}
+
+/// The call as it is written in the source, like '!Clear'. Syntax sugar that expands to
+/// a macro call is named by its operator
+fn macro_name(call: &Info) -> String {
+    lexer::FILES.with_borrow(|files| {
+        let name: String = files
+            .get(call.file)
+            .and_then(|file| {
+                file.contents
+                    .lines()
+                    .nth((call.line_number - 1).max(0) as usize)
+            })
+            .unwrap_or("")
+            .chars()
+            .skip((call.start_char - 1).max(0) as usize)
+            .take(call.length.max(1) as usize)
+            .collect();
+        match name.trim() {
+            "" => "?".to_owned(),
+            name => name.replace(';', "_"),
+        }
+    })
+}
+
+pub fn to_file(path: &Path, text: &str) -> Result<(), AssembleError> {
+    if let Err(e) = fs::write(path, text) {
+        error!("Failed to write the profile {}. {e}", path.display());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble_for_test;
+    use crate::runtimes::{interpreter, io::Buffered, machine::Machine};
+
+    #[test]
+    fn counts_by_line_and_macro() {
+        // The loop runs three times
+        let text = "@DecJump a? .to? [ a? -= ONE .to? ]\n.loop ->\n    !DecJump N .end\n    \
+                    Z -= Z .loop\n.end ->\n    Z -= Z -1\nN -> 3\nONE -> 1\nZ -> 0\n";
+        let (mem, tokens, _) = assemble_for_test(text);
+
+        let mut profile = Profile::new(mem.len());
+        let mut machine = Machine::new(mem, Buffered::default());
+        interpreter::interpret_profiled(&mut machine, &mut profile).unwrap();
+        assert_eq!(profile.total(), 6);
+
+        let lines: Vec<(i32, u64)> = profile
+            .by_line(&tokens)
+            .iter()
+            .map(|(info, count)| (info.line_number, *count))
+            .collect();
+        assert_eq!(lines, vec![(1, 3), (4, 2), (6, 1)]);
+        assert_eq!(profile.by_macro(&tokens), vec![("!DecJump".to_owned(), 3)]);
+        assert_eq!(
+            profile.folded_stacks(&tokens),
+            "Test.sbl:3 !DecJump;Test.sbl:1 3\nTest.sbl:4 2\nTest.sbl:6 1\n"
+        );
+    }
+}
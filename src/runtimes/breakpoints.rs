@@ -0,0 +1,272 @@
+//! Breakpoints on source lines and labels, resolved to the addresses the debugger stops at
+use std::collections::HashSet;
+use std::fmt;
+use std::path::Path;
+
+use crate::{
+    lexer,
+    listing::statement_of,
+    symbol_table::Symbol,
+    tokens::{Info, Token},
+};
+
+/// Where a breakpoint is set
+#[derive(Debug, Clone, PartialEq)]
+pub enum Location {
+    /// An index into lexer::FILES and a line number
+    Line {
+        file: usize,
+        line: i32,
+    },
+    Label(String),
+}
+
+impl Location {
+    /// 'file:line', 'line' for a line in the main file, or a label name. Files may be given
+    /// by their name or the end of their path
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let text = text.trim();
+        let (file, line) = match text.rsplit_once(':') {
+            Some((file, line)) => (Some(file), line),
+            None => (None, text),
+        };
+        let Ok(line) = line.parse::<i32>() else {
+            if text.is_empty() {
+                return Err("Expected 'file:line', a line or a label".to_owned());
+            }
+            return Ok(Location::Label(text.to_owned()));
+        };
+        let file = match file {
+            None => 0,
+            Some(name) => find_file(name)?,
+        };
+        Ok(Location::Line { file, line })
+    }
+
+    /// The addresses execution stops at. A line stops at the start of every run of words
+    /// assembled from it, so a line in a macro body stops at every expansion and a macro
+    /// call at the start of its expansion
+    pub fn resolve(&self, tokens: &[Token], symbols: &[Symbol]) -> Result<Vec<usize>, String> {
+        let addresses: Vec<usize> = match self {
+            Location::Line { file, line } => {
+                // The calls the line was expanded from, which differ between expansions
+                let expansion = |token: &Token| -> Option<Vec<Info>> {
+                    let (calls, statement) = statement_of(token);
+                    let chain: Vec<&Info> = calls.iter().chain([statement]).collect();
+                    let depth = chain
+                        .iter()
+                        .position(|info| info.file == *file && info.line_number == *line)?;
+                    Some(chain[..depth].iter().map(|info| (*info).clone()).collect())
+                };
+                let mut previous = None;
+                tokens
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(address, token)| {
+                        let current = expansion(token);
+                        let starts = current.is_some() && current != previous;
+                        previous = current;
+                        starts.then_some(address)
+                    })
+                    .collect()
+            }
+            Location::Label(name) => label_addresses(symbols, name),
+        };
+        if addresses.is_empty() {
+            return Err(match self {
+                Location::Line { .. } => format!("Nothing was assembled from {self}"),
+                Location::Label(name) => format!("There is no label '{name}'"),
+            });
+        }
+        Ok(addresses)
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Location::Line { file, line } => {
+                let path = lexer::FILES.with_borrow(|files| {
+                    files
+                        .get(*file)
+                        .map_or("?".to_owned(), |file| file.path.display().to_string())
+                });
+                write!(f, "{path}:{line}")
+            }
+            Location::Label(name) => write!(f, "{name}"),
+        }
+    }
+}
+
+/// The labels with the name, with or without the hygiene prefix of a macro and the dot of a
+/// jump target
+pub(crate) fn find_labels<'a>(symbols: &'a [Symbol], name: &str) -> Vec<&'a Symbol> {
+    symbols
+        .iter()
+        .filter(|symbol| {
+            [symbol.name.as_str(), symbol.short_name()]
+                .iter()
+                .any(|label| *label == name || label.strip_prefix('.') == Some(name))
+        })
+        .collect()
+}
+
+pub(crate) fn label_addresses(symbols: &[Symbol], name: &str) -> Vec<usize> {
+    find_labels(symbols, name)
+        .iter()
+        .map(|symbol| symbol.address)
+        .collect()
+}
+
+/// The index of the file whose path ends with name
+fn find_file(name: &str) -> Result<usize, String> {
+    let matches: Vec<(usize, String)> = lexer::FILES.with_borrow(|files| {
+        files
+            .iter()
+            .enumerate()
+            .filter(|(_, file)| file.path.ends_with(Path::new(name)))
+            .map(|(index, file)| (index, file.path.display().to_string()))
+            .collect()
+    });
+    match matches.as_slice() {
+        [(index, _)] => Ok(*index),
+        [] => Err(format!("No file '{name}' was assembled")),
+        _ => Err(format!(
+            "'{name}' could be any of {}",
+            matches
+                .iter()
+                .map(|(_, path)| path.as_str())
+                .collect::<Vec<&str>>()
+                .join(", ")
+        )),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Breakpoint {
+    pub location: Location,
+    pub addresses: Vec<usize>,
+}
+
+/// The breakpoints set in the debugger, numbered from 1 in the order they were set
+#[derive(Debug, Default)]
+pub struct Breakpoints {
+    list: Vec<Breakpoint>,
+    addresses: HashSet<usize>,
+}
+
+impl Breakpoints {
+    /// Parses and resolves the location, see Location
+    pub fn add(
+        &mut self,
+        text: &str,
+        tokens: &[Token],
+        symbols: &[Symbol],
+    ) -> Result<&Breakpoint, String> {
+        self.add_location(Location::parse(text)?, tokens, symbols)
+    }
+
+    pub fn add_location(
+        &mut self,
+        location: Location,
+        tokens: &[Token],
+        symbols: &[Symbol],
+    ) -> Result<&Breakpoint, String> {
+        if self
+            .list
+            .iter()
+            .any(|breakpoint| breakpoint.location == location)
+        {
+            return Err(format!("There already is a breakpoint at {location}"));
+        }
+        let addresses = location.resolve(tokens, symbols)?;
+        self.addresses.extend(&addresses);
+        self.list.push(Breakpoint {
+            location,
+            addresses,
+        });
+        Ok(self.list.last().unwrap())
+    }
+
+    /// Removes the breakpoint with the number or location
+    pub fn remove(&mut self, text: &str) -> Result<Breakpoint, String> {
+        let index = match text.trim().parse::<usize>() {
+            Ok(number) if (1..=self.list.len()).contains(&number) => number - 1,
+            Ok(number) => return Err(format!("There is no breakpoint {number}")),
+            Err(_) => {
+                let location = Location::parse(text)?;
+                self.list
+                    .iter()
+                    .position(|breakpoint| breakpoint.location == location)
+                    .ok_or_else(|| format!("There is no breakpoint at {location}"))?
+            }
+        };
+        let removed = self.list.remove(index);
+        self.addresses = self
+            .list
+            .iter()
+            .flat_map(|breakpoint| breakpoint.addresses.iter().copied())
+            .collect();
+        Ok(removed)
+    }
+
+    pub fn clear(&mut self) {
+        self.list.clear();
+        self.addresses.clear();
+    }
+
+    pub fn list(&self) -> &[Breakpoint] {
+        &self.list
+    }
+
+    /// Whether execution stops at the address
+    pub fn contains(&self, address: usize) -> bool {
+        self.addresses.contains(&address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble_for_test;
+
+    #[test]
+    fn lines_and_labels() {
+        let text = "@Clear a? {\n    a? -= a?\n}\n.start ->\n    !Clear A\n    !Clear B\n    \
+                    Z -= Z -1\nA -> 1\nB -> 2\nZ -> 0\n";
+        let (_, tokens, symbols) = assemble_for_test(text);
+
+        let mut breakpoints = Breakpoints::default();
+        // The body of the macro, at both expansions
+        let breakpoint = breakpoints.add("Test.sbl:2", &tokens, &symbols).unwrap();
+        assert_eq!(breakpoint.addresses, vec![0, 3]);
+        assert_eq!(breakpoint.location.to_string(), "Test.sbl:2");
+        // A call stops at its expansion
+        assert_eq!(
+            breakpoints.add("6", &tokens, &symbols).unwrap().addresses,
+            vec![3]
+        );
+        assert_eq!(
+            breakpoints.add("Z", &tokens, &symbols).unwrap().addresses,
+            vec![11]
+        );
+        assert_eq!(
+            breakpoints
+                .add("start", &tokens, &symbols)
+                .unwrap()
+                .addresses,
+            vec![0]
+        );
+        assert!(breakpoints.add("start", &tokens, &symbols).is_err());
+        assert!(breakpoints.add("Other.sbl:2", &tokens, &symbols).is_err());
+        assert!(breakpoints.add("Test.sbl:3", &tokens, &symbols).is_err());
+
+        assert!(breakpoints.contains(3));
+        breakpoints.remove("1").unwrap();
+        assert!(breakpoints.contains(3));
+        breakpoints.remove("Test.sbl:6").unwrap();
+        assert!(!breakpoints.contains(3));
+        assert!(breakpoints.remove("7").is_err());
+        assert_eq!(breakpoints.list().len(), 2);
+    }
+}
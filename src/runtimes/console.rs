@@ -0,0 +1,393 @@
+//! The commands of the debugger's ':' prompt, to look at and change the memory of a paused
+//! program by label.
+//!
+//! Places in memory are written like:
+//! * 'label' or an address like '0x1F'
+//! * '*ptr', the word ptr points to
+//! * 'label[3]' and 'label[0..10]', words after the label
+use crate::runtimes::breakpoints::{Breakpoints, find_labels};
+use crate::runtimes::io::Io;
+use crate::runtimes::machine::{Machine, Step};
+use crate::runtimes::watchpoints::{Watchpoints, parse_word};
+use crate::symbol_table::Symbol;
+use crate::tokens::Token;
+
+/// How long a string may be when it is read up to its null
+const MAX_STRING_LENGTH: usize = 256;
+
+const HELP: &str = "print <place> [as int|hex|char|string], set <place> = <value>, \
+                    goto <place>, break [location], watch [watchpoint], info labels [filter]";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Int,
+    Hex,
+    Char,
+    String,
+}
+
+/// Words in memory, see the module documentation
+#[derive(Debug, Clone, PartialEq)]
+struct Place {
+    /// As it was written, with the full name of the label
+    name: String,
+    address: usize,
+    /// Only set for ranges
+    length: Option<usize>,
+}
+
+/// Runs the command, returning what it prints or why it failed. Changes to memory and pc
+/// are pushed to history, so they can be stepped back. Without a history, when a recorded
+/// run is replayed, they are refused, since the replay has to follow the recording
+pub fn execute<I: Io>(
+    command: &str,
+    machine: &mut Machine<I>,
+    history: Option<&mut Vec<Step>>,
+    tokens: &[Token],
+    symbols: &[Symbol],
+    breakpoints: &mut Breakpoints,
+    watchpoints: &mut Watchpoints,
+) -> Result<String, String> {
+    let command = command.trim();
+    let (name, rest) = command
+        .split_once(char::is_whitespace)
+        .unwrap_or((command, ""));
+    let rest = rest.trim();
+    match name {
+        "print" | "p" => print(rest, &machine.mem, symbols),
+        "set" | "goto" if history.is_none() => {
+            Err(format!("Can't {name} while replaying a recorded run"))
+        }
+        "set" => {
+            let (place, value) = rest
+                .split_once('=')
+                .ok_or("Expected 'set <place> = <value>'")?;
+            let place = single(place, &machine.mem, symbols)?;
+            let value = value_of(value.trim(), symbols)?;
+            let step = machine.set(place.address, value);
+            history.unwrap().push(step);
+            Ok(format!("{} = {}", place.name, value as i16))
+        }
+        "goto" => {
+            let place = single(rest, &machine.mem, symbols)?;
+            let step = machine.goto(place.address);
+            history.unwrap().push(step);
+            Ok(format!("Continuing at 0x{:04X}", place.address))
+        }
+        "break" | "b" if rest.is_empty() => Ok(breakpoints
+            .list()
+            .iter()
+            .enumerate()
+            .map(|(i, breakpoint)| format!("{} {}", i + 1, breakpoint.location))
+            .collect::<Vec<String>>()
+            .join("\n")),
+        "break" | "b" => {
+            let breakpoint = breakpoints.add(rest, tokens, symbols)?;
+            Ok(format!(
+                "Breakpoint at {}, {} address(es)",
+                breakpoint.location,
+                breakpoint.addresses.len()
+            ))
+        }
+        "watch" | "w" if rest.is_empty() => Ok(watchpoints
+            .list()
+            .iter()
+            .enumerate()
+            .map(|(i, watchpoint)| format!("{} {watchpoint}", i + 1))
+            .collect::<Vec<String>>()
+            .join("\n")),
+        "watch" | "w" => {
+            let watchpoint = watchpoints.add(rest, machine.mem.len(), symbols)?;
+            Ok(format!(
+                "Watching {watchpoint}, {} address(es)",
+                watchpoint.addresses.len()
+            ))
+        }
+        "info" => match rest.split_once(char::is_whitespace).unwrap_or((rest, "")) {
+            ("labels", filter) => Ok(labels(filter.trim(), &machine.mem, symbols)),
+            _ => Err("Expected 'info labels [filter]'".to_owned()),
+        },
+        "help" | "" => Ok(HELP.to_owned()),
+        _ => Err(format!("Unknown command '{name}'. {HELP}")),
+    }
+}
+
+fn print(text: &str, mem: &[u16], symbols: &[Symbol]) -> Result<String, String> {
+    let (text, format) = match text.rsplit_once(" as ") {
+        Some((text, format)) => (
+            text,
+            match format.trim() {
+                "int" => Format::Int,
+                "hex" => Format::Hex,
+                "char" => Format::Char,
+                "string" => Format::String,
+                format => return Err(format!("Can't print as '{format}'")),
+            },
+        ),
+        None => (text, Format::Int),
+    };
+    let lines: Vec<String> = places(text, mem, symbols)?
+        .iter()
+        .map(|place| {
+            let words = match (place.length, format) {
+                (Some(length), _) => &mem[place.address..place.address + length],
+                // A string goes up to its null
+                (None, Format::String) => {
+                    let rest = &mem[place.address..];
+                    let length = rest.iter().position(|word| *word == 0);
+                    &rest[..length.unwrap_or(rest.len()).min(MAX_STRING_LENGTH)]
+                }
+                (None, _) => &mem[place.address..=place.address],
+            };
+            let value = match format {
+                Format::String => {
+                    format!(
+                        "{:?}",
+                        words
+                            .iter()
+                            .map(|word| *word as u8 as char)
+                            .collect::<String>()
+                    )
+                }
+                _ if place.length.is_none() => format_word(words[0], format),
+                _ => format!(
+                    "[{}]",
+                    words
+                        .iter()
+                        .map(|word| format_word(*word, format))
+                        .collect::<Vec<String>>()
+                        .join(", ")
+                ),
+            };
+            format!("{} (0x{:04X}) = {value}", place.name, place.address)
+        })
+        .collect();
+    Ok(lines.join("\n"))
+}
+
+fn format_word(word: u16, format: Format) -> String {
+    match format {
+        Format::Int => (word as i16).to_string(),
+        Format::Hex => format!("0x{word:04X}"),
+        Format::Char | Format::String => format!("{:?}", word as u8 as char),
+    }
+}
+
+/// A number, a char like 'a' or the address of a label
+fn value_of(text: &str, symbols: &[Symbol]) -> Result<u16, String> {
+    if let Some(ch) = text
+        .strip_prefix('\'')
+        .and_then(|text| text.strip_suffix('\''))
+        .filter(|ch| ch.chars().count() == 1)
+    {
+        return Ok(ch.chars().next().unwrap() as u16);
+    }
+    if let Some(value) = parse_word(text) {
+        return Ok(value);
+    }
+    match find_labels(symbols, text).as_slice() {
+        [symbol] => Ok(symbol.address as u16),
+        [] => Err(format!("'{text}' is not a number, char or label")),
+        _ => Err(ambiguous(symbols, text)),
+    }
+}
+
+fn ambiguous(symbols: &[Symbol], name: &str) -> String {
+    let names: Vec<String> = find_labels(symbols, name)
+        .iter()
+        .map(|symbol| symbol.name.clone())
+        .collect();
+    format!(
+        "There are several labels '{name}', use one of {}",
+        names.join(", ")
+    )
+}
+
+/// The one word the text is about
+fn single(text: &str, mem: &[u16], symbols: &[Symbol]) -> Result<Place, String> {
+    let places = places(text, mem, symbols)?;
+    match places.as_slice() {
+        [place] if place.length.is_none() => Ok(place.clone()),
+        [_] => Err("Expected a single word, not a range".to_owned()),
+        _ => Err(ambiguous(symbols, text.trim().trim_start_matches('*'))),
+    }
+}
+
+/// Every place the text is about. A label defined in a macro has a place per expansion
+fn places(text: &str, mem: &[u16], symbols: &[Symbol]) -> Result<Vec<Place>, String> {
+    let text = text.trim();
+    let (deref, text) = match text.strip_prefix('*') {
+        Some(text) => (true, text.trim()),
+        None => (false, text),
+    };
+    let (base, index) = match text.split_once('[') {
+        Some((base, index)) => (
+            base.trim(),
+            Some(index.strip_suffix(']').ok_or("Expected a ']'")?),
+        ),
+        None => (text, None),
+    };
+    let parse_offset = |text: &str| {
+        text.trim()
+            .parse::<usize>()
+            .map_err(|_| format!("'{}' is not an index", text.trim()))
+    };
+    let (offset, length) = match index.map(|index| index.split_once("..")) {
+        None => (0, None),
+        Some(None) => (parse_offset(index.unwrap())?, None),
+        Some(Some((start, end))) => {
+            let (start, end) = (parse_offset(start)?, parse_offset(end)?);
+            (start, Some(end.saturating_sub(start)))
+        }
+    };
+
+    let bases: Vec<(String, usize)> = match parse_word(base) {
+        Some(address) => vec![(base.to_owned(), address as usize)],
+        None => find_labels(symbols, base)
+            .into_iter()
+            .map(|symbol| (symbol.name.clone(), symbol.address))
+            .collect(),
+    };
+    if bases.is_empty() {
+        return Err(format!("There is no label '{base}'"));
+    }
+    bases
+        .into_iter()
+        .map(|(name, address)| {
+            let (name, address) = match deref {
+                true => (
+                    format!("*{name}"),
+                    *mem.get(address)
+                        .ok_or(format!("0x{address:04X} is outside of memory"))?
+                        as usize,
+                ),
+                false => (name, address),
+            };
+            let name = match index {
+                Some(index) => format!("{name}[{index}]"),
+                None => name,
+            };
+            // Indices are typed by the user, so they can be as large as a usize
+            let Some(address) = address.checked_add(offset).filter(|address| {
+                address
+                    .checked_add(length.unwrap_or(1))
+                    .is_some_and(|end| end <= mem.len())
+            }) else {
+                return Err(format!("{name} is outside of memory"));
+            };
+            Ok(Place {
+                name,
+                address,
+                length,
+            })
+        })
+        .collect()
+}
+
+/// The labels whose name contains the filter, with their address and value
+fn labels(filter: &str, mem: &[u16], symbols: &[Symbol]) -> String {
+    symbols
+        .iter()
+        .filter(|symbol| symbol.name.contains(filter))
+        .map(|symbol| {
+            let value = mem
+                .get(symbol.address)
+                .map_or("-".to_owned(), |word| (*word as i16).to_string());
+            format!("0x{:04X}  {} = {value}", symbol.address, symbol.name)
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble_for_test;
+    use crate::runtimes::io::Buffered;
+
+    #[test]
+    fn commands() {
+        let text = "@Local {\n    l -> 7\n}\nZ -= Z -1\nptr -> text\ntext -> \"Hi\"\n!Local\n!Local\n\
+                    Z -> 0\n";
+        let (mem, tokens, symbols) = assemble_for_test(text);
+        let mut machine = Machine::new(mem, Buffered::default());
+        let (mut breakpoints, mut watchpoints) = (Breakpoints::default(), Watchpoints::default());
+        let mut history = Vec::new();
+        let mut run = |command: &str| {
+            execute(
+                command,
+                &mut machine,
+                Some(&mut history),
+                &tokens,
+                &symbols,
+                &mut breakpoints,
+                &mut watchpoints,
+            )
+        };
+
+        assert_eq!(run("print ptr"), Ok("ptr (0x0003) = 4".to_owned()));
+        assert_eq!(
+            run("print *ptr as char"),
+            Ok("*ptr (0x0004) = 'H'".to_owned())
+        );
+        assert_eq!(
+            run("p text[0..3] as hex"),
+            Ok("text[0..3] (0x0004) = [0x0048, 0x0069, 0x0000]".to_owned())
+        );
+        assert_eq!(
+            run("print *ptr as string"),
+            Ok("*ptr (0x0004) = \"Hi\"".to_owned())
+        );
+        // A local label has a place per expansion
+        assert_eq!(run("print l").unwrap().lines().count(), 2);
+        assert!(
+            run("set l = 1")
+                .unwrap_err()
+                .starts_with("There are several")
+        );
+
+        assert_eq!(run("set text[1] = 'o'"), Ok("text[1] = 111".to_owned()));
+        assert_eq!(run("set ptr = Z"), Ok("ptr = 9".to_owned()));
+        assert!(run("set text[0..2] = 1").is_err());
+        assert_eq!(run("goto 0x3"), Ok("Continuing at 0x0003".to_owned()));
+        assert!(run("print missing").is_err());
+        assert!(run("print text[0..20]").is_err());
+        assert!(run("print text[18446744073709551615]").is_err());
+        assert!(run("print text[1..18446744073709551615]").is_err());
+
+        assert!(run("break Test.sbl:4").is_ok());
+        assert_eq!(run("break"), Ok("1 Test.sbl:4".to_owned()));
+        assert!(run("watch text changed").is_ok());
+        assert_eq!(run("watch"), Ok("1 text changed".to_owned()));
+        assert_eq!(
+            run("info labels t"),
+            Ok("0x0003  ptr = 9\n0x0004  text = 72".to_owned())
+        );
+        assert!(run("frobnicate").is_err());
+
+        assert_eq!(machine.pc, 3);
+        assert_eq!(&machine.mem[3..6], &[9, 'H' as u16, 'o' as u16]);
+
+        // Stepping back undoes the changes, without counting them as instructions
+        assert_eq!(history.len(), 3);
+        for step in history.iter().rev() {
+            machine.undo(step);
+        }
+        assert_eq!(machine.pc, 0);
+        assert_eq!(&machine.mem[3..6], &[4, 'H' as u16, 'i' as u16]);
+        assert_eq!(machine.instructions_executed, 0);
+
+        // A replay has to follow the recording
+        let replay = execute(
+            "goto 0x3",
+            &mut machine,
+            None,
+            &tokens,
+            &symbols,
+            &mut breakpoints,
+            &mut watchpoints,
+        );
+        assert!(replay.is_err());
+        assert_eq!(machine.pc, 0);
+    }
+}
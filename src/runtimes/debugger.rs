@@ -1,7 +1,16 @@
 use crate::lexer;
+use crate::runtimes::breakpoints::{Breakpoints, Location};
+use crate::runtimes::console;
+use crate::runtimes::io::{EndOfInput, Io};
+use crate::runtimes::machine::{IoEffect, Machine, Step, StepOutcome};
+use crate::runtimes::trace::Trace;
+use crate::runtimes::variables::Variables;
+use crate::runtimes::watchpoints::{Watchpoint, Watchpoints};
 use crate::runtimes::{RuntimeError, get_key};
+use crate::symbol_table::Symbol;
 use crate::symbols::{DEBUG_ADDR, IO_ADDR};
 use crate::{
+    listing::{source_line, statement_of},
     mem_view,
     tokens::{Info, Token},
 };
@@ -11,10 +20,8 @@ use crossterm::{
     event::KeyCode,
     terminal::{self},
 };
-use std::fs;
-use std::io::{self};
-use std::num::Wrapping;
-use std::path::PathBuf;
+use std::collections::HashSet;
+use std::io::{self, Write};
 
 enum DataType {
     Char,
@@ -22,35 +29,26 @@ enum DataType {
     Hex,
 }
 
-#[derive(Debug, PartialEq, Eq, Clone)]
-enum IOOperation {
-    Char(char),
-    Debug(u16),
-    Halt,
-    None,
-}
-
-#[derive(Debug, PartialEq, Eq, Clone)]
-struct InstructionHistoryItem {
-    pub pc: usize,
-    pub original_value_at_b: u16,
-    pub io_operation: IOOperation,
-}
-
-fn revert_historic_instruction(
-    mem: &mut [u16],
-    inst: &InstructionHistoryItem,
-    io_buffer: &mut String,
-) -> usize {
-    let b = mem[inst.pc + 1] as usize;
-    if b < mem.len() {
-        mem[b] = inst.original_value_at_b;
+/// The output shown below the preview
+fn push_output(io_buffer: &mut String, io: IoEffect) {
+    match io {
+        IoEffect::Char(ch) => io_buffer.push(ch),
+        IoEffect::Debug(value) => io_buffer.push_str(&format!("{value}\n")),
+        _ => {}
     }
+}
 
-    if let IOOperation::Char(..) = inst.io_operation {
-        io_buffer.pop();
+fn pop_output(io_buffer: &mut String, io: IoEffect) {
+    match io {
+        IoEffect::Char(..) => {
+            io_buffer.pop();
+        }
+        IoEffect::Debug(value) => {
+            let length = format!("{value}\n").len();
+            io_buffer.truncate(io_buffer.len().saturating_sub(length));
+        }
+        _ => {}
     }
-    inst.pc
 }
 
 fn val_to_string(val: u16, data_type: DataType) -> String {
@@ -68,38 +66,29 @@ fn val_to_string(val: u16, data_type: DataType) -> String {
         DataType::Hex => format!("{val:X}"),
     }
 }
-fn address_to_string(addr: u16, mem: &[u16], data_type: DataType) -> String {
+fn address_to_string<I: Io>(addr: u16, machine: &Machine<I>, data_type: DataType) -> String {
     match addr as usize {
         IO_ADDR => "IO".to_string(),
         DEBUG_ADDR => "Debug".to_string(),
-        x if x >= mem.len() => "OOB".to_string(),
+        x if machine.devices.claims(x) => "Device".to_string(),
+        x if x >= machine.mem.len() => "OOB".to_string(),
 
-        _ => val_to_string(mem[addr as usize], data_type),
+        _ => val_to_string(machine.mem[addr as usize], data_type),
     }
 }
 
-fn get_file_contents(path: &PathBuf) -> String {
-    fs::read_to_string(path)
-        .unwrap_or_else(|_| crate::error!("Debugger couldn't read file: {}", path.display()))
-}
-
 /// Display file preview and instruction that is being ran
-fn display(
+fn display<I: Io>(
     info: &Info,
     pc: usize,
     new_pc: usize,
     result: u16,
-    mem: &[u16],
+    machine: &Machine<I>,
     current_error: &Option<RuntimeError>,
+    cursor: Option<i32>,
 ) {
-    let file = lexer::FILES.with_borrow(|files| {
-        if !files.is_empty() {
-            // Cant just use .get() because the internal value has to be cloned.
-            Some(files[info.file].clone())
-        } else {
-            None
-        }
-    });
+    // The preview shows the contents that were assembled, not what is currently on disk
+    let file = lexer::FILES.with_borrow(|files| files.get(info.file).cloned());
 
     let file = match file {
         None => return,
@@ -107,12 +96,17 @@ fn display(
     };
     println!(
         "[ESC] exit debug mode       [UP/k] up  [DOWN/j] down  [LEFT/h] previous instruction
-[RIGHT/l] next instruction  [DELETE] halt   [m] toggle memory mode\n"
+[RIGHT/l] next instruction  [DELETE] halt   [m] toggle memory mode
+[b] add breakpoint          [B] remove breakpoint
+[w] add watchpoint          [W] remove watchpoint
+[n/N] step over/back over   [f/F] step out/back out
+[J/K] move cursor           [c/C] run/back to cursor
+[v] show variable           [V] hide variable
+[:] command, like 'print label', 'set label = 1' or 'help'\n"
     );
-    println!("{}:{: <100} ", file.display(), info.line_number);
-    let contents = get_file_contents(&file);
+    println!("{}:{: <100} ", file.path.display(), info.line_number);
 
-    let lines = contents.lines().collect::<Vec<&str>>();
+    let lines = file.contents.lines().collect::<Vec<&str>>();
 
     const UPPER_SIZE: i32 = 15;
     const LOWER_SIZE: i32 = 15;
@@ -131,9 +125,11 @@ fn display(
     // File preview
     for i in start_line..end_line {
         if i != info.line_number - 1 {
+            let marker = if cursor == Some(i + 1) { "*" } else { "|" };
             println!(
-                "{: >4} | {: <100}",
+                "{: >4} {} {: <100}",
                 format!("{}", i + 1).bright_cyan(),
+                marker.yellow(),
                 lines[i as usize]
             );
         } else {
@@ -152,6 +148,7 @@ fn display(
     }
 
     // Instruction preview
+    let mem = &machine.mem;
     if pc + 2 >= mem.len() {
         println!("Out of bounds");
         return;
@@ -162,37 +159,261 @@ fn display(
 
     println!(
         " {: >6} - {: >6} = {: >6} ",
-        address_to_string(mem[pc + 1], mem, DataType::Int),
-        address_to_string(mem[pc], mem, DataType::Int),
+        address_to_string(mem[pc + 1], machine, DataType::Int),
+        address_to_string(mem[pc], machine, DataType::Int),
         val_to_string(result, DataType::Int)
     );
     println!(
         " {: >6} - {: >6} = {: >6} ",
-        address_to_string(mem[pc + 1], mem, DataType::Hex),
-        address_to_string(mem[pc], mem, DataType::Hex),
+        address_to_string(mem[pc + 1], machine, DataType::Hex),
+        address_to_string(mem[pc], machine, DataType::Hex),
         val_to_string(result, DataType::Hex)
     );
     println!(
         " {: >6} - {: >6} = {: >6} ",
-        address_to_string(mem[pc + 1], mem, DataType::Char),
-        address_to_string(mem[pc], mem, DataType::Char),
+        address_to_string(mem[pc + 1], machine, DataType::Char),
+        address_to_string(mem[pc], machine, DataType::Char),
         val_to_string(result, DataType::Char)
     );
     println!("c: {: <100} ", val_to_string(new_pc as u16, DataType::Hex));
 }
 
-pub fn run_with_debugger(mem: &mut [u16], tokens: &[Token]) {
-    debug(mem, tokens, false, get_key);
+/// The macro calls and the statement a word was expanded from, outermost first. These are
+/// the depths the debugger can show
+fn frames(token: &Token) -> &[Info] {
+    if token.origin_info.is_empty() {
+        std::slice::from_ref(&token.info)
+    } else {
+        &token.origin_info
+    }
+}
+
+/// The deepest frame of the word at pc, 0 for a word that wasn't expanded from a macro
+fn deepest(tokens: &[Token], pc: usize) -> usize {
+    tokens.get(pc).map_or(0, |token| frames(token).len() - 1)
+}
+
+/// The file and line of the frame at depth, with the calls leading to it, which tell
+/// expansions apart. None if the word at pc wasn't expanded that deep
+type Expansion = (Vec<Info>, usize, i32);
+
+fn expansion(tokens: &[Token], pc: usize, depth: usize) -> Option<Expansion> {
+    let frames = frames(tokens.get(pc)?);
+    let frame = frames.get(depth)?;
+    Some((frames[..depth].to_vec(), frame.file, frame.line_number))
+}
+
+/// Where running stops, besides breakpoints, watchpoints and errors
+enum RunUntil {
+    /// Stepping over or out of a macro call, until the word at pc isn't part of the expansion
+    Leaving(usize, Option<Expansion>),
+    /// Running to the cursor, until pc is at one of the addresses of its line
+    Reaching(HashSet<usize>),
+}
+
+impl RunUntil {
+    fn is_reached(&self, tokens: &[Token], pc: usize) -> bool {
+        match self {
+            RunUntil::Leaving(depth, current) => expansion(tokens, pc, *depth) != *current,
+            RunUntil::Reaching(addresses) => addresses.contains(&pc),
+        }
+    }
+}
+
+/// Undoes the last executed instruction, false if there is none
+fn step_back<I: Io>(
+    machine: &mut Machine<I>,
+    history: &mut Vec<Step>,
+    io_buffer: &mut String,
+) -> bool {
+    match history.pop() {
+        Some(step) => {
+            machine.undo(&step);
+            pop_output(io_buffer, step.io);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Steps back while the previous instruction is in the same expansion at depth as the
+/// current one, so it ends where that expansion was entered
+fn back_to_start<I: Io>(
+    machine: &mut Machine<I>,
+    history: &mut Vec<Step>,
+    io_buffer: &mut String,
+    tokens: &[Token],
+    depth: usize,
+) {
+    let current = expansion(tokens, machine.pc, depth);
+    while history
+        .last()
+        .is_some_and(|step| expansion(tokens, step.pc, depth) == current)
+    {
+        step_back(machine, history, io_buffer);
+    }
+}
+
+/// Which instruction triggered the watchpoint, and what it wrote
+fn watchpoint_message(
+    watchpoint: &Watchpoint,
+    step: &Step,
+    mem: &[u16],
+    tokens: &[Token],
+) -> String {
+    let mut text = format!(
+        "Watchpoint {watchpoint}: 0x{:04X} went from {} to {}, written by the instruction at 0x{:04X}",
+        step.b, step.original_value_at_b as i16, mem[step.b] as i16, step.pc
+    );
+    if let Some(token) = tokens.get(step.pc) {
+        let (calls, statement) = statement_of(token);
+        text.push_str(&format!("\n    {}", source_line(statement)));
+        if let Some(call) = calls.last() {
+            text.push_str(&format!("\n    called from {}", source_line(call)));
+        }
+    }
+    text
+}
+
+/// Reads a line of text with the debugger's keys. None if it is cancelled with ESC
+fn read_line(prompt: &str, input: &mut impl FnMut() -> KeyCode) -> Option<String> {
+    let mut text = String::new();
+    loop {
+        print!("\r{prompt}{text: <60}");
+        io::stdout().flush().ok();
+        match input() {
+            KeyCode::Enter => {
+                println!();
+                return Some(text);
+            }
+            KeyCode::Esc => {
+                println!();
+                return None;
+            }
+            KeyCode::Backspace => {
+                text.pop();
+            }
+            KeyCode::Char(ch) => text.push(ch),
+            _ => {}
+        }
+    }
+}
+
+/// The program's io while it runs in the debugger. Output is only shown by the debugger,
+/// so it doesn't end up in its screen, and input is asked for with a prompt
+struct DebuggerIo<'a, I: Io> {
+    io: &'a mut I,
 }
 
-fn debug<T: FnMut() -> KeyCode>(
-    mem: &mut [u16],
+impl<I: Io> Io for DebuggerIo<'_, I> {
+    fn read_char(&mut self) -> Option<char> {
+        print!("Input: ");
+        io::stdout().flush().ok();
+        let input = self.io.read_char();
+        println!();
+        input
+    }
+    fn write_char(&mut self, _: char) {}
+    fn write_debug(&mut self, _: i16) {}
+    fn flush(&mut self) {}
+    fn end_of_input(&self) -> EndOfInput {
+        self.io.end_of_input()
+    }
+}
+
+/// The debugger is controlled with the keyboard, the program itself uses the machine's io.
+/// Its output is written to the io when the debugger exits, without what was stepped back
+pub fn run_with_debugger<I: Io>(
+    machine: &mut Machine<I>,
+    tokens: &[Token],
+    symbols: &[Symbol],
+    breakpoints: &mut Breakpoints,
+    watchpoints: &mut Watchpoints,
+) {
+    run_with_keys(machine, tokens, symbols, breakpoints, watchpoints, get_key);
+}
+
+fn run_with_keys<I: Io>(
+    machine: &mut Machine<I>,
     tokens: &[Token],
+    symbols: &[Symbol],
+    breakpoints: &mut Breakpoints,
+    watchpoints: &mut Watchpoints,
+    input: impl FnMut() -> KeyCode,
+) {
+    let history = machine.with_io(
+        |io| DebuggerIo { io },
+        |machine| {
+            debug(
+                machine,
+                tokens,
+                symbols,
+                false,
+                false,
+                breakpoints,
+                watchpoints,
+                input,
+                Machine::step,
+            )
+        },
+    );
+    for step in history {
+        match step.io {
+            IoEffect::Char(ch) => machine.io.write_char(ch),
+            IoEffect::Debug(value) => machine.io.write_debug(value),
+            _ => {}
+        }
+    }
+    machine.flush();
+}
+
+/// Steps through a run recorded with --trace. The trace is applied instead of running the
+/// program, so no input is needed. The run stops before the instruction that halted it
+pub fn replay<I: Io>(
+    machine: &mut Machine<I>,
+    tokens: &[Token],
+    symbols: &[Symbol],
+    trace: &Trace,
+    breakpoints: &mut Breakpoints,
+    watchpoints: &mut Watchpoints,
+) {
+    debug(
+        machine,
+        tokens,
+        symbols,
+        true,
+        true,
+        breakpoints,
+        watchpoints,
+        get_key,
+        |machine| apply_next(machine, trace),
+    );
+}
+
+/// Stepping back undoes entries, so the next one follows from the instructions executed
+fn apply_next<I: Io>(machine: &mut Machine<I>, trace: &Trace) -> StepOutcome {
+    match trace.entries.get(machine.instructions_executed as usize) {
+        Some(entry) if !(entry.jumped && entry.c as usize == IO_ADDR) => machine.apply(entry),
+        _ => StepOutcome::Paused,
+    }
+}
+
+/// advance executes the current instruction, Paused stops in debugging mode, as does reaching
+/// a breakpoint or triggering a watchpoint. When replaying, the console can't change memory
+/// or pc. Returns the steps that weren't stepped back
+#[allow(clippy::too_many_arguments)]
+fn debug<I: Io, T: FnMut() -> KeyCode>(
+    machine: &mut Machine<I>,
+    tokens: &[Token],
+    symbols: &[Symbol],
     mut in_debugging_mode: bool,
+    replaying: bool,
+    breakpoints: &mut Breakpoints,
+    watchpoints: &mut Watchpoints,
     mut input: T,
-) {
-    let mut pc = 0;
-    let mut instruction_history: Vec<InstructionHistoryItem> = Vec::new();
+    mut advance: impl FnMut(&mut Machine<I>) -> StepOutcome,
+) -> Vec<Step> {
+    let mut history: Vec<Step> = Vec::new();
     let mut io_buffer: String = String::new();
     let mut current_depth: usize = 0;
     let mut stdout = io::stdout();
@@ -201,112 +422,267 @@ fn debug<T: FnMut() -> KeyCode>(
         .unwrap();
     let stay_in_file = false;
     let mut mem_mode: bool = false;
-    let mut current_error: Option<RuntimeError> = None;
+    // Shown below the breakpoints until the next key
+    let mut message = String::new();
+    let mut run_until: Option<RunUntil> = None;
+    // The file and line of the cursor, which is moved from the shown line
+    let mut cursor: Option<(usize, i32)> = None;
+    let mut variables = Variables::default();
     loop {
-        // Run the instruction but don't apply it yet
-        if pc + 2 >= mem.len() {
-            current_error = Some(RuntimeError::COutOfRange(pc));
-        }
-
-        let a = mem[pc] as usize;
-        let b = mem[pc + 1] as usize;
-        let c = mem[pc + 2] as usize;
-
-        let mut original_value_at_b = 0;
-
-        let mut result: u16 = 0;
-        let mut io: IOOperation = IOOperation::None;
-
-        match (a, b) {
-            (a, _) if a >= mem.len() && a != IO_ADDR => {
-                current_error = Some(RuntimeError::AOutOfRange(pc));
-            }
-            (_, b) if b >= mem.len() && b != IO_ADDR && b != DEBUG_ADDR => {
-                current_error = Some(RuntimeError::BOutOfRange(pc));
-            }
-            (_, IO_ADDR) => {
-                result = mem[a];
-                io = IOOperation::Char(result as u8 as char);
-            }
-            (_, DEBUG_ADDR) => {
-                result = mem[a];
-                io = IOOperation::Debug(result);
-            }
-            (IO_ADDR, _) => {
-                println!("Input: ");
-                let c = match get_key() {
-                    KeyCode::Char(x) => x,
-                    _ => '\0',
-                };
-                result = c as u16;
-            }
-            (_, _) => {
-                original_value_at_b = mem[b];
-                result = (Wrapping(mem[b]) - (Wrapping(mem[a]))).0;
-            }
-        }
-
-        let new_pc = if result as i16 <= 0 {
-            match c {
-                IO_ADDR => {
-                    io = IOOperation::Halt;
-                    c
-                }
-                DEBUG_ADDR => {
-                    current_error = Some(RuntimeError::Breakpoint(pc));
-                    pc + 3
-                }
-                _ => c,
+        // Look at the instruction before running it
+        let preview = machine.preview();
+        let current_error = match &preview {
+            Err(e) => Some(e.clone()),
+            Ok(preview) if preview.jumps && preview.c == DEBUG_ADDR => {
+                Some(RuntimeError::Breakpoint(machine.pc))
             }
-        } else {
-            pc + 3
+            Ok(_) => None,
         };
 
         if current_error.is_some() {
             in_debugging_mode = true;
         }
+        if !in_debugging_mode && breakpoints.contains(machine.pc) {
+            in_debugging_mode = true;
+        }
+        if let Some(until) = &run_until
+            && until.is_reached(tokens, machine.pc)
+        {
+            in_debugging_mode = true;
+        }
 
         if in_debugging_mode {
+            run_until = None;
             stdout.execute(crossterm::cursor::MoveTo(0, 0)).unwrap();
 
-            let origin_info = &tokens[pc].origin_info;
-            let info = if origin_info.is_empty() {
-                &tokens[pc].info
-            } else {
-                let file_name = &origin_info[0].file; // Suboptimal
+            // An instruction outside of memory is shown where it was jumped to from
+            let pc = match current_error {
+                Some(RuntimeError::COutOfRange(prev_pc)) => prev_pc,
+                _ => machine.pc,
+            };
+            let (result, new_pc) = match &preview {
+                Ok(preview) => (
+                    preview.result.unwrap_or(0),
+                    if preview.jumps {
+                        preview.c
+                    } else {
+                        machine.pc + 3
+                    },
+                ),
+                Err(_) => (0, machine.pc),
+            };
+            // A breakpoint without a location is set on the line that is shown
+            let mut shown_line = None;
 
-                let mut deepest_in_file_depth = 999_999;
-                if stay_in_file {
-                    for (i, x) in origin_info.iter().enumerate() {
-                        if x.file == *file_name {
-                            deepest_in_file_depth = i;
+            if let Some(token) = tokens.get(pc) {
+                current_depth = current_depth.min(deepest(tokens, pc));
+                let origin_info = &token.origin_info;
+                let info = if origin_info.is_empty() {
+                    &token.info
+                } else {
+                    let file_name = &origin_info[0].file; // Suboptimal
+
+                    let mut deepest_in_file_depth = 999_999;
+                    if stay_in_file {
+                        for (i, x) in origin_info.iter().enumerate() {
+                            if x.file == *file_name {
+                                deepest_in_file_depth = i;
+                            }
                         }
                     }
-                }
 
-                current_depth = current_depth
-                    .min(origin_info.len() - 1)
-                    .min(deepest_in_file_depth);
+                    current_depth = current_depth
+                        .min(origin_info.len() - 1)
+                        .min(deepest_in_file_depth);
+
+                    if current_depth == origin_info.len() - 1 {
+                        &token.info
+                    } else {
+                        &origin_info[current_depth]
+                    }
+                };
 
-                if current_depth == origin_info.len() - 1 {
-                    &tokens[pc].info
+                shown_line = Some(Location::Line {
+                    file: info.file,
+                    line: info.line_number,
+                });
+                if cursor.is_some_and(|(file, _)| file != info.file) {
+                    cursor = None;
+                }
+                cursor = Some(cursor.unwrap_or((info.file, info.line_number)));
+                if !mem_mode {
+                    let cursor_line = cursor.map(|(_, line)| line);
+                    display(
+                        info,
+                        pc,
+                        new_pc,
+                        result,
+                        machine,
+                        &current_error,
+                        cursor_line,
+                    );
                 } else {
-                    &origin_info[current_depth]
+                    mem_view::draw_mem(&machine.mem, pc);
                 }
-            };
-
-            if !mem_mode {
-                display(info, pc, new_pc, result, mem, &current_error);
-            } else {
-                mem_view::draw_mem(mem, pc);
             }
             stdout
                 .execute(terminal::Clear(terminal::ClearType::FromCursorDown))
                 .unwrap();
 
+            if !breakpoints.list().is_empty() {
+                let list: Vec<String> = breakpoints
+                    .list()
+                    .iter()
+                    .enumerate()
+                    .map(|(i, breakpoint)| format!("{} {}", i + 1, breakpoint.location))
+                    .collect();
+                println!("Breakpoints: {}", list.join(", "));
+            }
+            if !watchpoints.list().is_empty() {
+                let list: Vec<String> = watchpoints
+                    .list()
+                    .iter()
+                    .enumerate()
+                    .map(|(i, watchpoint)| format!("{} {watchpoint}", i + 1))
+                    .collect();
+                println!("Watchpoints: {}", list.join(", "));
+            }
+            if !mem_mode {
+                // Values written by the last step are highlighted
+                for variable in variables.rows(&machine.mem, machine.pc, history.last(), symbols) {
+                    let row = format!("{variable: <100}");
+                    if variable.changed {
+                        println!("{}", row.bright_green());
+                    } else {
+                        println!("{row}");
+                    }
+                }
+            }
+            if !message.is_empty() {
+                println!("{}", message.yellow());
+                message.clear();
+            }
             println!("{io_buffer: <100}");
 
-            match input() {
+            let key = input();
+            // Going down past the deepest frame only shows the deepest one
+            current_depth = current_depth.min(deepest(tokens, machine.pc));
+            match key {
+                KeyCode::Char(':') => {
+                    let Some(text) = read_line(":", &mut input) else {
+                        continue;
+                    };
+                    message = match console::execute(
+                        &text,
+                        machine,
+                        (!replaying).then_some(&mut history),
+                        tokens,
+                        symbols,
+                        breakpoints,
+                        watchpoints,
+                    ) {
+                        Ok(output) | Err(output) => output,
+                    };
+                    continue;
+                }
+                KeyCode::Char('v') => {
+                    let Some(text) = read_line("Show the label: ", &mut input) else {
+                        continue;
+                    };
+                    if let Err(e) = variables.add(&text, symbols) {
+                        message = e;
+                    }
+                    continue;
+                }
+                KeyCode::Char('V') => {
+                    let Some(text) = read_line(
+                        "Hide variable (number or label, empty for all): ",
+                        &mut input,
+                    ) else {
+                        continue;
+                    };
+                    if text.trim().is_empty() {
+                        variables.clear();
+                    } else if let Err(e) = variables.remove(&text) {
+                        message = e;
+                    }
+                    continue;
+                }
+                KeyCode::Char('b') => {
+                    let Some(text) = read_line(
+                        "Break at (file:line, line or label, empty for this line): ",
+                        &mut input,
+                    ) else {
+                        continue;
+                    };
+                    let added = match (text.trim(), shown_line) {
+                        ("", Some(line)) => breakpoints.add_location(line, tokens, symbols),
+                        _ => breakpoints.add(&text, tokens, symbols),
+                    };
+                    message = match added {
+                        Ok(breakpoint) => format!(
+                            "Breakpoint at {}, {} address(es)",
+                            breakpoint.location,
+                            breakpoint.addresses.len()
+                        ),
+                        Err(e) => e,
+                    };
+                    continue;
+                }
+                KeyCode::Char('w') => {
+                    let Some(text) = read_line(
+                        "Watch (label or hex address, then optionally 'changed' or '= value'): ",
+                        &mut input,
+                    ) else {
+                        continue;
+                    };
+                    message = match watchpoints.add(&text, machine.mem.len(), symbols) {
+                        Ok(watchpoint) => format!(
+                            "Watching {watchpoint}, {} address(es)",
+                            watchpoint.addresses.len()
+                        ),
+                        Err(e) => e,
+                    };
+                    continue;
+                }
+                KeyCode::Char('W') => {
+                    let Some(text) = read_line(
+                        "Remove watchpoint (number or target, empty for all): ",
+                        &mut input,
+                    ) else {
+                        continue;
+                    };
+                    message = if text.trim().is_empty() {
+                        watchpoints.clear();
+                        "Removed all watchpoints".to_owned()
+                    } else {
+                        match watchpoints.remove(&text) {
+                            Ok(watchpoint) => format!("Stopped watching {watchpoint}"),
+                            Err(e) => e,
+                        }
+                    };
+                    continue;
+                }
+                KeyCode::Char('B') => {
+                    let Some(text) = read_line(
+                        "Remove breakpoint (number or location, empty for all): ",
+                        &mut input,
+                    ) else {
+                        continue;
+                    };
+                    message = if text.trim().is_empty() {
+                        breakpoints.clear();
+                        "Removed all breakpoints".to_owned()
+                    } else {
+                        match breakpoints.remove(&text) {
+                            Ok(breakpoint) => {
+                                format!("Removed the breakpoint at {}", breakpoint.location)
+                            }
+                            Err(e) => e,
+                        }
+                    };
+                    continue;
+                }
                 KeyCode::Char('m') => {
                     stdout
                         .execute(terminal::Clear(terminal::ClearType::All))
@@ -314,19 +690,76 @@ fn debug<T: FnMut() -> KeyCode>(
                     mem_mode = !mem_mode;
                     continue;
                 }
-                KeyCode::Delete => return,
+                KeyCode::Delete => return history,
                 KeyCode::Right => {}
                 KeyCode::Left | KeyCode::Char('h') => {
-                    current_error = None;
-                    let instr = instruction_history.pop();
-                    match instr {
-                        Some(x) => {
-                            pc = revert_historic_instruction(mem, &x, &mut io_buffer);
-                        }
-                        None => continue,
+                    step_back(machine, &mut history, &mut io_buffer);
+                    continue;
+                }
+                KeyCode::Char('n') => {
+                    let current = expansion(tokens, machine.pc, current_depth);
+                    run_until = Some(RunUntil::Leaving(current_depth, current));
+                    in_debugging_mode = false;
+                }
+                KeyCode::Char('N') => {
+                    if step_back(machine, &mut history, &mut io_buffer) {
+                        back_to_start(machine, &mut history, &mut io_buffer, tokens, current_depth);
                     }
                     continue;
                 }
+                KeyCode::Char('f') | KeyCode::Char('F') if current_depth == 0 => {
+                    message = "There is no macro to step out of, go down first".to_owned();
+                    continue;
+                }
+                KeyCode::Char('f') => {
+                    current_depth -= 1;
+                    let current = expansion(tokens, machine.pc, current_depth);
+                    run_until = Some(RunUntil::Leaving(current_depth, current));
+                    in_debugging_mode = false;
+                }
+                KeyCode::Char('F') => {
+                    current_depth -= 1;
+                    back_to_start(machine, &mut history, &mut io_buffer, tokens, current_depth);
+                    continue;
+                }
+                KeyCode::Char('J') | KeyCode::Char('K') => {
+                    let lines = cursor.map_or(0, |(file, _)| {
+                        lexer::FILES.with_borrow(|files| {
+                            files
+                                .get(file)
+                                .map_or(0, |file| file.contents.lines().count())
+                        })
+                    });
+                    if let Some((_, line)) = &mut cursor {
+                        *line = match key {
+                            KeyCode::Char('J') => (*line + 1).min(lines as i32),
+                            _ => (*line - 1).max(1),
+                        };
+                    }
+                    continue;
+                }
+                KeyCode::Char('c') | KeyCode::Char('C') => {
+                    let Some((file, line)) = cursor else {
+                        continue;
+                    };
+                    let addresses = match (Location::Line { file, line }).resolve(tokens, symbols) {
+                        Ok(addresses) => addresses.into_iter().collect::<HashSet<usize>>(),
+                        Err(e) => {
+                            message = e;
+                            continue;
+                        }
+                    };
+                    if key == KeyCode::Char('c') {
+                        run_until = Some(RunUntil::Reaching(addresses));
+                        in_debugging_mode = false;
+                    } else {
+                        // Back to where the line was last entered
+                        while step_back(machine, &mut history, &mut io_buffer)
+                            && !addresses.contains(&machine.pc)
+                        {}
+                        continue;
+                    }
+                }
                 KeyCode::Down | KeyCode::Char('j') => {
                     current_depth += 1;
                     continue;
@@ -341,28 +774,33 @@ fn debug<T: FnMut() -> KeyCode>(
                 _ => {}
             }
         }
-        if io == IOOperation::Halt {
-            break;
-        }
-        // Actually apply the instruction
-        if current_error.is_none() || matches!(current_error, Some(RuntimeError::Breakpoint(..))) {
-            instruction_history.push(InstructionHistoryItem {
-                pc,
-                original_value_at_b,
-                io_operation: io,
-            });
-            pc = new_pc;
-            if b < mem.len() {
-                mem[b] = result;
+
+        // Actually run the instruction
+        match advance(machine) {
+            StepOutcome::Ran(step) | StepOutcome::Breakpoint(step) => {
+                if let Some(watchpoint) = watchpoints.hit(&step, &machine.mem) {
+                    in_debugging_mode = true;
+                    message = watchpoint_message(watchpoint, &step, &machine.mem, tokens);
+                }
+                push_output(&mut io_buffer, step.io);
+                history.push(step);
             }
+            StepOutcome::Halted => break,
+            StepOutcome::Paused => in_debugging_mode = true,
+            // Shown by the preview, the instruction can't be run
+            StepOutcome::Error(_) => {}
         }
-        current_error = None;
     }
+    history
 }
 
 #[cfg(test)]
 mod tests {
 
+    use crate::assembler::assemble_for_test;
+    use crate::runtimes::interpreter;
+    use crate::runtimes::io::Buffered;
+    use crate::runtimes::trace::TraceWriter;
     use crate::tokens::{TokenVariant, tokens_from_token_variant_vec};
 
     use super::*;
@@ -406,7 +844,7 @@ mod tests {
 
             move || iter.next().unwrap()
         }
-        let mut mem: Vec<u16> = vec![14, 12, 3, 14, 13, 6, 13, 14, 9, 12, 12, 0, 0, 1, 0];
+        let mem: Vec<u16> = vec![14, 12, 3, 14, 13, 6, 13, 14, 9, 12, 12, 0, 0, 1, 0];
         let expected: Vec<u16> = vec![14, 12, 3, 14, 13, 6, 13, 14, 9, 12, 12, 0, 0, 1, 0xFFFF];
         let tokens = &tokens_from_token_variant_vec(vec![
             (0, TokenVariant::DecLiteral { value: 14 }),
@@ -426,7 +864,194 @@ mod tests {
             (0, TokenVariant::DecLiteral { value: 0 }),
         ]);
 
-        debug(&mut mem, tokens, true, simulate_input());
-        assert_eq!(mem, expected);
+        let mut machine = Machine::new(mem, Buffered::default());
+        debug(
+            &mut machine,
+            tokens,
+            &[],
+            true,
+            false,
+            &mut Breakpoints::default(),
+            &mut Watchpoints::default(),
+            simulate_input(),
+            Machine::step,
+        );
+        assert_eq!(machine.mem, expected);
+    }
+
+    #[test]
+    fn breakpoints() {
+        let text = "@Clear a? {\n    a? -= a?\n}\n.start ->\n    !Clear A\n    !Clear B\n    \
+                    Z -= Z -1\nA -> 1\nB -> 2\nZ -> 0\n";
+        let (mem, tokens, symbols) = assemble_for_test(text);
+        let mut breakpoints = Breakpoints::default();
+        breakpoints.add("Test.sbl:6", &tokens, &symbols).unwrap();
+
+        // Stops at the call on line 6, adds a breakpoint on line 7 and continues to it
+        let mut keys = [
+            KeyCode::Char('b'),
+            KeyCode::Char('7'),
+            KeyCode::Enter,
+            KeyCode::Esc,
+            KeyCode::Delete,
+        ]
+        .into_iter();
+        let mut machine = Machine::new(mem, Buffered::default());
+        debug(
+            &mut machine,
+            &tokens,
+            &symbols,
+            false,
+            false,
+            &mut breakpoints,
+            &mut Watchpoints::default(),
+            || keys.next().unwrap(),
+            Machine::step,
+        );
+        assert_eq!((machine.pc, machine.instructions_executed), (6, 2));
+        assert_eq!(breakpoints.list().len(), 2);
+    }
+
+    #[test]
+    fn watchpoints() {
+        let text = "!Clear A\n!Clear B\nZ -= Z -1\nA -> 0\nB -> 2\nZ -> 0\n\
+                    @Clear a? {\n    a? -= a?\n}\n";
+        let (mem, tokens, symbols) = assemble_for_test(text);
+        let mut watchpoints = Watchpoints::default();
+        // Clearing A writes it without changing it
+        watchpoints.add("A changed", mem.len(), &symbols).unwrap();
+        watchpoints.add("B changed", mem.len(), &symbols).unwrap();
+
+        let mut machine = Machine::new(mem, Buffered::default());
+        debug(
+            &mut machine,
+            &tokens,
+            &symbols,
+            false,
+            false,
+            &mut Breakpoints::default(),
+            &mut watchpoints,
+            || KeyCode::Delete,
+            Machine::step,
+        );
+        // Paused after the instruction that cleared B
+        assert_eq!((machine.pc, machine.instructions_executed), (6, 2));
+    }
+
+    #[test]
+    fn output_after_exit() {
+        let text = "A -= -1\n-1 -= A\n-1 -= B\nZ -= Z -2\nZ -= Z -1\nA -> 0\nB -> 'b'\nZ -> 0\n";
+        let (mem, tokens, symbols) = assemble_for_test(text);
+        // Runs to the breakpoint, then steps back over printing 'b'
+        let mut keys = [KeyCode::Left, KeyCode::Delete].into_iter();
+        let mut machine = Machine::new(mem, Buffered::new("a"));
+        run_with_keys(
+            &mut machine,
+            &tokens,
+            &symbols,
+            &mut Breakpoints::default(),
+            &mut Watchpoints::default(),
+            || keys.next().unwrap(),
+        );
+        assert_eq!(machine.pc, 6);
+        assert_eq!(machine.io.output, "a");
+    }
+
+    #[test]
+    fn step_over_and_out() {
+        let text = "@Clear a? {\n    a? -= a?\n    a? -= a?\n}\n!Clear A\n!Clear B\nZ -= Z -1\n\
+                    A -> 1\nB -> 2\nZ -> 0\n";
+        let (mem, tokens, symbols) = assemble_for_test(text);
+        // Where the machine is after the keys, with the Delete that ends debugging
+        let run = |keys: &[KeyCode]| {
+            let mut keys = keys.iter().copied().chain([KeyCode::Delete]);
+            let mut machine = Machine::new(mem.clone(), Buffered::default());
+            debug(
+                &mut machine,
+                &tokens,
+                &symbols,
+                true,
+                false,
+                &mut Breakpoints::default(),
+                &mut Watchpoints::default(),
+                || keys.next().unwrap(),
+                Machine::step,
+            );
+            (machine.pc, machine.instructions_executed)
+        };
+        let key = KeyCode::Char;
+
+        // Over the call on line 5, and back to its start
+        assert_eq!(run(&[key('n')]), (6, 2));
+        assert_eq!(run(&[key('n'), key('n'), key('N')]), (6, 2));
+        // Over the first line of its body, then out of it, or back to the call
+        assert_eq!(run(&[key('j'), key('n')]), (3, 1));
+        assert_eq!(run(&[key('j'), key('n'), key('f')]), (6, 2));
+        assert_eq!(run(&[key('j'), key('n'), key('F')]), (0, 0));
+        // Nothing to step out of at the top
+        assert_eq!(run(&[key('f')]), (0, 0));
+        // To the halt on line 7, and back to where line 6 was entered
+        assert_eq!(run(&[key('J'), key('J'), key('c')]), (12, 4));
+        assert_eq!(
+            run(&[key('J'), key('J'), key('c'), key('K'), key('C')]),
+            (6, 2)
+        );
+        // Going down on a line outside of a macro leaves nothing to step out of
+        assert_eq!(
+            run(&[key('J'), key('J'), key('c'), key('j'), key('f')]),
+            (12, 4)
+        );
+    }
+
+    #[test]
+    fn replay_trace() {
+        // Reads a char into 9, prints it and halts
+        let mem: Vec<u16> = vec![0xFFFF, 9, 3, 9, 0xFFFF, 6, 10, 10, 0xFFFF, 0, 0];
+        let tokens = &tokens_from_token_variant_vec(
+            mem.iter()
+                .map(|word| {
+                    (
+                        0,
+                        TokenVariant::DecLiteral {
+                            value: *word as i32,
+                        },
+                    )
+                })
+                .collect(),
+        );
+
+        let mut bytes = Vec::new();
+        let mut writer = TraceWriter::new(&mut bytes, &mem, mem.len());
+        let mut machine = Machine::new(mem.clone(), Buffered::new("q"));
+        interpreter::interpret_traced(&mut machine, &mut writer, None).unwrap();
+        writer.finish().unwrap();
+        assert_eq!(machine.io.output, "q");
+
+        let trace = Trace::read(bytes.as_slice(), &mem).unwrap();
+        assert_eq!(trace.entries.len(), 3);
+        let mut keys = [
+            KeyCode::Right,
+            KeyCode::Right,
+            KeyCode::Right,
+            KeyCode::Left,
+            KeyCode::Right,
+            KeyCode::Delete,
+        ]
+        .into_iter();
+        let mut machine = Machine::new(mem, Buffered::default());
+        debug(
+            &mut machine,
+            tokens,
+            &[],
+            true,
+            true,
+            &mut Breakpoints::default(),
+            &mut Watchpoints::default(),
+            || keys.next().unwrap(),
+            |machine| apply_next(machine, &trace),
+        );
+        // Stopped before the halting instruction, without reading or writing anything
+        assert_eq!((machine.pc, machine.mem[9]), (6, 'q' as u16));
+        assert_eq!(machine.io.output, "");
     }
 }
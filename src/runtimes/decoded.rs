@@ -0,0 +1,175 @@
+//! A faster way to run the machine. Instructions are decoded once and kept until the words
+//! they were decoded from are written to, which subleq programs do all the time. Only plain
+//! subtractions and clears run from the cache, everything else is executed by Machine::step(),
+//! so the results are exactly those of the reference interpreter
+use crate::runtimes::devices::Devices;
+use crate::runtimes::io::Io;
+use crate::runtimes::machine::{Machine, StepOutcome};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    /// Not decoded yet, or written to since
+    Undecoded,
+    /// IO, a device, a jump to -1 or -2 or operands outside of memory
+    Reference,
+    /// b -= a, the operands are in memory
+    Subtract { a: u16, b: u16, c: u16 },
+    /// b -= b, which always jumps
+    Clear { b: u16, c: u16 },
+}
+
+/// The decoded instructions of a machine's memory
+pub struct DecodedCache {
+    ops: Vec<Op>,
+    /// Words an instruction has been decoded from. Writes to other words don't invalidate
+    is_code: Vec<bool>,
+}
+
+impl DecodedCache {
+    pub fn new(memory_size: usize) -> Self {
+        DecodedCache {
+            ops: vec![Op::Undecoded; memory_size],
+            is_code: vec![false; memory_size],
+        }
+    }
+
+    fn decode(&mut self, mem: &[u16], devices: &Devices, pc: usize) -> Op {
+        let len = mem.len();
+        let op = if pc + 2 >= len {
+            Op::Reference
+        } else {
+            let (a, b, c) = (mem[pc], mem[pc + 1], mem[pc + 2]);
+            // Jumps out of memory are left to the reference, it halts, breaks or fails
+            let is_memory =
+                |address: u16| (address as usize) < len && !devices.claims(address as usize);
+            if !is_memory(a) || !is_memory(b) || c as usize + 2 >= len {
+                Op::Reference
+            } else if a == b {
+                Op::Clear { b, c }
+            } else {
+                Op::Subtract { a, b, c }
+            }
+        };
+        if op != Op::Reference {
+            self.is_code[pc..pc + 3].fill(true);
+        }
+        self.ops[pc] = op;
+        op
+    }
+
+    /// Like Machine::run(), with the same outcome and machine state
+    pub fn run<I: Io>(&mut self, machine: &mut Machine<I>, limit: Option<u128>) -> StepOutcome {
+        // Memory doesn't grow while running, but a cache may be reused
+        if self.ops.len() != machine.mem.len() {
+            *self = DecodedCache::new(machine.mem.len());
+        }
+        let mut remaining = limit.map_or(u64::MAX, |limit| limit.min(u64::MAX as u128) as u64);
+        loop {
+            remaining = self.run_decoded(machine, remaining);
+            if remaining == 0 {
+                return StepOutcome::Paused;
+            }
+            let pc = machine.pc;
+            if self.ops.get(pc) == Some(&Op::Undecoded)
+                && self.decode(&machine.mem, &machine.devices, pc) != Op::Reference
+            {
+                continue;
+            }
+
+            // Left to the reference
+            remaining -= 1;
+            let outcome = machine.step();
+            if let StepOutcome::Ran(step) | StepOutcome::Breakpoint(step) = &outcome
+                && step.b < self.is_code.len()
+            {
+                self.written(step.b);
+            }
+            if !matches!(outcome, StepOutcome::Ran(..)) {
+                return outcome;
+            }
+        }
+    }
+
+    /// Runs decoded instructions until one isn't, returning how many more may run. The state
+    /// is kept in locals, so it stays in registers
+    #[inline(always)]
+    fn run_decoded<I: Io>(&mut self, machine: &mut Machine<I>, mut remaining: u64) -> u64 {
+        let mem = &mut machine.mem[..];
+        let (mut pc, mut prev_pc) = (machine.pc, machine.prev_pc);
+        let start = remaining;
+        while remaining > 0 {
+            let (b, result, c) = match self.ops.get(pc) {
+                Some(Op::Subtract { a, b, c }) => {
+                    let b = *b as usize;
+                    let result = mem[b].wrapping_sub(mem[*a as usize]);
+                    (b, result, *c)
+                }
+                Some(Op::Clear { b, c }) => (*b as usize, 0, *c),
+                _ => break,
+            };
+            mem[b] = result;
+            if self.is_code[b] {
+                Self::invalidate(&mut self.ops, b);
+            }
+            prev_pc = pc;
+            pc = if result as i16 <= 0 {
+                c as usize
+            } else {
+                pc + 3
+            };
+            remaining -= 1;
+        }
+        machine.pc = pc;
+        machine.prev_pc = prev_pc;
+        machine.instructions_executed += (start - remaining) as u128;
+        remaining
+    }
+
+    /// Forgets the instructions that overlap the written word
+    fn invalidate(ops: &mut [Op], address: usize) {
+        ops[address.saturating_sub(2)..=address].fill(Op::Undecoded);
+    }
+
+    fn written(&mut self, address: usize) {
+        if self.is_code[address] {
+            Self::invalidate(&mut self.ops, address);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtimes::io::Buffered;
+
+    #[test]
+    fn self_modifying_code() {
+        let mem = vec![
+            15, 15, 6, // Jump to 6
+            16, 8, 6, // Add 6 to the jump at 6, so it jumps to 9 instead of 3
+            15, 15, 3, // Jump to 3, then to 9
+            17, 0xFFFF, 12, // Print 'A'
+            15, 15, 0xFFFF, // Halt
+            0, 0xFFFA, 'A' as u16,
+        ];
+        let mut reference = Machine::new(mem.clone(), Buffered::default());
+        let mut machine = Machine::new(mem, Buffered::default());
+
+        // Jumping with the stale decoded instruction would loop forever
+        assert_eq!(reference.run(Some(100)), StepOutcome::Halted);
+        assert_eq!(
+            DecodedCache::new(machine.mem.len()).run(&mut machine, Some(100)),
+            StepOutcome::Halted
+        );
+        assert_eq!(machine.io.output, "A");
+        assert_eq!(machine.mem, reference.mem);
+        assert_eq!(
+            (machine.pc, machine.prev_pc, machine.instructions_executed),
+            (
+                reference.pc,
+                reference.prev_pc,
+                reference.instructions_executed
+            )
+        );
+    }
+}
@@ -0,0 +1,383 @@
+//! Devices mapped to the words below the debug address. Reading a device stores its value
+//! at b, like reading input, and writing to one gives it the value at a, like writing output.
+//! Devices are only attached when asked for, otherwise their addresses are out of range, or
+//! memory when the machine uses full memory
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::runtimes::io::{EndOfInput, Io};
+use crate::symbols::{
+    CYCLES_ADDR, DEBUG_ADDR, DEVICES_ADDR, HALT_ADDR, INT_INPUT_ADDR, RANDOM_ADDR,
+};
+
+/// What a device can use of the machine it is attached to
+pub struct Context<'a> {
+    pub instructions_executed: u128,
+    pub io: &'a mut dyn Io,
+}
+
+/// What writing to a device does to the program
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Written {
+    Continue,
+    /// The program halts with the exit code
+    Exit(i16),
+}
+
+pub trait Device {
+    /// Shown in errors
+    fn name(&self) -> &'static str;
+    /// The first address the device claims
+    fn address(&self) -> usize;
+    /// The number of words it claims
+    fn size(&self) -> usize {
+        1
+    }
+    /// The value of the word at address + offset. None halts the program, like running out
+    /// of input does
+    fn read(&mut self, offset: usize, context: &mut Context) -> Option<u16>;
+    fn write(&mut self, offset: usize, value: u16, context: &mut Context) -> Written;
+}
+
+/// The devices a machine has attached, see Machine::devices
+#[derive(Default)]
+pub struct Devices {
+    devices: Vec<Box<dyn Device>>,
+    /// The device at every address from DEVICES_ADDR up to the debug address
+    map: [Option<usize>; DEBUG_ADDR - DEVICES_ADDR],
+}
+
+impl Devices {
+    /// The devices of the kinds, with the random device seeded with seed
+    pub fn from_kinds(kinds: &[DeviceKind], seed: u64) -> Result<Self, String> {
+        let mut devices = Devices::default();
+        for kind in kinds {
+            let device: Box<dyn Device> = match kind {
+                DeviceKind::Cycles => Box::new(CycleCounter::default()),
+                DeviceKind::Random => Box::new(Random { state: seed }),
+                DeviceKind::IntInput => Box::new(IntInput),
+                DeviceKind::Halt => Box::new(Halt),
+            };
+            devices.attach(device)?;
+        }
+        Ok(devices)
+    }
+
+    /// Attaches the device at its address, which has to be free
+    pub fn attach(&mut self, device: Box<dyn Device>) -> Result<(), String> {
+        let (start, end) = (device.address(), device.address() + device.size());
+        if start < DEVICES_ADDR || end > DEBUG_ADDR {
+            return Err(format!(
+                "The {} device at 0x{start:04X}..0x{end:04X} is outside of the device addresses 0x{DEVICES_ADDR:04X}..0x{DEBUG_ADDR:04X}",
+                device.name()
+            ));
+        }
+        let words = start - DEVICES_ADDR..end - DEVICES_ADDR;
+        if let Some(other) = self.map[words.clone()].iter().flatten().next() {
+            return Err(format!(
+                "The {} device overlaps the {} device",
+                device.name(),
+                self.devices[*other].name()
+            ));
+        }
+        self.map[words].fill(Some(self.devices.len()));
+        self.devices.push(device);
+        Ok(())
+    }
+
+    fn index(&self, address: usize) -> Option<usize> {
+        self.map
+            .get(address.wrapping_sub(DEVICES_ADDR))
+            .copied()
+            .flatten()
+    }
+
+    /// Whether a device is attached at the address
+    pub fn claims(&self, address: usize) -> bool {
+        self.index(address).is_some()
+    }
+
+    /// Reads the device at the address, which has to be claimed
+    pub fn read(&mut self, address: usize, context: &mut Context) -> Option<u16> {
+        let index = self.index(address).expect("No device at the address");
+        let device = &mut self.devices[index];
+        let offset = address - device.address();
+        device.read(offset, context)
+    }
+
+    /// Writes to the device at the address, which has to be claimed
+    pub fn write(&mut self, address: usize, value: u16, context: &mut Context) -> Written {
+        let index = self.index(address).expect("No device at the address");
+        let device = &mut self.devices[index];
+        let offset = address - device.address();
+        device.write(offset, value, context)
+    }
+}
+
+/// The devices that can be attached with --devices
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DeviceKind {
+    Cycles,
+    Random,
+    IntInput,
+    Halt,
+}
+
+impl FromStr for DeviceKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "cycles" => Ok(DeviceKind::Cycles),
+            "random" => Ok(DeviceKind::Random),
+            "int-input" => Ok(DeviceKind::IntInput),
+            "halt" => Ok(DeviceKind::Halt),
+            _ => Err(format!(
+                "expected 'cycles', 'random', 'int-input' or 'halt', found '{s}'"
+            )),
+        }
+    }
+}
+
+/// The status the process exits with for an exit code of the halt device. A process only
+/// keeps the low 8 bits of its status, so codes outside of 0..=255 become 255 instead of
+/// wrapping around, which could turn a failure like 256 into a success
+pub fn exit_status(code: i16) -> i32 {
+    match code {
+        0..=255 => code as i32,
+        _ => 255,
+    }
+}
+
+/// A seed that differs between runs, for when none is given
+pub fn clock_seed() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_nanos() as u64)
+}
+
+/// The instructions executed since the counter was last reset, in a low and a high word.
+/// Writing any value resets it
+#[derive(Debug, Default)]
+struct CycleCounter {
+    start: u128,
+}
+
+impl Device for CycleCounter {
+    fn name(&self) -> &'static str {
+        "cycles"
+    }
+    fn address(&self) -> usize {
+        CYCLES_ADDR
+    }
+    fn size(&self) -> usize {
+        2
+    }
+    fn read(&mut self, offset: usize, context: &mut Context) -> Option<u16> {
+        // Stepping back in the debugger can undo instructions executed since the reset
+        let count = context.instructions_executed.saturating_sub(self.start);
+        Some((count >> (16 * offset)) as u16)
+    }
+    fn write(&mut self, _: usize, _: u16, context: &mut Context) -> Written {
+        self.start = context.instructions_executed;
+        Written::Continue
+    }
+}
+
+/// Pseudo-random words from SplitMix64. Writing a value seeds it with that value
+#[derive(Debug)]
+struct Random {
+    state: u64,
+}
+
+impl Device for Random {
+    fn name(&self) -> &'static str {
+        "random"
+    }
+    fn address(&self) -> usize {
+        RANDOM_ADDR
+    }
+    fn read(&mut self, _: usize, _: &mut Context) -> Option<u16> {
+        self.state = self.state.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        Some(((z ^ (z >> 31)) >> 48) as u16)
+    }
+    fn write(&mut self, _: usize, value: u16, _: &mut Context) -> Written {
+        self.state = value as u64;
+        Written::Continue
+    }
+}
+
+/// Reads a decimal number from the program's input. Everything before it is skipped and
+/// the char after it is consumed. Numbers wrap around, like all arithmetic
+#[derive(Debug)]
+struct IntInput;
+
+impl Device for IntInput {
+    fn name(&self) -> &'static str {
+        "int-input"
+    }
+    fn address(&self) -> usize {
+        INT_INPUT_ADDR
+    }
+    fn read(&mut self, _: usize, context: &mut Context) -> Option<u16> {
+        context.io.flush();
+        let (mut value, mut negative): (Option<u16>, bool) = (None, false);
+        loop {
+            match (context.io.read_char(), value) {
+                (Some(ch @ '0'..='9'), _) => {
+                    let digit = ch as u16 - '0' as u16;
+                    value = Some(value.unwrap_or(0).wrapping_mul(10).wrapping_add(digit));
+                }
+                (Some(ch), None) => negative = ch == '-',
+                (Some(_), Some(_)) | (None, Some(_)) => break,
+                (None, None) => {
+                    return match context.io.end_of_input() {
+                        EndOfInput::Halt => None,
+                        EndOfInput::Value(value) => Some(value),
+                    };
+                }
+            }
+        }
+        let value = value.unwrap_or(0);
+        Some(if negative {
+            value.wrapping_neg()
+        } else {
+            value
+        })
+    }
+    fn write(&mut self, _: usize, _: u16, _: &mut Context) -> Written {
+        Written::Continue
+    }
+}
+
+/// Writing a value halts the program with it as the exit code
+#[derive(Debug)]
+struct Halt;
+
+impl Device for Halt {
+    fn name(&self) -> &'static str {
+        "halt"
+    }
+    fn address(&self) -> usize {
+        HALT_ADDR
+    }
+    fn read(&mut self, _: usize, _: &mut Context) -> Option<u16> {
+        Some(0)
+    }
+    fn write(&mut self, _: usize, value: u16, _: &mut Context) -> Written {
+        Written::Exit(value as i16)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble_for_test;
+    use crate::runtimes::{RuntimeError, interpreter, io::Buffered, machine::Machine};
+
+    #[test]
+    fn attach_and_access() {
+        let mut devices =
+            Devices::from_kinds(&[DeviceKind::Cycles, DeviceKind::IntInput], 0).unwrap();
+        assert!(devices.claims(CYCLES_ADDR + 1));
+        assert!(!devices.claims(RANDOM_ADDR));
+        assert!(devices.attach(Box::new(IntInput)).is_err());
+
+        let mut io = Buffered::new("x-12 34");
+        let mut context = Context {
+            instructions_executed: 0x1_0005,
+            io: &mut io,
+        };
+        assert_eq!(devices.read(CYCLES_ADDR, &mut context), Some(5));
+        assert_eq!(devices.read(CYCLES_ADDR + 1, &mut context), Some(1));
+        devices.write(CYCLES_ADDR, 0, &mut context);
+        assert_eq!(devices.read(CYCLES_ADDR, &mut context), Some(0));
+        // Stepping back past the reset
+        context.instructions_executed = 3;
+        assert_eq!(devices.read(CYCLES_ADDR, &mut context), Some(0));
+
+        assert_eq!(
+            devices.read(INT_INPUT_ADDR, &mut context),
+            Some(-12i16 as u16)
+        );
+        assert_eq!(devices.read(INT_INPUT_ADDR, &mut context), Some(34));
+        assert_eq!(devices.read(INT_INPUT_ADDR, &mut context), None);
+    }
+
+    #[test]
+    fn random_is_seeded() {
+        let mut io = Buffered::default();
+        let mut context = Context {
+            instructions_executed: 0,
+            io: &mut io,
+        };
+        let mut random = Random { state: 0 };
+        let first: Vec<Option<u16>> = (0..4).map(|_| random.read(0, &mut context)).collect();
+        assert_ne!(first[0], first[1]);
+        random.write(0, 0, &mut context);
+        let again: Vec<Option<u16>> = (0..4).map(|_| random.read(0, &mut context)).collect();
+        assert_eq!(first, again);
+    }
+
+    #[test]
+    fn asm_macros() {
+        // Exits with the sum of two numbers from the input
+        let text = "#ASM\n!ASM::Devices::ReadInt A\n!ASM::Devices::ReadInt B\nZ -= A\nZ -= B\n\
+                    !ASM::Devices::Exit Z\nA -> 0\nB -> 0\nZ -> 0\n_ASM -> 0\n";
+        let (mem, _, _) = assemble_for_test(text);
+
+        let mut machine = Machine::new(mem.clone(), Buffered::new("20 -62\n"));
+        assert!(matches!(
+            interpreter::interpret(&mut machine),
+            Err(RuntimeError::AOutOfRange(0))
+        ));
+
+        let mut machine = Machine::new(mem, Buffered::new("20 -62\n"));
+        machine.devices =
+            Devices::from_kinds(&[DeviceKind::IntInput, DeviceKind::Halt], 0).unwrap();
+        interpreter::interpret(&mut machine).unwrap();
+        assert_eq!(machine.exit_code, Some(42));
+    }
+
+    #[test]
+    fn exit_statuses() {
+        assert_eq!(exit_status(0), 0);
+        assert_eq!(exit_status(255), 255);
+        assert_eq!(exit_status(256), 255);
+        assert_eq!(exit_status(-1), 255);
+    }
+
+    #[test]
+    fn full_memory() {
+        // Writes 5 to 0xFFF0 and exits with it. Without the halt device it halts on a jump to -1
+        let mem = vec![
+            9,
+            0xFFF0,
+            3,
+            0xFFF0,
+            HALT_ADDR as u16,
+            6,
+            10,
+            10,
+            0xFFFF,
+            0xFFFB,
+            0,
+        ];
+        let mut machine = Machine::new(mem.clone(), Buffered::default());
+        machine.use_full_memory();
+        interpreter::interpret(&mut machine).unwrap();
+        assert_eq!(machine.mem[HALT_ADDR], 0xFFFB);
+        assert_eq!(machine.exit_code, None);
+
+        // Only the words of attached devices aren't memory
+        let mut machine = Machine::new(mem, Buffered::default());
+        machine.use_full_memory();
+        machine.devices = Devices::from_kinds(&[DeviceKind::Halt], 0).unwrap();
+        interpreter::interpret(&mut machine).unwrap();
+        assert_eq!(machine.mem[0xFFF0], 5);
+        assert_eq!(machine.exit_code, Some(5));
+    }
+}
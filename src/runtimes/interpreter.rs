@@ -1,92 +1,179 @@
-use crate::runtimes::RuntimeError;
-use crate::symbols::{DEBUG_ADDR, IO_ADDR};
-use crossterm::event::KeyCode;
-use std::{
-    num::Wrapping,
-    time::{Duration, Instant},
-};
-
-use crate::runtimes::get_key;
-
-/// Returns the a result with either the complete program output, or a runtime error, as well as
-/// the total executions executed, and the time spent on IO. Breakpoints are considered fatal
-pub fn interpret(mem: &mut [u16]) -> (Result<String, RuntimeError>, u128, Duration) {
-    let mut prev_pc: usize = 0xFFFF;
-    let mut pc = 0;
-    let mut total_ran: u128 = 0;
-    let mut io_buffer = String::new();
-    let mut io_time: Duration = Duration::new(0, 0);
-    loop {
-        if pc + 2 >= mem.len() {
-            return (Err(RuntimeError::COutOfRange(prev_pc)), total_ran, io_time);
-        }
-        let a = mem[pc] as usize;
-        let b = mem[pc + 1] as usize;
-        let c = mem[pc + 2] as usize;
+use std::io::Write;
+use std::time::Instant;
 
-        let mut result: u16 = 0;
+use crate::profiler::Profile;
+use crate::runtimes::decoded::DecodedCache;
+use crate::runtimes::io::Io;
+use crate::runtimes::machine::{Machine, StepOutcome};
+use crate::runtimes::trace::{TraceEntry, TraceWriter};
+use crate::runtimes::{Limit, RuntimeError};
 
-        match (a, b) {
-            (a, _) if a >= mem.len() && a != IO_ADDR => {
-                return (Err(RuntimeError::AOutOfRange(pc)), total_ran, io_time);
-            }
-            (_, b) if b >= mem.len() && b != IO_ADDR && b != DEBUG_ADDR => {
-                return (Err(RuntimeError::BOutOfRange(pc)), total_ran, io_time);
-            }
+/// How many instructions run between checks of the timeout
+const TIMEOUT_CHECK_INTERVAL: u128 = 1 << 16;
 
-            // Output char
-            (_, IO_ADDR) => {
-                let ch = mem[a] as u8 as char;
-                result = -(mem[a] as i16) as u16;
-                io_buffer.push(ch);
+/// Runs the program until it halts, which is Ok, or until a runtime error. The instructions
+/// executed and the time spent on IO are counted by the machine. Breakpoints are considered fatal.
+/// The machine's max_instructions and timeout stop the program with RuntimeError::LimitExceeded,
+/// though the timeout can't interrupt waiting for input
+pub fn interpret<I: Io>(machine: &mut Machine<I>) -> Result<(), RuntimeError> {
+    let outcome = run_within_limits(machine, |machine, chunk| machine.run(chunk));
+    finish(machine, outcome)
+}
 
-                let timer = Instant::now();
-                print!("{ch}");
-                io_time += timer.elapsed();
-            }
-            // Output int
-            (_, DEBUG_ADDR) => {
-                let ch = (mem[a] as i16).to_string();
-                result = -(mem[a] as i16) as u16;
+/// Like interpret(), but instructions are decoded once and run from a cache, see decoded.
+/// The results are the same, it is only faster
+pub fn interpret_fast<I: Io>(machine: &mut Machine<I>) -> Result<(), RuntimeError> {
+    let mut cache = DecodedCache::new(machine.mem.len());
+    let outcome = run_within_limits(machine, |machine, chunk| cache.run(machine, chunk));
+    finish(machine, outcome)
+}
 
-                io_buffer.push_str(&ch);
+/// Like interpret(), counting how often every address is executed
+pub fn interpret_profiled<I: Io>(
+    machine: &mut Machine<I>,
+    profile: &mut Profile,
+) -> Result<(), RuntimeError> {
+    let outcome = run_within_limits(machine, |machine, chunk| {
+        machine.run_observed(chunk, |pc| profile.count(pc))
+    });
+    finish(machine, outcome)
+}
 
-                let timer = Instant::now();
-                println!("{ch}");
-                io_time += timer.elapsed();
+/// Like interpret(), recording every executed instruction and, if given, profiling. This is
+/// a lot slower, the machine runs one instruction at a time
+pub fn interpret_traced<I: Io, W: Write>(
+    machine: &mut Machine<I>,
+    trace: &mut TraceWriter<W>,
+    mut profile: Option<&mut Profile>,
+) -> Result<(), RuntimeError> {
+    let outcome = run_within_limits(machine, |machine, chunk| {
+        let mut remaining = chunk.unwrap_or(u128::MAX);
+        loop {
+            if remaining == 0 {
+                return StepOutcome::Paused;
             }
-            // Input char
-            (IO_ADDR, _) => {
-                let timer = Instant::now();
-
-                let c = match get_key() {
-                    KeyCode::Char(x) => x,
-                    KeyCode::Delete => break,
-                    _ => '\0',
-                };
-                io_time += timer.elapsed();
-                mem[b] = c as u16;
+            remaining -= 1;
+            if let Some(profile) = &mut profile {
+                profile.count(machine.pc);
             }
+            let Ok(preview) = machine.preview() else {
+                return machine.step();
+            };
+            let (pc, executed) = (machine.pc, machine.instructions_executed);
+            let old_value = machine.mem.get(preview.b).copied().unwrap_or(0);
+            let outcome = machine.step();
+            // Running out of input halts without executing the instruction
+            if machine.instructions_executed > executed {
+                trace.record(TraceEntry::new(pc, &preview, old_value, &machine.mem));
+            }
+            match outcome {
+                StepOutcome::Ran(..) => {}
+                outcome => return outcome,
+            }
+        }
+    });
+    finish(machine, outcome)
+}
 
-            (_, _) => {
-                result = (Wrapping(mem[b]) - (Wrapping(mem[a]))).0;
-                mem[b] = result;
+fn finish<I: Io>(
+    machine: &mut Machine<I>,
+    outcome: Result<StepOutcome, RuntimeError>,
+) -> Result<(), RuntimeError> {
+    machine.flush();
+    match outcome? {
+        StepOutcome::Halted => Ok(()),
+        StepOutcome::Breakpoint(step) => Err(RuntimeError::Breakpoint(step.pc)),
+        StepOutcome::Error(e) => Err(e),
+        StepOutcome::Ran(..) | StepOutcome::Paused => {
+            unreachable!("The machine only stops within the limits when the program does")
+        }
+    }
+}
+
+/// Runs the machine in chunks with run, checking the limits in between
+fn run_within_limits<I: Io>(
+    machine: &mut Machine<I>,
+    mut run: impl FnMut(&mut Machine<I>, Option<u128>) -> StepOutcome,
+) -> Result<StepOutcome, RuntimeError> {
+    let start = Instant::now();
+    loop {
+        let mut chunk = machine.timeout.map(|_| TIMEOUT_CHECK_INTERVAL);
+        if let Some(max) = machine.max_instructions {
+            let remaining = max.saturating_sub(machine.instructions_executed);
+            if remaining == 0 {
+                return Err(RuntimeError::LimitExceeded(
+                    machine.pc,
+                    Limit::Instructions(max),
+                ));
             }
+            chunk = Some(chunk.map_or(remaining, |chunk| chunk.min(remaining)));
         }
-        total_ran += 1;
-
-        prev_pc = pc;
-        if result as i16 <= 0 {
-            match c {
-                IO_ADDR => break,
-                DEBUG_ADDR => {
-                    return (Err(RuntimeError::Breakpoint(pc)), total_ran, io_time);
+
+        match run(machine, chunk) {
+            StepOutcome::Paused => {
+                if let Some(timeout) = machine.timeout
+                    && start.elapsed() >= timeout
+                {
+                    return Err(RuntimeError::LimitExceeded(
+                        machine.pc,
+                        Limit::Time(timeout),
+                    ));
                 }
-                _ => pc = c,
             }
-        } else {
-            pc += 3;
+            outcome => return Ok(outcome),
         }
     }
-    (Ok(io_buffer), total_ran, io_time)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble_for_test;
+    use crate::runtimes::io::{Buffered, EndOfInput};
+    use std::time::Duration;
+
+    #[test]
+    fn buffered_io() {
+        let text =
+            "x -= -1\n-1 -= x\n-2 -= x\nx -= x\nx -= -1\n-1 -= x\nZ -= Z -1\nx -> 0\nZ -> 0\n";
+        let (mem, _, _) = assemble_for_test(text);
+
+        let mut machine = Machine::new(mem.clone(), Buffered::new("ab"));
+        assert!(interpret(&mut machine).is_ok());
+        assert_eq!(machine.io.output, "a97\nb");
+
+        // Running out of input halts the program
+        let mut machine = Machine::new(mem.clone(), Buffered::new("a"));
+        assert!(interpret(&mut machine).is_ok());
+        assert_eq!(machine.io.output, "a97\n");
+        assert_eq!(machine.instructions_executed, 4);
+
+        // Unless the end of input is a value
+        let mut io = Buffered::new("a");
+        io.end_of_input = EndOfInput::Value(0);
+        let mut machine = Machine::new(mem, io);
+        assert!(interpret(&mut machine).is_ok());
+        assert_eq!(machine.io.output, "a97\n\0");
+    }
+
+    #[test]
+    fn limits() {
+        // Loops forever
+        let mem = vec![0, 0, 0];
+
+        let mut machine = Machine::new(mem.clone(), Buffered::default());
+        machine.max_instructions = Some(10);
+        assert_eq!(
+            interpret(&mut machine),
+            Err(RuntimeError::LimitExceeded(0, Limit::Instructions(10)))
+        );
+        assert_eq!(machine.instructions_executed, 10);
+
+        let mut machine = Machine::new(mem, Buffered::default());
+        machine.timeout = Some(Duration::ZERO);
+        assert_eq!(
+            interpret(&mut machine),
+            Err(RuntimeError::LimitExceeded(0, Limit::Time(Duration::ZERO)))
+        );
+    }
 }
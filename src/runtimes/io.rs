@@ -0,0 +1,208 @@
+//! Where the input and output of a running program go
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Stdout, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use crossterm::event::KeyCode;
+
+use crate::runtimes::get_key;
+
+/// What reading from -1 yields once all input has been read
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum EndOfInput {
+    /// The program halts, like when DELETE is pressed
+    #[default]
+    Halt,
+    /// The word is read, so the program can handle the end itself. Often 0 or -1
+    Value(u16),
+}
+
+impl FromStr for EndOfInput {
+    type Err = String;
+
+    /// 'halt' or a number from -32768 to 65535
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("halt") {
+            return Ok(EndOfInput::Halt);
+        }
+        match s.parse::<i32>() {
+            Ok(value) if (i16::MIN as i32..=u16::MAX as i32).contains(&value) => {
+                Ok(EndOfInput::Value(value as u16))
+            }
+            _ => Err(format!(
+                "expected 'halt' or a number from -32768 to 65535, found '{s}'"
+            )),
+        }
+    }
+}
+
+/// The IO of a subleq program. Reading from -1 reads a char, writing to -1 writes a char
+/// and writing to -2 writes a number
+pub trait Io {
+    /// None if there is no more input, see end_of_input()
+    fn read_char(&mut self) -> Option<char>;
+    fn write_char(&mut self, ch: char);
+    fn write_debug(&mut self, value: i16);
+    /// Called before input is read and when the program stops
+    fn flush(&mut self);
+    fn end_of_input(&self) -> EndOfInput {
+        EndOfInput::Halt
+    }
+}
+
+impl<T: Io + ?Sized> Io for &mut T {
+    fn read_char(&mut self) -> Option<char> {
+        (**self).read_char()
+    }
+    fn write_char(&mut self, ch: char) {
+        (**self).write_char(ch)
+    }
+    fn write_debug(&mut self, value: i16) {
+        (**self).write_debug(value)
+    }
+    fn flush(&mut self) {
+        (**self).flush()
+    }
+    fn end_of_input(&self) -> EndOfInput {
+        (**self).end_of_input()
+    }
+}
+
+impl<T: Io + ?Sized> Io for Box<T> {
+    fn read_char(&mut self) -> Option<char> {
+        (**self).read_char()
+    }
+    fn write_char(&mut self, ch: char) {
+        (**self).write_char(ch)
+    }
+    fn write_debug(&mut self, value: i16) {
+        (**self).write_debug(value)
+    }
+    fn flush(&mut self) {
+        (**self).flush()
+    }
+    fn end_of_input(&self) -> EndOfInput {
+        (**self).end_of_input()
+    }
+}
+
+/// Keys are read in raw mode without waiting for enter, DELETE ends the input
+#[derive(Debug, Default)]
+pub struct Terminal;
+
+impl Io for Terminal {
+    fn read_char(&mut self) -> Option<char> {
+        match get_key() {
+            KeyCode::Char(x) => Some(x),
+            KeyCode::Delete => None,
+            _ => Some('\0'),
+        }
+    }
+    fn write_char(&mut self, ch: char) {
+        print!("{ch}");
+    }
+    fn write_debug(&mut self, value: i16) {
+        println!("{value}");
+    }
+    fn flush(&mut self) {
+        io::stdout().flush().ok();
+    }
+}
+
+/// Input is given up front and output is collected, for tests and embedding
+#[derive(Debug, Default)]
+pub struct Buffered {
+    pub input: VecDeque<char>,
+    pub output: String,
+    pub end_of_input: EndOfInput,
+}
+
+impl Buffered {
+    pub fn new(input: &str) -> Self {
+        Buffered {
+            input: input.chars().collect(),
+            ..Default::default()
+        }
+    }
+}
+
+impl Io for Buffered {
+    fn read_char(&mut self) -> Option<char> {
+        self.input.pop_front()
+    }
+    fn write_char(&mut self, ch: char) {
+        self.output.push(ch);
+    }
+    fn write_debug(&mut self, value: i16) {
+        self.output.push_str(&format!("{value}\n"));
+    }
+    fn flush(&mut self) {}
+    fn end_of_input(&self) -> EndOfInput {
+        self.end_of_input
+    }
+}
+
+/// Reads bytes from stdin or a file and writes to stdout, so programs can be used in shell
+/// pipelines. Output is buffered until input is needed or the program stops
+pub struct Pipe {
+    input: BufReader<Box<dyn Read>>,
+    stdout: BufWriter<Stdout>,
+    end_of_input: EndOfInput,
+}
+
+impl Pipe {
+    pub fn stdin(end_of_input: EndOfInput) -> Self {
+        Pipe::new(Box::new(io::stdin()), end_of_input)
+    }
+
+    pub fn file(path: &Path, end_of_input: EndOfInput) -> io::Result<Self> {
+        Ok(Pipe::new(Box::new(File::open(path)?), end_of_input))
+    }
+
+    fn new(input: Box<dyn Read>, end_of_input: EndOfInput) -> Self {
+        Pipe {
+            input: BufReader::new(input),
+            stdout: BufWriter::new(io::stdout()),
+            end_of_input,
+        }
+    }
+}
+
+impl Io for Pipe {
+    /// Every byte is a char, there is no decoding
+    fn read_char(&mut self) -> Option<char> {
+        let mut byte = [0];
+        match self.input.read_exact(&mut byte) {
+            Ok(()) => Some(byte[0] as char),
+            Err(_) => None,
+        }
+    }
+    fn write_char(&mut self, ch: char) {
+        write!(self.stdout, "{ch}").ok();
+    }
+    fn write_debug(&mut self, value: i16) {
+        writeln!(self.stdout, "{value}").ok();
+    }
+    fn flush(&mut self) {
+        self.stdout.flush().ok();
+    }
+    fn end_of_input(&self) -> EndOfInput {
+        self.end_of_input
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn end_of_input_from_str() {
+        assert_eq!("halt".parse(), Ok(EndOfInput::Halt));
+        assert_eq!("0".parse(), Ok(EndOfInput::Value(0)));
+        assert_eq!("-1".parse(), Ok(EndOfInput::Value(0xFFFF)));
+        assert!("65536".parse::<EndOfInput>().is_err());
+        assert!("eof".parse::<EndOfInput>().is_err());
+    }
+}
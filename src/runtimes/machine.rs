@@ -0,0 +1,505 @@
+//! The subleq machine both runtimes are built on, so they execute instructions the same way
+use std::num::Wrapping;
+use std::time::{Duration, Instant};
+
+use crate::runtimes::RuntimeError;
+use crate::runtimes::devices::{Context, Devices, Written};
+use crate::runtimes::io::{EndOfInput, Io};
+use crate::runtimes::trace::TraceEntry;
+use crate::symbols::{DEBUG_ADDR, IO_ADDR, MEMORY_SIZE};
+
+/// The IO an instruction did, so it can be shown and undone
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IoEffect {
+    None,
+    Char(char),
+    Debug(i16),
+    Input(u16),
+}
+
+/// What an executed instruction changed
+#[derive(Debug, Clone, PartialEq)]
+pub struct Step {
+    pub pc: usize,
+    pub b: usize,
+    pub original_value_at_b: u16,
+    pub io: IoEffect,
+    prev_pc: usize,
+    /// False for a change made by hand, see Machine::set()
+    executed: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StepOutcome {
+    /// The instruction was executed, the program continues at pc
+    Ran(Step),
+    /// The instruction was executed and jumped to -2. The program continues after it
+    Breakpoint(Step),
+    /// The instruction jumped to -1, wrote to the halt device or there was no input left
+    Halted,
+    /// run() or run_until() stopped before the program did
+    Paused,
+    /// Nothing was changed
+    Error(RuntimeError),
+}
+
+/// What the next instruction will do, without doing it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Preview {
+    pub a: usize,
+    pub b: usize,
+    pub c: usize,
+    /// None if the instruction reads input or a device
+    pub result: Option<u16>,
+    /// Whether it jumps to c
+    pub jumps: bool,
+}
+
+pub struct Machine<I: Io> {
+    pub mem: Vec<u16>,
+    pub pc: usize,
+    /// The instruction before the current one, for errors about jumping out of memory
+    pub prev_pc: usize,
+    pub instructions_executed: u128,
+    /// Time spent waiting on IO and devices
+    pub io_time: Duration,
+    pub io: I,
+    /// Devices mapped to the words below the debug address, none by default
+    pub devices: Devices,
+    /// Given by the program when it halted through the halt device
+    pub exit_code: Option<i16>,
+    /// Limits enforced by interpreter::interpret(), see Limit
+    pub max_instructions: Option<u128>,
+    pub timeout: Option<Duration>,
+}
+
+impl<I: Io> Machine<I> {
+    pub fn new(mem: Vec<u16>, io: I) -> Self {
+        Machine {
+            mem,
+            pc: 0,
+            prev_pc: IO_ADDR,
+            instructions_executed: 0,
+            io_time: Duration::ZERO,
+            io,
+            devices: Devices::default(),
+            exit_code: None,
+            max_instructions: None,
+            timeout: None,
+        }
+    }
+
+    /// Zero fills memory up to the full address space, so programs can use the words above
+    /// their image without reserving them. The IO and debug addresses stay special, as do
+    /// the words attached devices claim
+    pub fn use_full_memory(&mut self) {
+        if self.mem.len() < MEMORY_SIZE {
+            self.mem.resize(MEMORY_SIZE, 0);
+        }
+    }
+
+    /// Whether the address is a word of memory, rather than IO or a device. With full memory
+    /// a device claims words that would otherwise be memory
+    #[inline(always)]
+    pub fn is_memory(&self, address: usize) -> bool {
+        address < self.mem.len() && !self.devices.claims(address)
+    }
+
+    /// Runs f on this machine with its io wrapped by wrap, like the debugger does to keep the
+    /// program's output out of its screen. The state f leaves the machine in is kept
+    pub fn with_io<'a, J: Io, R>(
+        &'a mut self,
+        wrap: impl FnOnce(&'a mut I) -> J,
+        f: impl FnOnce(&mut Machine<J>) -> R,
+    ) -> R {
+        let Machine {
+            mem,
+            pc,
+            prev_pc,
+            instructions_executed,
+            io_time,
+            io,
+            devices,
+            exit_code,
+            max_instructions,
+            timeout,
+        } = self;
+        let mut wrapped = Machine {
+            mem: std::mem::take(mem),
+            pc: *pc,
+            prev_pc: *prev_pc,
+            instructions_executed: *instructions_executed,
+            io_time: *io_time,
+            io: wrap(io),
+            devices: std::mem::take(devices),
+            exit_code: *exit_code,
+            max_instructions: *max_instructions,
+            timeout: *timeout,
+        };
+        let result = f(&mut wrapped);
+        (*mem, *pc, *prev_pc) = (wrapped.mem, wrapped.pc, wrapped.prev_pc);
+        (*instructions_executed, *io_time) = (wrapped.instructions_executed, wrapped.io_time);
+        (*devices, *exit_code) = (wrapped.devices, wrapped.exit_code);
+        result
+    }
+
+    /// The operands of the current instruction
+    #[inline(always)]
+    fn operands(&self) -> Result<(usize, usize, usize), RuntimeError> {
+        let pc = self.pc;
+        if pc + 2 >= self.mem.len() {
+            return Err(RuntimeError::COutOfRange(self.prev_pc));
+        }
+        let (a, b, c) = (
+            self.mem[pc] as usize,
+            self.mem[pc + 1] as usize,
+            self.mem[pc + 2] as usize,
+        );
+        // Only b may be the debug address, and a and b can't both be IO or a device
+        if !self.is_memory(a) && ((a != IO_ADDR && !self.devices.claims(a)) || !self.is_memory(b)) {
+            return Err(RuntimeError::AOutOfRange(pc));
+        }
+        if !self.is_memory(b) && b != IO_ADDR && b != DEBUG_ADDR && !self.devices.claims(b) {
+            return Err(RuntimeError::BOutOfRange(pc));
+        }
+        Ok((a, b, c))
+    }
+
+    pub fn preview(&self) -> Result<Preview, RuntimeError> {
+        let (a, b, c) = self.operands()?;
+        let result = match (a, b) {
+            (IO_ADDR, _) => None,
+            (_, IO_ADDR | DEBUG_ADDR) => Some((self.mem[a] as i16).wrapping_neg() as u16),
+            (a, _) if !self.is_memory(a) => None,
+            (_, b) if !self.is_memory(b) => Some((self.mem[a] as i16).wrapping_neg() as u16),
+            _ => Some((Wrapping(self.mem[b]) - Wrapping(self.mem[a])).0),
+        };
+        Ok(Preview {
+            a,
+            b,
+            c,
+            result,
+            jumps: result.is_none_or(|result| result as i16 <= 0),
+        })
+    }
+
+    /// Executes the instruction at pc. Always inlined, so the loop in run() is as fast as
+    /// a hand written one
+    #[inline(always)]
+    pub fn step(&mut self) -> StepOutcome {
+        let (a, b, c) = match self.operands() {
+            Ok(operands) => operands,
+            Err(e) => return StepOutcome::Error(e),
+        };
+        let original_value_at_b = self.mem.get(b).copied().unwrap_or(0);
+        let mut io = IoEffect::None;
+        let mut exit_code = None;
+
+        let result = match (a, b) {
+            // Input char
+            (IO_ADDR, _) => {
+                let timer = Instant::now();
+                self.io.flush();
+                let input = self.io.read_char();
+                self.io_time += timer.elapsed();
+
+                let value = match (input, self.io.end_of_input()) {
+                    (Some(c), _) => c as u16,
+                    (None, EndOfInput::Value(value)) => value,
+                    (None, EndOfInput::Halt) => return StepOutcome::Halted,
+                };
+                self.mem[b] = value;
+                io = IoEffect::Input(value);
+                0
+            }
+            // Output char
+            (_, IO_ADDR) => {
+                let ch = self.mem[a] as u8 as char;
+                let timer = Instant::now();
+                self.io.write_char(ch);
+                self.io_time += timer.elapsed();
+                io = IoEffect::Char(ch);
+                (self.mem[a] as i16).wrapping_neg() as u16
+            }
+            // Output int
+            (_, DEBUG_ADDR) => {
+                let value = self.mem[a] as i16;
+                let timer = Instant::now();
+                self.io.write_debug(value);
+                self.io_time += timer.elapsed();
+                io = IoEffect::Debug(value);
+                value.wrapping_neg() as u16
+            }
+            // Read a device, the value is stored like input
+            (a, _) if !self.is_memory(a) => {
+                let timer = Instant::now();
+                let mut context = Context {
+                    instructions_executed: self.instructions_executed,
+                    io: &mut self.io,
+                };
+                let value = self.devices.read(a, &mut context);
+                self.io_time += timer.elapsed();
+                let Some(value) = value else {
+                    return StepOutcome::Halted;
+                };
+                self.mem[b] = value;
+                0
+            }
+            // Write to a device, the value is given like output
+            (_, b) if !self.is_memory(b) => {
+                let value = self.mem[a];
+                let timer = Instant::now();
+                let mut context = Context {
+                    instructions_executed: self.instructions_executed,
+                    io: &mut self.io,
+                };
+                if let Written::Exit(code) = self.devices.write(b, value, &mut context) {
+                    exit_code = Some(code);
+                }
+                self.io_time += timer.elapsed();
+                (value as i16).wrapping_neg() as u16
+            }
+            (_, _) => {
+                let result = (Wrapping(self.mem[b]) - Wrapping(self.mem[a])).0;
+                self.mem[b] = result;
+                result
+            }
+        };
+
+        let step = Step {
+            pc: self.pc,
+            b,
+            original_value_at_b,
+            io,
+            prev_pc: self.prev_pc,
+            executed: true,
+        };
+        self.instructions_executed += 1;
+        self.prev_pc = self.pc;
+
+        if exit_code.is_some() {
+            self.exit_code = exit_code;
+            return StepOutcome::Halted;
+        }
+        if result as i16 > 0 {
+            self.pc += 3;
+            return StepOutcome::Ran(step);
+        }
+        match c {
+            IO_ADDR => StepOutcome::Halted,
+            DEBUG_ADDR => {
+                self.pc += 3;
+                StepOutcome::Breakpoint(step)
+            }
+            _ => {
+                self.pc = c;
+                StepOutcome::Ran(step)
+            }
+        }
+    }
+
+    /// Does what a recorded instruction did, without running it or doing its IO. An entry
+    /// recorded at another pc isn't applied, since the state wouldn't be one that happened
+    pub fn apply(&mut self, entry: &TraceEntry) -> StepOutcome {
+        if entry.pc as usize != self.pc {
+            return StepOutcome::Paused;
+        }
+        let b = entry.b as usize;
+        let step = Step {
+            pc: entry.pc as usize,
+            b,
+            original_value_at_b: self.mem.get(b).copied().unwrap_or(0),
+            io: entry.io,
+            prev_pc: self.prev_pc,
+            executed: true,
+        };
+        if b < self.mem.len() {
+            self.mem[b] = entry.new_value;
+        }
+        self.instructions_executed += 1;
+        self.prev_pc = step.pc;
+        self.pc = entry.next_pc();
+        match (entry.jumped, entry.c as usize) {
+            (true, IO_ADDR) => StepOutcome::Halted,
+            (true, DEBUG_ADDR) => StepOutcome::Breakpoint(step),
+            _ => StepOutcome::Ran(step),
+        }
+    }
+
+    /// Restores the state from before the step. Output can't be taken back
+    pub fn undo(&mut self, step: &Step) {
+        if step.b < self.mem.len() {
+            self.mem[step.b] = step.original_value_at_b;
+        }
+        self.pc = step.pc;
+        self.prev_pc = step.prev_pc;
+        if step.executed {
+            self.instructions_executed -= 1;
+        }
+    }
+
+    /// Writes a word by hand, like the debugger's console does. Undoing the returned step
+    /// restores it, without it counting as an executed instruction
+    pub fn set(&mut self, address: usize, value: u16) -> Step {
+        let step = Step {
+            pc: self.pc,
+            b: address,
+            original_value_at_b: self.mem[address],
+            io: IoEffect::None,
+            prev_pc: self.prev_pc,
+            executed: false,
+        };
+        self.mem[address] = value;
+        step
+    }
+
+    /// Continues at pc by hand, see set()
+    pub fn goto(&mut self, pc: usize) -> Step {
+        let step = Step {
+            pc: self.pc,
+            // Nothing is written
+            b: usize::MAX,
+            original_value_at_b: 0,
+            io: IoEffect::None,
+            prev_pc: self.prev_pc,
+            executed: false,
+        };
+        self.pc = pc;
+        step
+    }
+
+    /// Runs until the program stops or, if given, the limit of instructions has been executed
+    pub fn run(&mut self, limit: Option<u128>) -> StepOutcome {
+        let mut remaining = limit.unwrap_or(u128::MAX);
+        loop {
+            if remaining == 0 {
+                return StepOutcome::Paused;
+            }
+            remaining -= 1;
+            match self.step() {
+                StepOutcome::Ran(..) => {}
+                outcome => return outcome,
+            }
+        }
+    }
+
+    /// Like run(), calling observe with the pc before every instruction. Kept out of line,
+    /// so the loop of run() is compiled the same as when nothing is observed
+    #[inline(never)]
+    pub fn run_observed(
+        &mut self,
+        limit: Option<u128>,
+        mut observe: impl FnMut(usize),
+    ) -> StepOutcome {
+        let mut remaining = limit.unwrap_or(u128::MAX);
+        loop {
+            if remaining == 0 {
+                return StepOutcome::Paused;
+            }
+            remaining -= 1;
+            observe(self.pc);
+            match self.step() {
+                StepOutcome::Ran(..) => {}
+                outcome => return outcome,
+            }
+        }
+    }
+
+    /// Runs until the program stops, or the predicate holds before an instruction
+    pub fn run_until(&mut self, mut predicate: impl FnMut(&Self) -> bool) -> StepOutcome {
+        loop {
+            if predicate(self) {
+                return StepOutcome::Paused;
+            }
+            match self.step() {
+                StepOutcome::Ran(..) => {}
+                outcome => return outcome,
+            }
+        }
+    }
+
+    pub fn flush(&mut self) {
+        let timer = Instant::now();
+        self.io.flush();
+        self.io_time += timer.elapsed();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtimes::io::Buffered;
+
+    #[test]
+    fn step_undo_and_run() {
+        // A -= B, jumping to the end when A <= 0, then print A and halt
+        let mem = vec![10, 9, 3, 9, 0xFFFF, 6, 11, 11, 0xFFFF, 66, 1, 0];
+        let mut machine = Machine::new(mem.clone(), Buffered::default());
+
+        let preview = machine.preview().unwrap();
+        assert_eq!(preview.result, Some(65));
+        assert!(!preview.jumps);
+        let StepOutcome::Ran(step) = machine.step() else {
+            panic!("Expected the instruction to run");
+        };
+        assert_eq!((machine.pc, machine.mem[9]), (3, 65));
+
+        machine.undo(&step);
+        assert_eq!(machine.mem, mem);
+        assert_eq!((machine.pc, machine.instructions_executed), (0, 0));
+
+        assert_eq!(machine.run_until(|m| m.pc == 6), StepOutcome::Paused);
+        assert_eq!(machine.io.output, "A");
+        assert_eq!(machine.run(Some(10)), StepOutcome::Halted);
+        assert_eq!(machine.instructions_executed, 3);
+
+        let mut machine = Machine::new(vec![0, 0, 0], Buffered::default());
+        assert_eq!(machine.run(Some(5)), StepOutcome::Paused);
+        machine.pc = 1;
+        assert_eq!(
+            machine.step(),
+            StepOutcome::Error(RuntimeError::COutOfRange(0))
+        );
+    }
+
+    #[test]
+    fn apply_follows_pc() {
+        let mem = vec![10, 9, 3, 9, 0xFFFF, 6, 11, 11, 0xFFFF, 66, 1, 0];
+        let mut machine = Machine::new(mem.clone(), Buffered::default());
+        let entry = TraceEntry {
+            pc: 3,
+            a: 9,
+            b: 0xFFFF,
+            c: 6,
+            old_value: 0,
+            new_value: 0,
+            jumped: false,
+            io: IoEffect::Char('B'),
+        };
+        // Recorded at another pc
+        assert_eq!(machine.apply(&entry), StepOutcome::Paused);
+        assert_eq!((machine.pc, machine.instructions_executed), (0, 0));
+
+        machine.goto(3);
+        assert!(matches!(machine.apply(&entry), StepOutcome::Ran(_)));
+        assert_eq!(machine.pc, 6);
+    }
+
+    #[test]
+    fn full_memory() {
+        // Subtracts from and outputs 0x100, which is past the image
+        let mem = vec![3, 0x100, 3, 0x100, 0xFFFF, 6, 7, 7, 0xFFFF];
+        let mut machine = Machine::new(mem.clone(), Buffered::default());
+        assert_eq!(
+            machine.step(),
+            StepOutcome::Error(RuntimeError::BOutOfRange(0))
+        );
+
+        let mut machine = Machine::new(mem, Buffered::default());
+        machine.use_full_memory();
+        assert_eq!(machine.mem.len(), MEMORY_SIZE);
+        assert_eq!(machine.run(None), StepOutcome::Halted);
+        assert_eq!(machine.mem[0x100], 0xFF00);
+        assert_eq!(machine.io.output, "\0");
+    }
+}
@@ -3,30 +3,49 @@ use crossterm::{
     event::{Event, KeyCode, KeyEventKind, read},
     terminal::{disable_raw_mode, enable_raw_mode},
 };
+use std::time::Duration;
 
+pub mod breakpoints;
+pub mod console;
 pub mod debugger;
+pub mod decoded;
+pub mod devices;
 pub mod interpreter;
+pub mod io;
+pub mod machine;
+pub mod trace;
+pub mod variables;
+pub mod watchpoints;
 
 /// These are all the issues that can occur when running a subleq program.
 /// Note that Breakpoints are non-canonical and specific to this assembler.
 /// They occur during a jump to -2
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum RuntimeError {
     AOutOfRange(usize),
     BOutOfRange(usize),
     COutOfRange(usize),
     Breakpoint(usize),
+    /// Execution was stopped at pc because it ran into a limit
+    LimitExceeded(usize, Limit),
+}
+
+/// A limit on how long a program may run, see Machine
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Limit {
+    Instructions(u128),
+    Time(Duration),
 }
 /// Get a single key (blocking)
 pub fn get_key() -> KeyCode {
     enable_raw_mode().unwrap();
     loop {
-        if let Ok(Event::Key(event)) = read() {
-            if event.kind == KeyEventKind::Press {
-                disable_raw_mode().unwrap();
+        if let Ok(Event::Key(event)) = read()
+            && event.kind == KeyEventKind::Press
+        {
+            disable_raw_mode().unwrap();
 
-                return event.code;
-            }
+            return event.code;
         }
     }
 }
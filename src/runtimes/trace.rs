@@ -0,0 +1,252 @@
+//! Records every executed instruction in a compact binary format, so a run can be replayed
+//! in the debugger without running the program again.
+//!
+//! A trace starts with a header:
+//! * The magic bytes 'SBLTRACE' and a version byte
+//! * The length (u32) and an FNV-1a hash (u64) of the program image the run started with
+//! * The length of memory (u32), which is longer than the image with full memory
+//!
+//! Followed by an entry of 15 bytes per instruction: pc, a, b, c, the value at b before and
+//! after, flags and the IO value. Everything is little endian
+use std::io::{self, Read, Write};
+
+use crate::runtimes::machine::{IoEffect, Preview};
+use crate::symbols::{DEBUG_ADDR, IO_ADDR};
+
+const MAGIC: &[u8; 8] = b"SBLTRACE";
+const VERSION: u8 = 1;
+const HEADER_SIZE: usize = MAGIC.len() + 1 + 4 + 8 + 4;
+const ENTRY_SIZE: usize = 15;
+
+const JUMPED: u8 = 1;
+const IO_SHIFT: u8 = 1;
+const IO_CHAR: u8 = 1;
+const IO_DEBUG: u8 = 2;
+const IO_INPUT: u8 = 3;
+
+/// One executed instruction
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TraceEntry {
+    pub pc: u16,
+    pub a: u16,
+    pub b: u16,
+    pub c: u16,
+    /// 0 when b is the IO or debug address
+    pub old_value: u16,
+    pub new_value: u16,
+    pub jumped: bool,
+    pub io: IoEffect,
+}
+
+impl TraceEntry {
+    /// The entry for the instruction at pc, from its preview and memory after running it
+    pub fn new(pc: usize, preview: &Preview, old_value: u16, mem: &[u16]) -> Self {
+        let io = match (preview.a, preview.b) {
+            (IO_ADDR, b) => IoEffect::Input(mem[b]),
+            (a, IO_ADDR) => IoEffect::Char(mem[a] as u8 as char),
+            (a, DEBUG_ADDR) => IoEffect::Debug(mem[a] as i16),
+            _ => IoEffect::None,
+        };
+        TraceEntry {
+            pc: pc as u16,
+            a: preview.a as u16,
+            b: preview.b as u16,
+            c: preview.c as u16,
+            old_value,
+            new_value: mem.get(preview.b).copied().unwrap_or(0),
+            jumped: preview.jumps,
+            io,
+        }
+    }
+
+    /// Where execution continues. Breakpoints continue after the instruction
+    pub fn next_pc(&self) -> usize {
+        match (self.jumped, self.c as usize) {
+            (true, DEBUG_ADDR) | (false, _) => self.pc as usize + 3,
+            (true, c) => c,
+        }
+    }
+
+    fn to_bytes(self) -> [u8; ENTRY_SIZE] {
+        let (kind, value) = match self.io {
+            IoEffect::None => (0, 0),
+            IoEffect::Char(ch) => (IO_CHAR, ch as u16),
+            IoEffect::Debug(value) => (IO_DEBUG, value as u16),
+            IoEffect::Input(value) => (IO_INPUT, value),
+        };
+        let mut bytes = [0; ENTRY_SIZE];
+        let words = [
+            self.pc,
+            self.a,
+            self.b,
+            self.c,
+            self.old_value,
+            self.new_value,
+        ];
+        for (i, word) in words.iter().enumerate() {
+            bytes[i * 2..i * 2 + 2].copy_from_slice(&word.to_le_bytes());
+        }
+        bytes[12] = (kind << IO_SHIFT) | if self.jumped { JUMPED } else { 0 };
+        bytes[13..15].copy_from_slice(&value.to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8; ENTRY_SIZE]) -> Self {
+        let word = |i: usize| u16::from_le_bytes([bytes[i * 2], bytes[i * 2 + 1]]);
+        let value = u16::from_le_bytes([bytes[13], bytes[14]]);
+        TraceEntry {
+            pc: word(0),
+            a: word(1),
+            b: word(2),
+            c: word(3),
+            old_value: word(4),
+            new_value: word(5),
+            jumped: bytes[12] & JUMPED != 0,
+            io: match bytes[12] >> IO_SHIFT {
+                IO_CHAR => IoEffect::Char(value as u8 as char),
+                IO_DEBUG => IoEffect::Debug(value as i16),
+                IO_INPUT => IoEffect::Input(value),
+                _ => IoEffect::None,
+            },
+        }
+    }
+}
+
+/// FNV-1a, to recognise the program a trace was recorded with
+fn hash(image: &[u16]) -> u64 {
+    image.iter().fold(0xcbf29ce484222325, |hash, word| {
+        word.to_le_bytes().iter().fold(hash, |hash, byte| {
+            (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+        })
+    })
+}
+
+/// Writes entries as they are recorded. The first error is kept and returned by finish(),
+/// so recording doesn't have to be checked after every instruction
+pub struct TraceWriter<W: Write> {
+    out: W,
+    error: Option<io::Error>,
+}
+
+impl<W: Write> TraceWriter<W> {
+    /// Writes the header for a run of the image in a memory of memory_size words
+    pub fn new(mut out: W, image: &[u16], memory_size: usize) -> Self {
+        let mut header = Vec::with_capacity(HEADER_SIZE);
+        header.extend_from_slice(MAGIC);
+        header.push(VERSION);
+        header.extend_from_slice(&(image.len() as u32).to_le_bytes());
+        header.extend_from_slice(&hash(image).to_le_bytes());
+        header.extend_from_slice(&(memory_size as u32).to_le_bytes());
+        let error = out.write_all(&header).err();
+        TraceWriter { out, error }
+    }
+
+    pub fn record(&mut self, entry: TraceEntry) {
+        if self.error.is_none() {
+            self.error = self.out.write_all(&entry.to_bytes()).err();
+        }
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        match self.error {
+            Some(e) => Err(e),
+            None => self.out.flush(),
+        }
+    }
+}
+
+/// A recorded run of a program
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trace {
+    /// The length of memory the run had
+    pub memory_size: usize,
+    pub entries: Vec<TraceEntry>,
+}
+
+impl Trace {
+    /// Reads a trace of the image, failing if it was recorded with a different program
+    pub fn read(mut input: impl Read, image: &[u16]) -> Result<Self, String> {
+        let mut bytes = Vec::new();
+        input
+            .read_to_end(&mut bytes)
+            .map_err(|e| format!("Failed to read the trace. {e}"))?;
+
+        if bytes.len() < HEADER_SIZE || &bytes[..MAGIC.len()] != MAGIC {
+            return Err("This is not a trace written with --trace".to_owned());
+        }
+        if bytes[MAGIC.len()] != VERSION {
+            return Err(format!(
+                "The trace has version {}, only version {VERSION} can be read",
+                bytes[MAGIC.len()]
+            ));
+        }
+        let header = &bytes[MAGIC.len() + 1..HEADER_SIZE];
+        let image_length = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+        let image_hash = u64::from_le_bytes(header[4..12].try_into().unwrap());
+        let memory_size = u32::from_le_bytes(header[12..16].try_into().unwrap()) as usize;
+        if image_length != image.len() || image_hash != hash(image) {
+            return Err("The trace was recorded with a different program".to_owned());
+        }
+
+        let body = &bytes[HEADER_SIZE..];
+        if body.len() % ENTRY_SIZE != 0 {
+            return Err("The trace ends in the middle of an instruction".to_owned());
+        }
+        let entries = body
+            .chunks_exact(ENTRY_SIZE)
+            .map(|chunk| TraceEntry::from_bytes(chunk.try_into().unwrap()))
+            .collect();
+        Ok(Trace {
+            memory_size,
+            entries,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_and_read() {
+        let image = vec![0xFFFF, 4, 3, 4, 0xFFFF, 0xFFFF];
+        let entries = vec![
+            TraceEntry {
+                pc: 0,
+                a: 0xFFFF,
+                b: 4,
+                c: 3,
+                old_value: 0xFFFF,
+                new_value: 'x' as u16,
+                jumped: true,
+                io: IoEffect::Input('x' as u16),
+            },
+            TraceEntry {
+                pc: 3,
+                a: 4,
+                b: 0xFFFF,
+                c: 0xFFFF,
+                old_value: 0,
+                new_value: 0,
+                jumped: true,
+                io: IoEffect::Char('x'),
+            },
+        ];
+
+        let mut writer = TraceWriter::new(Vec::new(), &image, image.len());
+        for entry in &entries {
+            writer.record(*entry);
+        }
+        let bytes = writer.out.clone();
+        writer.finish().unwrap();
+        assert_eq!(bytes.len(), HEADER_SIZE + 2 * ENTRY_SIZE);
+
+        let trace = Trace::read(bytes.as_slice(), &image).unwrap();
+        assert_eq!(trace.memory_size, image.len());
+        assert_eq!(trace.entries, entries);
+        assert_eq!(trace.entries[0].next_pc(), 3);
+
+        assert!(Trace::read(bytes.as_slice(), &image[..5]).is_err());
+        assert!(Trace::read(&bytes[..bytes.len() - 1], &image).is_err());
+    }
+}
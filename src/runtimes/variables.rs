@@ -0,0 +1,179 @@
+//! The variables panel of the debugger, the values of the labels chosen to be shown and of
+//! the labels the current instruction uses
+use std::fmt;
+
+use crate::runtimes::breakpoints::find_labels;
+use crate::runtimes::machine::Step;
+use crate::symbol_table::Symbol;
+
+/// A row of the panel
+#[derive(Debug, Clone, PartialEq)]
+pub struct Variable {
+    /// The full name of the label, with the hygiene prefix of a macro
+    pub name: String,
+    pub address: usize,
+    pub value: u16,
+    /// Whether the last step wrote a different value
+    pub changed: bool,
+    /// Shown because the current instruction uses it, not because it was chosen
+    pub automatic: bool,
+}
+
+impl fmt::Display for Variable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ch = match self.value {
+            0x20..0x7F => format!("'{}'", self.value as u8 as char),
+            _ => String::new(),
+        };
+        write!(
+            f,
+            "{}{: <24} 0x{:04X} {: >6}  0x{:04X} {: >3}",
+            if self.automatic { "~" } else { " " },
+            self.name,
+            self.address,
+            self.value as i16,
+            self.value,
+            ch
+        )
+    }
+}
+
+/// The labels chosen to be shown, in the order they were added
+#[derive(Debug, Default)]
+pub struct Variables {
+    list: Vec<String>,
+}
+
+impl Variables {
+    /// Adds the label, a label in a macro is shown for every expansion
+    pub fn add(&mut self, name: &str, symbols: &[Symbol]) -> Result<(), String> {
+        let name = name.trim();
+        if find_labels(symbols, name).is_empty() {
+            return Err(format!("There is no label '{name}'"));
+        }
+        if self.list.iter().any(|shown| shown == name) {
+            return Err(format!("{name} is already shown"));
+        }
+        self.list.push(name.to_owned());
+        Ok(())
+    }
+
+    /// Removes the label with the number or name
+    pub fn remove(&mut self, text: &str) -> Result<String, String> {
+        let text = text.trim();
+        let index = match text.parse::<usize>() {
+            Ok(number) if (1..=self.list.len()).contains(&number) => number - 1,
+            _ => self
+                .list
+                .iter()
+                .position(|name| name == text)
+                .ok_or_else(|| format!("{text} isn't shown"))?,
+        };
+        Ok(self.list.remove(index))
+    }
+
+    pub fn clear(&mut self) {
+        self.list.clear();
+    }
+
+    pub fn list(&self) -> &[String] {
+        &self.list
+    }
+
+    /// The rows of the panel: the chosen labels, then the labels a and b of the instruction at
+    /// pc point to. last_step is the step that led to mem
+    pub fn rows(
+        &self,
+        mem: &[u16],
+        pc: usize,
+        last_step: Option<&Step>,
+        symbols: &[Symbol],
+    ) -> Vec<Variable> {
+        let chosen = self
+            .list
+            .iter()
+            .flat_map(|name| find_labels(symbols, name))
+            .map(|symbol| (&symbol.name, symbol.address, false));
+        let operands = mem.get(pc..pc + 2).unwrap_or_default();
+        let used = operands.iter().filter_map(|&address| {
+            let name = label_at(symbols, address as usize)?;
+            Some((name, address as usize, true))
+        });
+
+        let mut rows: Vec<Variable> = Vec::new();
+        for (name, address, automatic) in chosen.chain(used) {
+            let Some(&value) = mem.get(address) else {
+                continue;
+            };
+            if rows.iter().any(|row| row.address == address) {
+                continue;
+            }
+            rows.push(Variable {
+                name: name.clone(),
+                address,
+                value,
+                changed: last_step
+                    .is_some_and(|step| step.b == address && value != step.original_value_at_b),
+                automatic,
+            });
+        }
+        rows
+    }
+}
+
+/// The name of the first label at the address
+fn label_at(symbols: &[Symbol], address: usize) -> Option<&String> {
+    symbols
+        .iter()
+        .find(|symbol| symbol.address == address)
+        .map(|symbol| &symbol.name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble_for_test;
+    use crate::runtimes::io::Buffered;
+    use crate::runtimes::machine::{Machine, StepOutcome};
+
+    #[test]
+    fn rows() {
+        let text = "A -= B\nZ -= Z -1\nA -> 5\nB -> 2\nC -> 'x'\nZ -> 0\n";
+        let (mem, _, symbols) = assemble_for_test(text);
+        let mut machine = Machine::new(mem, Buffered::default());
+
+        let mut variables = Variables::default();
+        variables.add("C", &symbols).unwrap();
+        variables.add("A", &symbols).unwrap();
+        assert!(variables.add("A", &symbols).is_err());
+        assert!(variables.add("missing", &symbols).is_err());
+
+        let names = |rows: &[Variable]| -> Vec<(String, bool, bool)> {
+            rows.iter()
+                .map(|row| (row.name.clone(), row.changed, row.automatic))
+                .collect()
+        };
+        // A is chosen, so only B is shown for the instruction
+        assert_eq!(
+            names(&variables.rows(&machine.mem, machine.pc, None, &symbols)),
+            vec![
+                ("C".to_owned(), false, false),
+                ("A".to_owned(), false, false),
+                ("B".to_owned(), false, true)
+            ]
+        );
+
+        let StepOutcome::Ran(step) = machine.step() else {
+            panic!("Expected the instruction to run");
+        };
+        let rows = variables.rows(&machine.mem, machine.pc, Some(&step), &symbols);
+        assert_eq!(rows[1].value, 3);
+        assert!(rows[1].changed);
+        assert_eq!(rows[2].name, "Z");
+        assert_eq!(rows[0].to_string().split_whitespace().last(), Some("'x'"));
+
+        variables.remove("1").unwrap();
+        assert!(variables.remove("C").is_err());
+        assert_eq!(variables.list(), ["A"]);
+    }
+}
@@ -0,0 +1,215 @@
+//! Watchpoints on labels and addresses, checked against the steps the debugger executes
+use std::fmt;
+
+use crate::runtimes::breakpoints::label_addresses;
+use crate::runtimes::machine::Step;
+use crate::symbol_table::Symbol;
+
+/// When a watchpoint pauses the debugger
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Condition {
+    /// The word is written, even with the value it already had
+    Written,
+    /// The word is written with a different value
+    Changed,
+    /// The word is written with this value
+    Equals(u16),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Watchpoint {
+    /// The label or address as it was given
+    pub target: String,
+    pub addresses: Vec<usize>,
+    pub condition: Condition,
+}
+
+impl Watchpoint {
+    /// 'target', 'target changed' or 'target = value'. The target is a label or an address in
+    /// hex like 0x1F, so it can't be mistaken for the number of a watchpoint. Values are
+    /// decimal or hex
+    pub fn parse(text: &str, memory_size: usize, symbols: &[Symbol]) -> Result<Self, String> {
+        let text = text.trim();
+        let (target, rest) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+        let rest = rest.trim();
+        if target.is_empty() {
+            return Err("Expected a label or an address to watch".to_owned());
+        }
+
+        let condition = if rest.is_empty() {
+            Condition::Written
+        } else if rest == "changed" {
+            Condition::Changed
+        } else if let Some(value) = rest.strip_prefix("==").or(rest.strip_prefix('=')) {
+            let value = value.trim();
+            Condition::Equals(
+                parse_word(value).ok_or_else(|| format!("'{value}' is not a value for a word"))?,
+            )
+        } else {
+            return Err(format!(
+                "Expected 'changed' or '= value' after the target, found '{rest}'"
+            ));
+        };
+
+        let addresses = match (parse_address(target), parse_word(target)) {
+            (Some(address), _) if (address as usize) < memory_size => vec![address as usize],
+            (Some(address), _) => return Err(format!("0x{address:04X} is outside of memory")),
+            (None, Some(address)) => {
+                return Err(format!(
+                    "Addresses are watched in hex, like 0x{address:X}, found '{target}'"
+                ));
+            }
+            (None, None) => label_addresses(symbols, target),
+        };
+        if addresses.is_empty() {
+            return Err(format!("There is no label '{target}'"));
+        }
+        Ok(Watchpoint {
+            target: target.to_owned(),
+            addresses,
+            condition,
+        })
+    }
+
+    /// Whether the step, after which memory is mem, triggers the watchpoint
+    pub fn is_hit(&self, step: &Step, mem: &[u16]) -> bool {
+        let Some(&value) = mem.get(step.b) else {
+            return false;
+        };
+        self.addresses.contains(&step.b)
+            && match self.condition {
+                Condition::Written => true,
+                Condition::Changed => value != step.original_value_at_b,
+                Condition::Equals(expected) => value == expected,
+            }
+    }
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.condition {
+            Condition::Written => write!(f, "{}", self.target),
+            Condition::Changed => write!(f, "{} changed", self.target),
+            Condition::Equals(value) => write!(f, "{} = {}", self.target, value as i16),
+        }
+    }
+}
+
+/// An address in hex like 0x1F
+fn parse_address(text: &str) -> Option<u16> {
+    let hex = text.strip_prefix("0x").or(text.strip_prefix("0X"))?;
+    u16::from_str_radix(hex, 16).ok()
+}
+
+/// A word like -3, 65533 or 0xFFFD
+pub(crate) fn parse_word(text: &str) -> Option<u16> {
+    if text.starts_with("0x") || text.starts_with("0X") {
+        return parse_address(text);
+    }
+    match text.parse::<i32>() {
+        Ok(value) if (i16::MIN as i32..=u16::MAX as i32).contains(&value) => Some(value as u16),
+        _ => None,
+    }
+}
+
+/// The watchpoints set in the debugger, numbered from 1 in the order they were set
+#[derive(Debug, Default)]
+pub struct Watchpoints {
+    list: Vec<Watchpoint>,
+}
+
+impl Watchpoints {
+    /// Parses the watchpoint, see Watchpoint::parse(). A watchpoint on the same addresses
+    /// with the same condition is already watched, however its target was written
+    pub fn add(
+        &mut self,
+        text: &str,
+        memory_size: usize,
+        symbols: &[Symbol],
+    ) -> Result<&Watchpoint, String> {
+        let watchpoint = Watchpoint::parse(text, memory_size, symbols)?;
+        if self.list.iter().any(|watched| {
+            watched.addresses == watchpoint.addresses && watched.condition == watchpoint.condition
+        }) {
+            return Err(format!("{watchpoint} is already watched"));
+        }
+        self.list.push(watchpoint);
+        Ok(self.list.last().unwrap())
+    }
+
+    /// Removes the watchpoint with the number or target. Addresses are matched by value, so
+    /// 0x9 removes a watchpoint on 0x09
+    pub fn remove(&mut self, text: &str) -> Result<Watchpoint, String> {
+        let text = text.trim();
+        let index = match text.parse::<usize>() {
+            Ok(number) if (1..=self.list.len()).contains(&number) => number - 1,
+            Ok(number) => return Err(format!("There is no watchpoint {number}")),
+            Err(_) => self
+                .list
+                .iter()
+                .position(|watchpoint| match parse_address(text) {
+                    Some(address) => parse_address(&watchpoint.target) == Some(address),
+                    None => watchpoint.target == text,
+                })
+                .ok_or_else(|| format!("There is no watchpoint on {text}"))?,
+        };
+        Ok(self.list.remove(index))
+    }
+
+    pub fn clear(&mut self) {
+        self.list.clear();
+    }
+
+    pub fn list(&self) -> &[Watchpoint] {
+        &self.list
+    }
+
+    /// The first watchpoint the step triggers
+    pub fn hit(&self, step: &Step, mem: &[u16]) -> Option<&Watchpoint> {
+        self.list
+            .iter()
+            .find(|watchpoint| watchpoint.is_hit(step, mem))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtimes::io::Buffered;
+    use crate::runtimes::machine::{Machine, StepOutcome};
+
+    #[test]
+    fn conditions() {
+        // Subtracts 0 from 9, then 1 from 9 twice
+        let mem = vec![10, 9, 3, 11, 9, 6, 11, 9, 0xFFFF, 5, 0, 1];
+        let mut machine = Machine::new(mem.clone(), Buffered::default());
+        let mut watchpoints = Watchpoints::default();
+        watchpoints.add("0x9 changed", mem.len(), &[]).unwrap();
+        watchpoints.add("0x09 = 3", mem.len(), &[]).unwrap();
+        watchpoints.add("0X9", mem.len(), &[]).unwrap();
+        assert!(watchpoints.add("0x9 = 3", mem.len(), &[]).is_err());
+        assert!(watchpoints.add("0xC", mem.len(), &[]).is_err());
+        // A decimal address could be mistaken for the number of a watchpoint
+        assert!(watchpoints.add("9", mem.len(), &[]).is_err());
+        assert!(watchpoints.add("9 == x", mem.len(), &[]).is_err());
+        assert!(watchpoints.add("missing", mem.len(), &[]).is_err());
+
+        fn hit(machine: &mut Machine<Buffered>, watchpoints: &Watchpoints) -> Option<String> {
+            let StepOutcome::Ran(step) = machine.step() else {
+                panic!("Expected the instruction to run");
+            };
+            watchpoints
+                .hit(&step, &machine.mem)
+                .map(|watchpoint| watchpoint.to_string())
+        }
+        assert_eq!(hit(&mut machine, &watchpoints), Some("0X9".to_owned()));
+        assert_eq!(
+            hit(&mut machine, &watchpoints),
+            Some("0x9 changed".to_owned())
+        );
+        assert!(watchpoints.remove("4").is_err());
+        watchpoints.remove("3").unwrap();
+        watchpoints.remove("0x9").unwrap();
+        assert_eq!(hit(&mut machine, &watchpoints), Some("0x09 = 3".to_owned()));
+    }
+}
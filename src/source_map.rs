@@ -0,0 +1,244 @@
+//! The .sblmap sidecar, which records where every word of an assembled program came from.
+//! It lets the debugger and runtime errors show the source of .bin and .sblx files
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error,
+    feedback::AssembleError,
+    lexer::{self, SourceFile},
+    symbol_table::Symbol,
+    tokens::{Info, Token, TokenVariant},
+};
+
+pub const SOURCE_MAP_EXTENSION: &str = "sblmap";
+/// Increased whenever the format changes in a way older versions can't read
+const VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct SourceMap {
+    version: u32,
+    /// The assembled files, with the contents that were assembled
+    files: Vec<MappedFile>,
+    /// One per memory word
+    words: Vec<MappedWord>,
+    /// The labels, see symbol_table
+    #[serde(default)]
+    symbols: Vec<MappedSymbol>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct MappedSymbol {
+    name: String,
+    address: usize,
+    location: Location,
+    scope_path: Vec<usize>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct MappedFile {
+    path: PathBuf,
+    contents: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct MappedWord {
+    #[serde(flatten)]
+    location: Location,
+    /// The macro expansion chain, outermost call first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    origin_info: Vec<Location>,
+}
+
+/// All positions are 1-based
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Location {
+    file: usize,
+    line: i32,
+    column: i32,
+    length: i32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    suffix: Option<String>,
+}
+
+impl From<&Info> for Location {
+    fn from(info: &Info) -> Self {
+        Location {
+            file: info.file,
+            line: info.line_number,
+            column: info.start_char,
+            length: info.length,
+            suffix: info.sourceline_suffix.clone(),
+        }
+    }
+}
+
+impl From<Location> for Info {
+    fn from(location: Location) -> Self {
+        Info {
+            start_char: location.column,
+            length: location.length,
+            line_number: location.line,
+            file: location.file,
+            sourceline_suffix: location.suffix,
+        }
+    }
+}
+
+/// The path of the source map that belongs to an output or input file
+pub fn path_for(file: &Path) -> PathBuf {
+    file.with_extension(SOURCE_MAP_EXTENSION)
+}
+
+/// Serialises the tokens and symbols of an assembly, together with the files in lexer::FILES
+pub fn to_string(tokens: &[Token], symbols: &[Symbol]) -> String {
+    let files = lexer::FILES.with_borrow(|files| {
+        files
+            .iter()
+            .map(|file| MappedFile {
+                path: file.path.clone(),
+                contents: file.contents.clone(),
+            })
+            .collect()
+    });
+    let words = tokens
+        .iter()
+        .map(|token| MappedWord {
+            location: Location::from(&token.info),
+            origin_info: token.origin_info.iter().map(Location::from).collect(),
+        })
+        .collect();
+    let symbols = symbols
+        .iter()
+        .map(|symbol| MappedSymbol {
+            name: symbol.name.clone(),
+            address: symbol.address,
+            location: Location::from(&symbol.info),
+            scope_path: symbol.scope_path.clone(),
+        })
+        .collect();
+
+    serde_json::to_string(&SourceMap {
+        version: VERSION,
+        files,
+        words,
+        symbols,
+    })
+    .expect("A source map is always valid JSON")
+}
+
+/// Reads a source map for the given memory, returning its tokens and symbols. The mapped
+/// files replace lexer::FILES, so feedback messages can show them. Every returned token is
+/// a literal of its word
+pub fn from_str(text: &str, mem: &[u16]) -> Result<(Vec<Token>, Vec<Symbol>), AssembleError> {
+    let map: SourceMap = match serde_json::from_str(text) {
+        Ok(map) => map,
+        Err(e) => error!("Invalid source map. {e}"),
+    };
+    if map.version != VERSION {
+        error!(
+            "Unsupported source map version {}, expected {VERSION}",
+            map.version
+        );
+    }
+    if map.words.len() != mem.len() {
+        error!(
+            "The source map has {} words, but the program has {}",
+            map.words.len(),
+            mem.len()
+        );
+    }
+    let contains_file = |location: &Location| location.file < map.files.len();
+    if !map
+        .words
+        .iter()
+        .all(|word| contains_file(&word.location) && word.origin_info.iter().all(contains_file))
+        || !map
+            .symbols
+            .iter()
+            .all(|symbol| contains_file(&symbol.location))
+    {
+        error!("The source map refers to a file it doesn't contain");
+    }
+
+    let symbols = map
+        .symbols
+        .into_iter()
+        .map(|symbol| {
+            Symbol::new(
+                symbol.name,
+                symbol.address,
+                symbol.location.into(),
+                symbol.scope_path,
+            )
+        })
+        .collect();
+    lexer::FILES.set(
+        map.files
+            .into_iter()
+            .map(|file| SourceFile {
+                path: file.path,
+                contents: file.contents,
+            })
+            .collect(),
+    );
+    let tokens = map
+        .words
+        .into_iter()
+        .zip(mem)
+        .map(|(word, value)| Token {
+            info: Info::from(word.location),
+            variant: TokenVariant::DecLiteral {
+                value: *value as i32,
+            },
+            origin_info: word.origin_info.into_iter().map(Info::from).collect(),
+        })
+        .collect();
+    Ok((tokens, symbols))
+}
+
+/// Loads the source map next to an input file, if there is one. A map that can't be used
+/// is ignored with a warning, since the program itself can still run
+pub fn load_sidecar(target: &Path, mem: &[u16]) -> Option<(Vec<Token>, Vec<Symbol>)> {
+    let path = path_for(target);
+    let text = fs::read_to_string(&path).ok()?;
+    match from_str(&text, mem) {
+        Ok(map) => Some(map),
+        Err(e) => {
+            log::warn!("Ignoring the source map {}. {e}", path.display());
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble_for_test;
+
+    #[test]
+    fn round_trip() {
+        let text = "@Clear a? {\n    a? -= a?\n}\n!Clear A\nA -> 0\n";
+        let (mem, tokens, symbols) = assemble_for_test(text);
+        let map = to_string(&tokens, &symbols);
+
+        lexer::FILES.set(Vec::new());
+        let (loaded, loaded_symbols) = from_str(&map, &mem).unwrap();
+        assert_eq!(loaded_symbols, symbols);
+        for (original, loaded) in tokens.iter().zip(&loaded) {
+            assert_eq!(original.info, loaded.info);
+            assert_eq!(original.origin_info, loaded.origin_info);
+        }
+        lexer::FILES.with_borrow(|files| {
+            assert_eq!(files[0].path, PathBuf::from("Test.sbl"));
+            assert_eq!(files[0].contents, text);
+        });
+
+        let error = from_str(&map, &mem[1..]).unwrap_err();
+        assert!(error.message.starts_with("The source map has"));
+    }
+}
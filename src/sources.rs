@@ -0,0 +1,171 @@
+//! Where the assembler gets its source files from
+use std::{
+    collections::HashMap,
+    fmt, fs, io,
+    iter::once,
+    path::{Component, Path, PathBuf},
+};
+
+/// Provides the source files for an assembly. The lexer resolves and reads includes through
+/// it, the contents it returns are also what feedback messages and the debugger preview show
+pub trait SourceProvider: fmt::Debug + Send + Sync {
+    fn read_file(&self, path: &Path) -> io::Result<String>;
+    fn is_file(&self, path: &Path) -> bool;
+    fn is_dir(&self, path: &Path) -> bool;
+    /// The same path for every way of referring to a file, so each file is included once
+    fn canonicalise(&self, path: &Path) -> PathBuf;
+
+    /// All paths an include may refer to, in the order they are tried. First relative to
+    /// the file being assembled, then in every library folder
+    fn include_candidates(
+        &self,
+        name: &str,
+        base_dir: &Path,
+        libs_paths: &[PathBuf],
+    ) -> Vec<PathBuf> {
+        once(base_dir)
+            .chain(libs_paths.iter().map(PathBuf::as_path))
+            .map(|dir| {
+                let mut path = dir.join(name);
+                // When trying to import a folder, it looks for a file named Lib.sbl in the folder
+                if self.is_dir(&path) {
+                    path.push("Lib");
+                }
+                // Imports in sublang don't need an extension but files do
+                if path.extension().is_none() {
+                    path.set_extension("sbl");
+                }
+                path
+            })
+            .collect()
+    }
+
+    /// The first candidate of an include that exists
+    fn resolve_include(
+        &self,
+        name: &str,
+        base_dir: &Path,
+        libs_paths: &[PathBuf],
+    ) -> Option<PathBuf> {
+        self.include_candidates(name, base_dir, libs_paths)
+            .into_iter()
+            .find(|path| self.is_file(path))
+    }
+}
+
+/// Reads sources from disk
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FileSystem;
+
+impl SourceProvider for FileSystem {
+    fn read_file(&self, path: &Path) -> io::Result<String> {
+        fs::read_to_string(path)
+    }
+    fn is_file(&self, path: &Path) -> bool {
+        path.is_file()
+    }
+    fn is_dir(&self, path: &Path) -> bool {
+        path.is_dir()
+    }
+    fn canonicalise(&self, path: &Path) -> PathBuf {
+        fs::canonicalize(path)
+            .or_else(|_| std::path::absolute(path))
+            .unwrap_or_else(|_| path.to_path_buf())
+    }
+}
+
+/// Sources that only exist in memory, for example sublang generated by another tool.
+/// Paths are compared after removing '.' and '..' components, so 'a/../b.sbl' and './b.sbl'
+/// refer to the same file
+#[derive(Debug, Default, Clone)]
+pub struct InMemory {
+    files: HashMap<PathBuf, String>,
+}
+
+impl InMemory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, path: impl AsRef<Path>, contents: impl Into<String>) {
+        self.files.insert(normalise(path.as_ref()), contents.into());
+    }
+}
+
+impl SourceProvider for InMemory {
+    fn read_file(&self, path: &Path) -> io::Result<String> {
+        self.files.get(&normalise(path)).cloned().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("'{}' is not an in-memory source", path.display()),
+            )
+        })
+    }
+    fn is_file(&self, path: &Path) -> bool {
+        self.files.contains_key(&normalise(path))
+    }
+    fn is_dir(&self, path: &Path) -> bool {
+        let path = normalise(path);
+        self.files
+            .keys()
+            .any(|file| file != &path && file.starts_with(&path))
+    }
+    fn canonicalise(&self, path: &Path) -> PathBuf {
+        normalise(path)
+    }
+}
+
+/// Lexically removes '.' and '..' components
+fn normalise(path: &Path) -> PathBuf {
+    let mut normalised = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !normalised.pop() {
+                    normalised.push("..");
+                }
+            }
+            _ => normalised.push(component),
+        }
+    }
+    normalised
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn in_memory_includes() {
+        let mut sources = InMemory::new();
+        sources.insert("project/Main.sbl", "#Util\n#sublib");
+        sources.insert("./project/Util.sbl", "");
+        sources.insert("libs/sublib/Lib.sbl", "");
+        let libs = vec![PathBuf::from("libs")];
+        let base_dir = Path::new("project");
+
+        assert_eq!(
+            sources.resolve_include("Util", base_dir, &libs),
+            Some(PathBuf::from("project/Util.sbl"))
+        );
+        assert_eq!(
+            sources.resolve_include("sublib", base_dir, &libs),
+            Some(PathBuf::from("libs/sublib/Lib.sbl"))
+        );
+        assert_eq!(sources.resolve_include("Missing", base_dir, &libs), None);
+        assert_eq!(
+            sources.include_candidates("Missing", base_dir, &libs),
+            vec![
+                PathBuf::from("project/Missing.sbl"),
+                PathBuf::from("libs/Missing.sbl")
+            ]
+        );
+        assert!(
+            sources
+                .read_file(Path::new("libs/../project/Util.sbl"))
+                .is_ok()
+        );
+        assert!(sources.read_file(Path::new("Util.sbl")).is_err());
+    }
+}
@@ -0,0 +1,231 @@
+//! Every label of an assembly with its address, for tools that refer to memory by name
+use std::{collections::HashMap, fs, path::Path};
+
+use serde_json::json;
+
+use crate::{
+    error,
+    feedback::AssembleError,
+    lexer,
+    tokens::{Info, Token, TokenVariant},
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    /// As the assembler uses it, so labels defined in macros keep their hygiene prefix
+    pub name: String,
+    pub address: usize,
+    /// Where the label is defined
+    pub info: Info,
+    /// Indices of the scopes from the global scope (0) to the one the label is defined in
+    pub scope_path: Vec<usize>,
+    /// The macro whose expansion defined the label, taken from the '?Macro?name' hygiene name
+    pub macro_name: Option<String>,
+}
+
+impl Symbol {
+    pub fn new(name: String, address: usize, info: Info, scope_path: Vec<usize>) -> Self {
+        Symbol {
+            macro_name: name
+                .strip_prefix('?')
+                .and_then(|hygienic| hygienic.split('?').next())
+                .map(str::to_owned),
+            name,
+            address,
+            info,
+            scope_path,
+        }
+    }
+
+    /// The name without the hygiene prefix
+    pub fn short_name(&self) -> &str {
+        match &self.macro_name {
+            Some(_) => self.name.rsplit('?').next().unwrap_or(&self.name),
+            None => &self.name,
+        }
+    }
+}
+
+/// For each scope, the indices of the scopes that enclose it. The scopes are numbered the
+/// same way as when the addresses are assigned to labels
+fn scope_paths(tokens: &[Token]) -> Vec<Vec<usize>> {
+    let mut paths = vec![vec![0]];
+    let mut current_scope_indexes: Vec<usize> = vec![0];
+
+    for token in tokens {
+        match token.variant {
+            TokenVariant::Scope => {
+                current_scope_indexes.push(paths.len());
+                paths.push(current_scope_indexes.clone());
+            }
+            TokenVariant::Unscope => {
+                current_scope_indexes.pop();
+            }
+            _ => {}
+        }
+    }
+    paths
+}
+
+/// Builds the symbols from the scoped label table, sorted by address
+pub fn collect(
+    tokens: &[Token],
+    scoped_label_table: &[HashMap<String, (usize, Info)>],
+) -> Vec<Symbol> {
+    let paths = scope_paths(tokens);
+    let mut symbols = scoped_label_table
+        .iter()
+        .zip(paths)
+        .flat_map(|(scope, path)| {
+            scope.iter().map(move |(name, (address, info))| {
+                Symbol::new(name.clone(), *address, info.clone(), path.clone())
+            })
+        })
+        .collect::<Vec<Symbol>>();
+    symbols.sort_by(|a, b| (a.address, &a.name).cmp(&(b.address, &b.name)));
+    symbols
+}
+
+fn file_of(info: &Info) -> String {
+    lexer::FILES.with_borrow(|files| {
+        files
+            .get(info.file)
+            .map(|file| file.path.display().to_string())
+            .unwrap_or_default()
+    })
+}
+
+fn scope_path_string(path: &[usize]) -> String {
+    path.iter()
+        .map(usize::to_string)
+        .collect::<Vec<String>>()
+        .join("/")
+}
+
+/// One label per line, sorted by address.
+///
+/// Example:
+/// 0x0004  0/2     ?Clear?tmp   Clear  Main.sbl:3:5
+pub fn to_text(symbols: &[Symbol]) -> String {
+    let name_width = symbols.iter().map(|s| s.name.len()).max().unwrap_or(0);
+    let scope_width = symbols
+        .iter()
+        .map(|s| scope_path_string(&s.scope_path).len())
+        .max()
+        .unwrap_or(0);
+    let macro_width = symbols
+        .iter()
+        .map(|s| s.macro_name.as_ref().map_or(1, String::len))
+        .max()
+        .unwrap_or(0);
+
+    let mut text = String::new();
+    for symbol in symbols {
+        text.push_str(&format!(
+            "0x{:04X}  {:<scope_width$}  {:<name_width$}  {:<macro_width$}  {}:{}:{}\n",
+            symbol.address,
+            scope_path_string(&symbol.scope_path),
+            symbol.name,
+            symbol.macro_name.as_deref().unwrap_or("-"),
+            file_of(&symbol.info),
+            symbol.info.line_number,
+            symbol.info.start_char,
+        ));
+    }
+    text
+}
+
+pub fn to_json(symbols: &[Symbol]) -> String {
+    let symbols = symbols
+        .iter()
+        .map(|symbol| {
+            json!({
+                "name": symbol.name,
+                "short_name": symbol.short_name(),
+                "address": symbol.address,
+                "file": file_of(&symbol.info),
+                "line": symbol.info.line_number,
+                "column": symbol.info.start_char,
+                "scope_path": symbol.scope_path,
+                "macro": symbol.macro_name,
+            })
+        })
+        .collect::<Vec<_>>();
+    format!("{:#}", json!(symbols))
+}
+
+/// Writes the symbols to the file, as JSON if it has a .json extension and as text otherwise
+pub fn to_file(path: &Path, symbols: &[Symbol]) -> Result<(), AssembleError> {
+    let contents = if path.extension().is_some_and(|ext| ext == "json") {
+        to_json(symbols)
+    } else {
+        to_text(symbols)
+    };
+    if let Err(e) = fs::write(path, contents) {
+        error!("Failed to write the symbol table {}. {e}", path.display());
+    }
+    Ok(())
+}
+
+/// Reads the addresses and names from a symbol table written by to_file()
+pub fn names_from_file(path: &Path) -> Result<Vec<(usize, String)>, AssembleError> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) => error!("Failed to read the symbol table {}. {e}", path.display()),
+    };
+
+    if path.extension().is_some_and(|ext| ext == "json") {
+        let symbols: Vec<serde_json::Value> = match serde_json::from_str(&contents) {
+            Ok(symbols) => symbols,
+            Err(e) => error!("Invalid symbol table {}. {e}", path.display()),
+        };
+        return Ok(symbols
+            .iter()
+            .filter_map(|symbol| {
+                let address = symbol["address"].as_u64()? as usize;
+                Some((address, symbol["name"].as_str()?.to_owned()))
+            })
+            .collect());
+    }
+
+    let mut names = Vec::new();
+    for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+        let mut columns = line.split_whitespace();
+        let address = columns
+            .next()
+            .and_then(|address| address.strip_prefix("0x"))
+            .and_then(|address| usize::from_str_radix(address, 16).ok());
+        // The scope path comes before the name
+        match (address, columns.nth(1)) {
+            (Some(address), Some(name)) => names.push((address, name.to_owned())),
+            _ => error!(
+                "Invalid line in the symbol table {}: {line}",
+                path.display()
+            ),
+        }
+    }
+    Ok(names)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::assemble_for_test;
+
+    #[test]
+    fn symbols_of_an_assembly() {
+        let text = "@Clear a? {\n    a? -= a?\n    tmp -> 0\n}\n!Clear A\nA -> 0\n";
+        let (_, _, symbols) = assemble_for_test(text);
+
+        assert_eq!(symbols.len(), 2);
+        assert_eq!(symbols[0].name, "?Clear?tmp");
+        assert_eq!(symbols[0].short_name(), "tmp");
+        assert_eq!(symbols[0].macro_name.as_deref(), Some("Clear"));
+        assert_eq!(symbols[0].scope_path, vec![0, 1]);
+        assert_eq!(symbols[0].info.line_number, 3);
+
+        assert_eq!(symbols[1].name, "A");
+        assert_eq!(symbols[1].address, 4);
+        assert_eq!(symbols[1].macro_name, None);
+        assert_eq!(symbols[1].scope_path, vec![0]);
+    }
+}
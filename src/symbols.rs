@@ -7,3 +7,13 @@ pub const ANY_TYPE_PREFIX: &str = "a_";
 
 pub const IO_ADDR: usize = 0xFFFF;
 pub const DEBUG_ADDR: usize = 0xFFFE;
+/// The words below the debug address devices can be attached to, see runtimes::devices
+pub const DEVICES_ADDR: usize = 0xFFF0;
+pub const HALT_ADDR: usize = 0xFFFD;
+pub const INT_INPUT_ADDR: usize = 0xFFFC;
+pub const RANDOM_ADDR: usize = 0xFFFB;
+/// The low word of the count, the high word follows it
+pub const CYCLES_ADDR: usize = 0xFFF9;
+/// Words in the full address space, everything below the IO and debug addresses. Words an
+/// attached device claims are the device instead
+pub const MEMORY_SIZE: usize = DEBUG_ADDR;
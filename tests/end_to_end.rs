@@ -1,12 +1,18 @@
 use std::fs;
+use std::time::Duration;
 
 use log::{LevelFilter, info};
 use simple_logger::SimpleLogger;
 
-use asa::{feedback::asm_runtime_error, *};
+use asa::{
+    feedback::asm_runtime_error,
+    runtimes::{io::Buffered, machine::Machine},
+    *,
+};
 
 fn test_at_path(path: &str) {
     let paths = fs::read_dir(path).unwrap();
+    let config = assembler::AssemblerConfig::default();
 
     for path in paths {
         let input_file = path.unwrap().path();
@@ -19,8 +25,13 @@ fn test_at_path(path: &str) {
         println!("{}", "-".repeat(80));
 
         let (target, input_file_type, _module) =
-            files::get_target_and_module_name(Some(input_file.to_string_lossy().to_string()));
-        let (mut mem, tokens) = files::process_input_file(&target, input_file_type);
+            files::get_target_and_module_name(Some(input_file.to_string_lossy().to_string()))
+                .unwrap();
+        let ((mem, tokens, _), _) = files::process_input_file(&target, input_file_type, &config)
+            .unwrap_or_else(|e| {
+                e.print(LevelFilter::Warn);
+                panic!("Failed to assemble '{}'", input_file.display());
+            });
         let result = files::to_text(&mem);
 
         let sblx_path = input_file.with_extension("sblx");
@@ -34,18 +45,39 @@ fn test_at_path(path: &str) {
 
         if !out_path.is_file() {
             if !sblx_is_file {
-                crate::error!("No .sblx or .out found for '{}'", input_file.display());
+                panic!("No .sblx or .out found for '{}'", input_file.display());
             }
             continue;
         }
         let expected_out = fs::read_to_string(out_path).unwrap();
         let expected_out = lexer::generic_sanitisation(&expected_out);
-        let (result, ..) = runtimes::interpreter::interpret(&mut mem);
-        let out = result.unwrap_or_else(|e| {
+        // Input for the program may be given in a .in file
+        let in_path = input_file.with_extension("in");
+        let input = if in_path.is_file() {
+            lexer::generic_sanitisation(&fs::read_to_string(in_path).unwrap())
+        } else {
+            String::new()
+        };
+        let mut machine = Machine::new(mem.clone(), Buffered::new(&input));
+        let mut fast = Machine::new(mem, Buffered::new(&input));
+        // A program that never halts fails instead of hanging the tests
+        machine.timeout = Some(Duration::from_secs(60));
+        fast.timeout = machine.timeout;
+
+        runtimes::interpreter::interpret(&mut machine).unwrap_or_else(|e| {
             asm_runtime_error(e, &tokens);
-            terminate!()
+            panic!("Runtime error in '{}'", input_file.display());
         });
-        assert_eq!(out, expected_out);
+        assert_eq!(machine.io.output, expected_out);
+
+        // The fast interpreter ends in exactly the same state
+        runtimes::interpreter::interpret_fast(&mut fast).unwrap();
+        assert_eq!(fast.io.output, machine.io.output);
+        assert_eq!(fast.mem, machine.mem);
+        assert_eq!(
+            (fast.pc, fast.prev_pc, fast.instructions_executed),
+            (machine.pc, machine.prev_pc, machine.instructions_executed)
+        );
 
         println!();
     }